@@ -0,0 +1,83 @@
+//! JSON against the binary codec on the messages that dominate a
+//! replication-heavy workload: full AppendEntries batches and their acks.
+#![feature(test)]
+extern crate raft;
+extern crate test;
+
+use test::Bencher;
+
+use raft::codec;
+use raft::msg::{BaseMsg, Command, Entry, InternalMsg, Msg, MsgType, RequestId};
+use raft::node::NodeId;
+
+fn base() -> BaseMsg {
+    BaseMsg::new(NodeId::from("0001"), NodeId::from("0002"), NodeId::from("0001"), "hb42".to_owned())
+}
+
+/// A full batch of client writes, as a busy leader sends them
+fn append_entries() -> Msg {
+    let entries = (0..35).map(|i| {
+        let command = Command::Put(format!("key-{}", i % 8), format!("value-{:08}", i));
+        Entry::command(command, 7).with_request(RequestId::new(NodeId::from("C001"), format!("m{}", i)))
+    }).collect();
+    Msg::new(base(), MsgType::AppendEntries {
+        details: InternalMsg::new(7, 1200, 7),
+        leader_commit: 1190,
+        entries: Some(entries),
+    })
+}
+
+fn ack() -> Msg {
+    Msg::new(base(), MsgType::AEResp {
+        term: 7,
+        success: true,
+        match_index: 1235,
+        commit_idx: 1190,
+        conflict_term: 0,
+        conflict_index: 0,
+    })
+}
+
+fn decode(bytes: &[u8]) -> Msg {
+    codec::read_frame(&mut &bytes[..]).unwrap().unwrap().decode().unwrap().0
+}
+
+#[bench]
+fn json_encode_append_entries(b: &mut Bencher) {
+    let msg = append_entries();
+    b.bytes = codec::encode_json(&msg, false).len() as u64;
+    b.iter(|| codec::encode_json(&msg, false));
+}
+
+#[bench]
+fn binary_encode_append_entries(b: &mut Bencher) {
+    let msg = append_entries();
+    b.bytes = codec::encode_binary(&msg).len() as u64;
+    b.iter(|| codec::encode_binary(&msg));
+}
+
+#[bench]
+fn json_decode_append_entries(b: &mut Bencher) {
+    let bytes = codec::encode_json(&append_entries(), false);
+    b.bytes = bytes.len() as u64;
+    b.iter(|| decode(&bytes));
+}
+
+#[bench]
+fn binary_decode_append_entries(b: &mut Bencher) {
+    let bytes = codec::encode_binary(&append_entries());
+    b.bytes = bytes.len() as u64;
+    b.iter(|| decode(&bytes));
+}
+
+#[bench]
+fn json_roundtrip_ack(b: &mut Bencher) {
+    let msg = ack();
+    b.iter(|| decode(&codec::encode_json(&msg, false)));
+}
+
+#[bench]
+fn binary_roundtrip_ack(b: &mut Bencher) {
+    let msg = ack();
+    b.iter(|| decode(&codec::encode_binary(&msg)));
+}
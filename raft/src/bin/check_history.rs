@@ -0,0 +1,24 @@
+extern crate raft;
+
+use std::env;
+use std::fs::File;
+use std::io::BufReader;
+use std::process;
+
+use raft::history::{self, History};
+
+fn main() {
+    let path = match env::args().nth(1) {
+        Some(path) => path,
+        None       => panic!("usage: check_history <history file>"),
+    };
+    let file = File::open(&path).ok().expect("could not open history file");
+    let history = History::read(BufReader::new(file)).ok().expect("could not parse history file");
+    match history::check(&history) {
+        Ok(())         => println!("{} events, linearizable", history.events().len()),
+        Err(violation) => {
+            print!("{}", violation);
+            process::exit(1);
+        },
+    }
+}
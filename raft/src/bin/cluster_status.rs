@@ -0,0 +1,101 @@
+extern crate raft;
+extern crate time;
+extern crate unix_socket;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::io::Write;
+use std::process;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+use unix_socket::UnixStream;
+
+use raft::clock::WallClock;
+use raft::codec::{self, Links};
+use raft::msg::{BaseMsg, Msg, MsgType};
+use raft::node::NodeId;
+use raft::port::Port;
+use raft::status::{self, NodeStatus};
+use raft::trace::{Level, Tracer};
+
+/// How long to wait for every replica to answer one poll
+const POLL_TIMEOUT_MS: u64 = 500;
+
+fn usage(problem: &str) -> ! {
+    println!("{}", problem);
+    println!("usage: cluster_status SOCKET [--every MS] [ID...]");
+    println!("       SOCKET is the harness's admin socket; IDs default to 0000 through 0004");
+    process::exit(2)
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let path = args.next().unwrap_or_else(|| usage("missing the admin socket"));
+    let mut every = None;
+    let mut ids = vec![];
+    while let Some(arg) = args.next() {
+        if arg == "--every" {
+            let ms = args.next().and_then(|ms| ms.parse().ok()).unwrap_or_else(|| usage("--every needs milliseconds"));
+            every = Some(ms);
+        } else {
+            ids.push(NodeId::from_str(&arg).unwrap_or_else(|| usage(&format!("bad node id {}", arg))));
+        }
+    }
+    if ids.is_empty() {
+        ids = (0..5).map(|i| NodeId::from(format!("{:04}", i))).collect();
+    }
+
+    let socket = UnixStream::connect(&path).unwrap_or_else(|e| usage(&format!("could not connect to {}: {}", path, e)));
+    let me = NodeId::from(format!("S{:03}", process::id() % 1000));
+    // Frames are read whole on a thread of their own, so giving up on a
+    // poll never leaves the socket in the middle of one
+    let (sender, replies) = mpsc::channel();
+    let port = Port::new(socket.try_clone().unwrap(), sender, Arc::new(Links::new(false)));
+    thread::spawn(move || port.relay(&Tracer::new(me, Level::Warn, Box::new(WallClock))));
+    let mut writer = socket;
+
+    let mut round = 0;
+    loop {
+        round += 1;
+        for id in &ids {
+            let base = BaseMsg::new(me, *id, NodeId::broadcast(), format!("s{}", round));
+            writer.write_all(&codec::encode_json(&Msg::new(base, MsgType::Status), false))
+                .ok()
+                .expect("lost the admin socket");
+        }
+
+        let mut statuses: BTreeMap<NodeId, NodeStatus> = BTreeMap::new();
+        let deadline = time::precise_time_ns() / 1_000_000 + POLL_TIMEOUT_MS;
+        while statuses.len() < ids.len() {
+            let wait = deadline.saturating_sub(time::precise_time_ns() / 1_000_000);
+            let msg = match replies.recv_timeout(Duration::from_millis(wait)) {
+                Ok(msg)                                   => msg,
+                Err(mpsc::RecvTimeoutError::Timeout)      => break,
+                Err(mpsc::RecvTimeoutError::Disconnected) => usage("the harness closed the admin socket"),
+            };
+            if let MsgType::StatusReport(status) = msg.msg {
+                if msg.base.mid == format!("s{}", round) {
+                    statuses.insert(status.id, status);
+                }
+            }
+        }
+
+        println!("{}", status::table_header());
+        for id in &ids {
+            match statuses.get(id) {
+                Some(status) => println!("{}", status),
+                None         => println!("{:<6} (no answer)", id.as_str()),
+            }
+        }
+
+        match every {
+            Some(ms) => {
+                println!("");
+                thread::sleep(Duration::from_millis(ms));
+            },
+            None => break,
+        }
+    }
+}
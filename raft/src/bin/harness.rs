@@ -0,0 +1,651 @@
+//! Runs a raft cluster as real processes behind a router that injects
+//! faults, drives clients against it, and checks their history is
+//! linearizable. For example, from the crate root after `cargo build`:
+//!
+//! ```text
+//! target/debug/harness --duration 10000
+//! target/debug/harness --duration 20000 --kill-every 2000 --down-for 800 --seed 3
+//! target/debug/harness --partition-every 3000 --partition-for 1000 --forward true
+//! target/debug/harness --transfer-every 1500 --reshard-every 2000 --codec binary
+//! ```
+//!
+//! It exits with 1 if the history was not linearizable.
+
+extern crate rand;
+extern crate raft;
+extern crate time;
+extern crate unix_socket;
+
+use std::cmp;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{BufReader, Write};
+use std::path::PathBuf;
+use std::process::{self, Child, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use rand::{Rng, SeedableRng, XorShiftRng};
+use unix_socket::{UnixListener, UnixStream};
+
+use raft::codec;
+use raft::history::{self, History};
+use raft::msg::{BaseMsg, Msg, MsgType, FIRST_GROUP};
+use raft::node::NodeId;
+
+/// How long a client waits for a reply before resending to another replica
+const RETRY_MS: u64 = 500;
+
+/// How often the router wakes up to run the workload and the fault schedule
+const TICK_MS: u64 = 10;
+
+/// Sends the leadership transfers, splits and merges; its replies are not
+/// client operations
+const ADMIN: &'static str = "A000";
+
+struct Options {
+    raft: PathBuf,
+    replicas: usize,
+    clients: usize,
+    keys: usize,
+    read_ratio: f64,
+    think_ms: u64,
+    duration_ms: u64,
+    kill_every_ms: u64,
+    down_ms: u64,
+    partition_every_ms: u64,
+    partition_ms: u64,
+    transfer_every_ms: u64,
+    reshard_every_ms: u64,
+    seed: u64,
+    codec: String,
+    forward: bool,
+    history: Option<PathBuf>,
+    /// Replicas trace at this level to `trace-<id>.jsonl` in the run's directory
+    trace_level: Option<String>,
+}
+
+impl Options {
+    fn parse() -> Options {
+        let mut opts = Options {
+            raft: env::current_exe()
+                .ok()
+                .expect("could not find the harness executable")
+                .with_file_name("raft"),
+            replicas: 5,
+            clients: 4,
+            keys: 8,
+            read_ratio: 0.5,
+            think_ms: 20,
+            duration_ms: 10000,
+            kill_every_ms: 0,
+            down_ms: 1000,
+            partition_every_ms: 0,
+            partition_ms: 1000,
+            transfer_every_ms: 0,
+            reshard_every_ms: 0,
+            seed: 1,
+            codec: "json".to_owned(),
+            forward: false,
+            history: None,
+            trace_level: None,
+        };
+        let mut args = env::args().skip(1);
+        while let Some(flag) = args.next() {
+            let value = args.next().unwrap_or_else(|| usage(&format!("{} needs a value", flag)));
+            let number = || value.parse::<u64>().unwrap_or_else(|_| usage(&format!("{} must be a number", flag)));
+            match &flag[..] {
+                "--raft"            => opts.raft = PathBuf::from(&value),
+                "--replicas"        => opts.replicas = number() as usize,
+                "--clients"         => opts.clients = number() as usize,
+                "--keys"            => opts.keys = number() as usize,
+                "--read-ratio"      => opts.read_ratio = value.parse()
+                    .unwrap_or_else(|_| usage("--read-ratio must be a fraction")),
+                "--think"           => opts.think_ms = number(),
+                "--duration"        => opts.duration_ms = number(),
+                "--kill-every"      => opts.kill_every_ms = number(),
+                "--down-for"        => opts.down_ms = number(),
+                "--partition-every" => opts.partition_every_ms = number(),
+                "--partition-for"   => opts.partition_ms = number(),
+                "--transfer-every"  => opts.transfer_every_ms = number(),
+                "--reshard-every"   => opts.reshard_every_ms = number(),
+                "--seed"            => opts.seed = number(),
+                "--codec"           => opts.codec = value.clone(),
+                "--forward"         => opts.forward = value.parse()
+                    .unwrap_or_else(|_| usage("--forward must be true or false")),
+                "--history"         => opts.history = Some(PathBuf::from(&value)),
+                "--trace-level"     => opts.trace_level = Some(value.clone()),
+                _                   => usage(&format!("unknown flag {}", flag)),
+            }
+        }
+        if opts.codec != "json" && opts.codec != "binary" {
+            usage("--codec must be json or binary");
+        }
+        if opts.replicas == 0 || opts.clients == 0 || opts.keys == 0 {
+            usage("--replicas, --clients and --keys must be positive");
+        }
+        opts
+    }
+}
+
+fn usage(problem: &str) -> ! {
+    println!("{}", problem);
+    println!("usage: harness [--raft PATH] [--replicas N] [--clients N] [--keys N] [--read-ratio F]
+               [--think MS] [--duration MS] [--kill-every MS] [--down-for MS]
+               [--partition-every MS] [--partition-for MS] [--transfer-every MS]
+               [--reshard-every MS] [--seed N] [--codec json|binary] [--forward true|false] [--history FILE]
+               [--trace-level debug|info|warn]");
+    process::exit(2)
+}
+
+enum Event {
+    Tick,
+    Sent(Msg, Vec<u8>),
+    /// An outside client, such as `cluster_status`, spoke for the first time
+    /// on the admin socket; replies to its id go back down `UnixStream`
+    Connected(NodeId, UnixStream),
+}
+
+/// A raft process and the socket it connected to us on
+struct Replica {
+    listener: UnixListener,
+    process: Option<Child>,
+    socket: Option<UnixStream>,
+    restart_at: Option<u64>,
+}
+
+struct Pending {
+    mid: String,
+    op: MsgType,
+    invoked_at: u64,
+    retry_at: u64,
+}
+
+struct Client {
+    id: NodeId,
+    sent: u64,
+    ready_at: u64,
+    pending: Option<Pending>,
+}
+
+#[derive(Default)]
+struct Stats {
+    routed: HashMap<&'static str, u64>,
+    cut: u64,
+    lost: u64,
+    bytes: u64,
+    latencies: Vec<u64>,
+    gets: u64,
+    puts: u64,
+    retries: u64,
+    kills: u64,
+    partitions: u64,
+    transfers: u64,
+    transferred: u64,
+    reshards: u64,
+    resharded: u64,
+}
+
+struct Harness {
+    opts: Options,
+    dir: PathBuf,
+    ids: Vec<NodeId>,
+    replicas: HashMap<NodeId, Replica>,
+    clients: Vec<Client>,
+    groups: HashMap<NodeId, usize>,
+    heal_at: Option<u64>,
+    leader: Option<NodeId>,
+    /// Splits and merges not yet answered, by MID
+    reshards: HashMap<String, MsgType>,
+    rng: XorShiftRng,
+    events: mpsc::Sender<Event>,
+    gateways: HashMap<NodeId, UnixStream>,
+    start: u64,
+    history: History,
+    stats: Stats,
+}
+
+impl Harness {
+    fn new(opts: Options, events: mpsc::Sender<Event>) -> Harness {
+        let dir = env::temp_dir().join(format!("raft-harness-{}", opts.seed));
+        drop(fs::remove_dir_all(&dir));
+        fs::create_dir_all(&dir).ok().expect("could not create the harness directory");
+
+        let ids: Vec<NodeId> = (0..opts.replicas).map(|i| NodeId::from(format!("{:04}", i))).collect();
+        let mut replicas = HashMap::new();
+        for id in &ids {
+            let listener = UnixListener::bind(dir.join(id.as_str()))
+                .ok()
+                .expect("could not bind a replica socket");
+            replicas.insert(*id, Replica {
+                listener: listener,
+                process: None,
+                socket: None,
+                restart_at: None,
+            });
+        }
+        let clients = (0..opts.clients).map(|i| Client {
+            id: NodeId::from(format!("C{:03}", i)),
+            sent: 0,
+            ready_at: 0,
+            pending: None,
+        }).collect();
+        let seed = opts.seed;
+
+        let admin = UnixListener::bind(dir.join("admin")).ok().expect("could not bind the admin socket");
+        println!("admin socket: {}", dir.join("admin").display());
+        let gateway = events.clone();
+        thread::spawn(move || {
+            for socket in admin.incoming() {
+                if let Ok(socket) = socket {
+                    let events = gateway.clone();
+                    thread::spawn(move || relay_admin(socket, events));
+                }
+            }
+        });
+
+        Harness {
+            opts: opts,
+            dir: dir,
+            ids: ids,
+            replicas: replicas,
+            clients: clients,
+            groups: HashMap::new(),
+            heal_at: None,
+            leader: None,
+            reshards: HashMap::new(),
+            rng: XorShiftRng::from_seed([seed as u32 | 1, (seed >> 32) as u32, 0x9e37_79b9, 0x7f4a_7c15]),
+            events: events,
+            gateways: HashMap::new(),
+            start: time::precise_time_ns() / 1_000_000,
+            history: History::new(),
+            stats: Stats::default(),
+        }
+    }
+
+    fn now(&self) -> u64 {
+        time::precise_time_ns() / 1_000_000 - self.start
+    }
+
+    /// Starts the raft process for `id` and waits for it to connect
+    fn spawn(&mut self, id: NodeId) {
+        let peers: Vec<&str> = self.ids.iter().filter(|peer| **peer != id).map(NodeId::as_str).collect();
+        let replica = self.replicas.get_mut(&id).unwrap();
+        let mut command = Command::new(&self.opts.raft);
+        command.args(&["--forward-requests", if self.opts.forward { "true" } else { "false" }]);
+        command.args(&["--binary-codec", if self.opts.codec == "binary" { "true" } else { "false" }]);
+        if let Some(ref level) = self.opts.trace_level {
+            command.args(&["--trace-level", level, "--trace-sinks", &format!("trace-{}.jsonl", id.as_str())]);
+        }
+        let child = command
+            .arg(id.as_str())
+            .args(&peers)
+            .current_dir(&self.dir)
+            .stdout(Stdio::null())
+            .spawn()
+            .ok()
+            .expect("could not start raft; pass its path with --raft");
+        let (socket, _) = replica.listener.accept().ok().expect("replica never connected");
+        let reader = socket.try_clone().unwrap();
+        let events = self.events.clone();
+        thread::spawn(move || {
+            // Frames are passed on as they were written so that replicas can
+            // negotiate their codec with each other through us
+            let mut reader = BufReader::new(reader);
+            while let Ok(Some(frame)) = codec::read_frame(&mut reader) {
+                match frame.decode() {
+                    Ok((msg, _)) => drop(events.send(Event::Sent(msg, frame.to_bytes()))),
+                    Err(e)       => println!("dropping bad message ({}): {}",
+                                             e, String::from_utf8_lossy(&frame.to_bytes())),
+                }
+            }
+        });
+        replica.process = Some(child);
+        replica.socket = Some(socket);
+        replica.restart_at = None;
+    }
+
+    fn kill(&mut self, id: NodeId) {
+        let replica = self.replicas.get_mut(&id).unwrap();
+        if let Some(mut child) = replica.process.take() {
+            drop(child.kill());
+            drop(child.wait());
+        }
+        replica.socket = None;
+    }
+
+    fn connected(&self, a: NodeId, b: NodeId) -> bool {
+        if !self.replicas.contains_key(&a) || !self.replicas.contains_key(&b) {
+            return true;
+        }
+        self.groups.get(&a) == self.groups.get(&b)
+    }
+
+    fn deliver(&mut self, msg: &Msg, bytes: &[u8]) {
+        if !self.connected(msg.base.src, msg.base.dst) {
+            self.stats.cut += 1;
+            return;
+        }
+        let sent = match self.replicas.get_mut(&msg.base.dst).and_then(|replica| replica.socket.as_mut()) {
+            Some(socket) => socket.write_all(bytes).is_ok(),
+            None         => false,
+        };
+        if !sent {
+            self.stats.lost += 1;
+        }
+    }
+
+    fn route(&mut self, msg: Msg, bytes: Vec<u8>) {
+        *self.stats.routed.entry(msg.msg.name()).or_insert(0) += 1;
+        self.stats.bytes += bytes.len() as u64;
+        if let MsgType::AppendEntries { .. } = msg.msg {
+            // Transfers go to the first group, the one a request is for
+            // unless it says otherwise
+            if msg.base.group == FIRST_GROUP {
+                self.leader = Some(msg.base.src);
+            }
+        }
+        if let Some(socket) = self.gateways.get_mut(&msg.base.dst) {
+            drop(socket.write_all(&bytes));
+            return;
+        }
+        if msg.base.dst == NodeId::from(ADMIN) && self.reshards.contains_key(&msg.base.mid) {
+            self.reshard_reply(msg);
+        } else if msg.base.dst == NodeId::from(ADMIN) {
+            println!("{:>6}ms transfer {}: {}", self.now(), msg.base.mid, msg.msg.name());
+            if let MsgType::OK(_) = msg.msg {
+                self.stats.transferred += 1;
+            }
+        } else if self.replicas.contains_key(&msg.base.dst) {
+            self.deliver(&msg, &bytes);
+        } else {
+            self.reply(msg);
+        }
+    }
+
+    /// Follows a split or merge to the leader of the group it went to, or
+    /// reports how it ended
+    fn reshard_reply(&mut self, msg: Msg) {
+        if msg.msg == MsgType::Redirect && self.replicas.contains_key(&msg.base.leader) {
+            let op = self.reshards[&msg.base.mid].clone();
+            let retry = Msg::new(BaseMsg::new(NodeId::from(ADMIN), msg.base.leader, NodeId::broadcast(), msg.base.mid),
+                                 op);
+            self.deliver(&retry, &codec::encode_json(&retry, false));
+            return;
+        }
+        self.reshards.remove(&msg.base.mid);
+        match msg.msg {
+            MsgType::OK(ref group) if !group.is_empty() => println!("{:>6}ms reshard {}: ok, split off group {}",
+                                                                     self.now(), msg.base.mid, group),
+            ref reply => println!("{:>6}ms reshard {}: {}", self.now(), msg.base.mid, reply.name()),
+        }
+        if let MsgType::OK(_) = msg.msg {
+            self.stats.resharded += 1;
+        }
+    }
+
+    fn send_request(&mut self, client: usize, dst: NodeId) {
+        let msg = {
+            let pending = self.clients[client].pending.as_ref().unwrap();
+            Msg::new(BaseMsg::new(self.clients[client].id, dst, NodeId::broadcast(), pending.mid.clone()),
+                     pending.op.clone())
+        };
+        self.deliver(&msg, &codec::encode_json(&msg, false));
+    }
+
+    fn random_replica(&mut self) -> NodeId {
+        let i = self.rng.gen_range(0, self.ids.len());
+        self.ids[i]
+    }
+
+    fn reply(&mut self, msg: Msg) {
+        let now = self.now();
+        let client = match self.clients.iter().position(|client| client.id == msg.base.dst) {
+            Some(client) => client,
+            None         => return,
+        };
+        let matches = self.clients[client].pending.as_ref().map_or(false, |pending| pending.mid == msg.base.mid);
+        if !matches {
+            return;
+        }
+
+        if msg.msg == MsgType::Redirect {
+            if self.replicas.contains_key(&msg.base.leader) {
+                self.stats.retries += 1;
+                self.send_request(client, msg.base.leader);
+            } else {
+                self.clients[client].pending.as_mut().unwrap().retry_at = now + 50;
+            }
+            return;
+        }
+
+        self.history.complete(&msg, now);
+        let pending = self.clients[client].pending.take().unwrap();
+        self.stats.latencies.push(now - pending.invoked_at);
+        match pending.op {
+            MsgType::Get(_) => self.stats.gets += 1,
+            _               => self.stats.puts += 1,
+        }
+        self.clients[client].ready_at = now + self.opts.think_ms;
+    }
+
+    /// Starts new client operations and resends ones that went unanswered
+    fn run_clients(&mut self, now: u64) {
+        for client in 0..self.clients.len() {
+            let ready = self.clients[client].ready_at <= now;
+            let resend = match self.clients[client].pending {
+                Some(ref mut pending) if pending.retry_at <= now => {
+                    pending.retry_at = now + RETRY_MS;
+                    true
+                },
+                Some(_) => false,
+                None if ready => {
+                    let key = format!("k{}", self.rng.gen_range(0, self.opts.keys));
+                    let op = if self.rng.gen::<f64>() < self.opts.read_ratio {
+                        MsgType::Get(key)
+                    } else {
+                        MsgType::Put(key, format!("{}-{}", self.clients[client].id.as_str(), self.clients[client].sent))
+                    };
+                    let mid = format!("{}-{}", self.clients[client].id.as_str(), self.clients[client].sent);
+                    self.clients[client].sent += 1;
+                    self.history.invoke(self.clients[client].id, &mid, now, &op);
+                    self.clients[client].pending = Some(Pending {
+                        mid: mid,
+                        op: op,
+                        invoked_at: now,
+                        retry_at: now + RETRY_MS,
+                    });
+                    false
+                },
+                None => continue,
+            };
+            if resend {
+                self.stats.retries += 1;
+            }
+            let dst = self.random_replica();
+            self.send_request(client, dst);
+        }
+    }
+
+    /// Kills, restarts, partitions and heals on the configured schedule,
+    /// never taking down a majority
+    fn run_faults(&mut self, now: u64, last: u64) {
+        let due = |every: u64| every > 0 && now / every > last / every;
+
+        let restarts: Vec<NodeId> = self.replicas.iter()
+            .filter(|&(_, replica)| replica.restart_at.map_or(false, |at| at <= now))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in restarts {
+            println!("{:>6}ms restarting {}", now, id);
+            self.spawn(id);
+        }
+
+        if due(self.opts.kill_every_ms) {
+            let down = self.replicas.values().filter(|replica| replica.process.is_none()).count();
+            if (down + 1) * 2 < self.ids.len() {
+                let id = self.random_replica();
+                if self.replicas[&id].process.is_some() {
+                    println!("{:>6}ms killing {}", now, id);
+                    self.kill(id);
+                    self.replicas.get_mut(&id).unwrap().restart_at = Some(now + self.opts.down_ms);
+                    self.stats.kills += 1;
+                }
+            }
+        }
+
+        if self.heal_at.map_or(false, |at| at <= now) {
+            println!("{:>6}ms healing the partition", now);
+            self.groups.clear();
+            self.heal_at = None;
+        }
+        if due(self.opts.partition_every_ms) && self.heal_at.is_none() {
+            let mut ids = self.ids.clone();
+            self.rng.shuffle(&mut ids);
+            let minority = &ids[..(self.ids.len() - 1) / 2];
+            println!("{:>6}ms cutting off {:?}", now, minority.iter().map(NodeId::as_str).collect::<Vec<_>>());
+            for id in minority {
+                self.groups.insert(*id, 1);
+            }
+            self.heal_at = Some(now + self.opts.partition_ms);
+            self.stats.partitions += 1;
+        }
+
+        if due(self.opts.transfer_every_ms) {
+            if let Some(leader) = self.leader {
+                let target = self.random_replica();
+                if target != leader && self.replicas[&target].process.is_some() {
+                    let mid = format!("t{}", self.stats.transfers);
+                    println!("{:>6}ms transfer {}: asking {} to hand over to {}", now, mid, leader, target);
+                    let msg = Msg::new(BaseMsg::new(NodeId::from(ADMIN), leader, NodeId::broadcast(), mid),
+                                       MsgType::TransferLeader(target));
+                    self.deliver(&msg, &codec::encode_json(&msg, false));
+                    self.stats.transfers += 1;
+                }
+            }
+        }
+
+        if due(self.opts.reshard_every_ms) {
+            let key = format!("k{}", self.rng.gen_range(0, self.opts.keys));
+            let op = if self.rng.gen() { MsgType::Split(key.clone()) } else { MsgType::Merge(key.clone()) };
+            let mid = format!("r{}", self.stats.reshards);
+            println!("{:>6}ms reshard {}: {} at {}", now, mid, op.name(), key);
+            let dst = self.random_replica();
+            let msg = Msg::new(BaseMsg::new(NodeId::from(ADMIN), dst, NodeId::broadcast(), mid.clone()), op.clone());
+            self.reshards.insert(mid, op);
+            self.deliver(&msg, &codec::encode_json(&msg, false));
+            self.stats.reshards += 1;
+        }
+    }
+
+    fn run(&mut self, events: mpsc::Receiver<Event>) {
+        for id in self.ids.clone() {
+            self.spawn(id);
+        }
+        let mut last = 0;
+        for event in events.iter() {
+            match event {
+                Event::Sent(msg, bytes) => self.route(msg, bytes),
+                Event::Connected(id, socket) => drop(self.gateways.insert(id, socket)),
+                Event::Tick => {
+                    let now = self.now();
+                    if now >= self.opts.duration_ms {
+                        break;
+                    }
+                    self.run_faults(now, last);
+                    self.run_clients(now);
+                    last = now;
+                },
+            }
+        }
+        for id in self.ids.clone() {
+            self.kill(id);
+        }
+    }
+
+    /// Prints what happened and whether the history was linearizable
+    fn report(&self) -> bool {
+        let mut latencies = self.stats.latencies.clone();
+        latencies.sort();
+        let percentile = |p: usize| latencies.get(latencies.len() * p / 100).cloned().unwrap_or(0);
+        let mean = latencies.iter().fold(0, |sum, l| sum + l) as f64 / cmp::max(latencies.len(), 1) as f64;
+        let unanswered = self.clients.iter().filter(|client| client.pending.is_some()).count();
+
+        println!("");
+        println!("{} replicas for {}ms, {} kills, {} partitions, {} of {} leadership transfers, {} of {} reshards",
+                 self.ids.len(), self.opts.duration_ms, self.stats.kills, self.stats.partitions,
+                 self.stats.transferred, self.stats.transfers, self.stats.resharded, self.stats.reshards);
+        println!("operations: {} gets, {} puts, {} retries, {} unanswered at the end",
+                 self.stats.gets, self.stats.puts, self.stats.retries, unanswered);
+        println!("latency ms: mean {:.1}, p50 {}, p99 {}, max {}",
+                 mean, percentile(50), percentile(99), latencies.last().cloned().unwrap_or(0));
+        let total = self.stats.routed.values().fold(0, |sum, n| sum + n);
+        println!("messages: {} routed ({} bytes), {} cut by partitions, {} sent to dead replicas",
+                 total, self.stats.bytes, self.stats.cut, self.stats.lost);
+        let mut routed: Vec<(&&str, &u64)> = self.stats.routed.iter().collect();
+        routed.sort();
+        for (name, count) in routed {
+            println!("  {:<16} {}", name, count);
+        }
+
+        if self.opts.trace_level.is_some() {
+            println!("traces: {}", self.dir.join("trace-*.jsonl").display());
+        }
+
+        match history::check(&self.history) {
+            Ok(()) => {
+                println!("history of {} events is linearizable", self.history.events().len());
+                true
+            },
+            Err(violation) => {
+                print!("history is NOT linearizable, {}", violation);
+                false
+            },
+        }
+    }
+}
+
+/// Passes requests from one admin connection into the router
+fn relay_admin(socket: UnixStream, events: mpsc::Sender<Event>) {
+    let mut reader = BufReader::new(match socket.try_clone() {
+        Ok(reader) => reader,
+        Err(_)     => return,
+    });
+    let mut writer = Some(socket);
+    while let Ok(Some(frame)) = codec::read_frame(&mut reader) {
+        match frame.decode() {
+            Ok((msg, _)) => {
+                if let Some(writer) = writer.take() {
+                    drop(events.send(Event::Connected(msg.base.src, writer)));
+                }
+                drop(events.send(Event::Sent(msg, frame.to_bytes())));
+            },
+            Err(e) => println!("dropping bad admin message ({})", e),
+        }
+    }
+}
+
+fn main() {
+    let opts = Options::parse();
+    let history_path = opts.history.clone();
+    let (sender, receiver) = mpsc::channel();
+    let ticker = sender.clone();
+    thread::spawn(move || {
+        while ticker.send(Event::Tick).is_ok() {
+            thread::sleep(Duration::from_millis(TICK_MS));
+        }
+    });
+
+    let mut harness = Harness::new(opts, sender);
+    harness.run(receiver);
+    let linearizable = harness.report();
+    if let Some(path) = history_path {
+        let mut file = fs::File::create(&path).ok().expect("could not create the history file");
+        harness.history.write(&mut file).ok().expect("could not write the history file");
+    }
+    if !linearizable {
+        process::exit(1);
+    }
+}
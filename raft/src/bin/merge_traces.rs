@@ -0,0 +1,70 @@
+extern crate raft;
+extern crate rustc_serialize;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::process;
+
+use rustc_serialize::json::ToJson;
+
+use raft::trace::{self, Level, Record};
+
+fn usage(problem: &str) -> ! {
+    println!("{}", problem);
+    println!("usage: merge_traces [--level LEVEL] [--node ID]... [--json] TRACE...");
+    println!("       interleaves replicas' traces by time; LEVEL is debug, info or warn");
+    process::exit(2)
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let mut level = Level::Debug;
+    let mut nodes = vec![];
+    let mut json = false;
+    let mut paths = vec![];
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "--level" => level = args.next().and_then(|l| Level::from_str(&l))
+                .unwrap_or_else(|| usage("--level needs debug, info or warn")),
+            "--node"  => nodes.push(args.next().unwrap_or_else(|| usage("--node needs a node id"))),
+            "--json"  => json = true,
+            _         => paths.push(arg),
+        }
+    }
+    if paths.is_empty() {
+        usage("no traces given");
+    }
+
+    // A trace written to stdout may have other output mixed in
+    let mut skipped = 0;
+    let mut traces = vec![];
+    for path in &paths {
+        let file = File::open(path).unwrap_or_else(|e| usage(&format!("could not open {}: {}", path, e)));
+        let mut records = vec![];
+        for line in BufReader::new(file).lines() {
+            let line = line.unwrap_or_else(|e| usage(&format!("could not read {}: {}", path, e)));
+            match Record::parse(&line) {
+                Some(record) => records.push(record),
+                None         => skipped += 1,
+            }
+        }
+        traces.push(records);
+    }
+
+    let shown = trace::merge(traces).into_iter()
+        .filter(|record| record.level >= level)
+        .filter(|record| nodes.is_empty() || nodes.contains(&record.node));
+    // Stop quietly if whatever we are piped into, like `head`, goes away
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for record in shown {
+        let line = if json { record.to_json().to_string() } else { record.describe() };
+        if writeln!(out, "{}", line).is_err() {
+            return;
+        }
+    }
+    if skipped > 0 {
+        drop(writeln!(io::stderr(), "skipped {} lines that were not trace events", skipped));
+    }
+}
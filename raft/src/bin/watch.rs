@@ -0,0 +1,119 @@
+extern crate raft;
+extern crate time;
+extern crate unix_socket;
+
+use std::env;
+use std::io::Write;
+use std::process;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+use unix_socket::UnixStream;
+
+use raft::clock::WallClock;
+use raft::codec::{self, Links};
+use raft::msg::{BaseMsg, Msg, MsgType};
+use raft::node::NodeId;
+use raft::port::Port;
+use raft::trace::{Level, Tracer};
+
+/// How often the watch is renewed. Renewing resumes from the last event
+/// seen, so a leader that died quietly costs at most this much delay; the
+/// price is that an event in flight during a renewal may be printed twice.
+const RENEW_MS: u64 = 1000;
+
+fn usage(problem: &str) -> ! {
+    println!("{}", problem);
+    println!("usage: watch SOCKET KEY [--prefix] [--from INDEX] [ID...]");
+    println!("       SOCKET is the harness's admin socket; IDs default to 0000 through 0004");
+    process::exit(2)
+}
+
+fn now_ms() -> u64 {
+    time::precise_time_ns() / 1_000_000
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let path = args.next().unwrap_or_else(|| usage("missing the admin socket"));
+    let key = args.next().unwrap_or_else(|| usage("missing the key to watch"));
+    let mut prefix = false;
+    let mut next_index = 0;
+    let mut ids = vec![];
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "--prefix" => prefix = true,
+            "--from"   => next_index = args.next().and_then(|i| i.parse().ok())
+                .unwrap_or_else(|| usage("--from needs a log index")),
+            _          => ids.push(NodeId::from_str(&arg).unwrap_or_else(|| usage(&format!("bad node id {}", arg)))),
+        }
+    }
+    if ids.is_empty() {
+        ids = (0..5).map(|i| NodeId::from(format!("{:04}", i))).collect();
+    }
+
+    let socket = UnixStream::connect(&path).unwrap_or_else(|e| usage(&format!("could not connect to {}: {}", path, e)));
+    let me = NodeId::from(format!("W{:03}", process::id() % 1000));
+    // Frames are read whole on a thread of their own, so waking up to renew
+    // never leaves the socket in the middle of an event
+    let (sender, events) = mpsc::channel();
+    let port = Port::new(socket.try_clone().unwrap(), sender, Arc::new(Links::new(false)));
+    thread::spawn(move || port.relay(&Tracer::new(me, Level::Warn, Box::new(WallClock))));
+    let mut writer = socket;
+
+    let mut target = 0;
+    let mut renew_at = 0;
+    let mut acked = true;
+    loop {
+        if now_ms() >= renew_at {
+            if !acked {
+                target = (target + 1) % ids.len();
+            }
+            acked = false;
+            let base = BaseMsg::new(me, ids[target], NodeId::broadcast(), "watch".to_owned());
+            let watch = MsgType::Watch { key: key.clone(), prefix: prefix, from_index: next_index };
+            writer.write_all(&codec::encode_json(&Msg::new(base, watch), false))
+                .ok()
+                .expect("lost the admin socket");
+            renew_at = now_ms() + RENEW_MS;
+        }
+
+        let wait = renew_at.saturating_sub(now_ms());
+        let msg = match events.recv_timeout(Duration::from_millis(wait)) {
+            Ok(msg)                                   => msg,
+            Err(mpsc::RecvTimeoutError::Timeout)      => continue,
+            Err(mpsc::RecvTimeoutError::Disconnected) => usage("the harness closed the admin socket"),
+        };
+        match msg.msg {
+            MsgType::WatchEvent { index, key, value } => {
+                match value {
+                    Some(value) => println!("{:>8} {} = {}", index, key, value),
+                    None        => println!("{:>8} {} deleted", index, key),
+                }
+                next_index = index + 1;
+            },
+            MsgType::OK(ref start) => {
+                acked = true;
+                // Pin the stream's start so renewals don't skip ahead
+                if next_index == 0 {
+                    next_index = start.parse().unwrap_or(0);
+                }
+            },
+            MsgType::Redirect | MsgType::Fail => {
+                target = match ids.iter().position(|id| *id == msg.base.leader) {
+                    Some(leader) => leader,
+                    None         => (target + 1) % ids.len(),
+                };
+                acked = true;
+                renew_at = 0;
+            },
+            MsgType::Compacted(oldest) => {
+                println!("changes before {} are gone; resuming from there", oldest);
+                next_index = oldest;
+                renew_at = 0;
+            },
+            _ => {},
+        }
+    }
+}
@@ -0,0 +1,25 @@
+use time;
+
+/// A monotonic source of time in milliseconds from an arbitrary start
+pub trait Clock {
+    fn now_ms(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u64 {
+        time::precise_time_ns() / 1_000_000
+    }
+}
+
+/// Milliseconds since the Unix epoch. It can jump, but unlike `SystemClock`
+/// it reads the same on every machine, which lining up traces needs.
+pub struct WallClock;
+
+impl Clock for WallClock {
+    fn now_ms(&self) -> u64 {
+        let now = time::get_time();
+        now.sec as u64 * 1000 + now.nsec as u64 / 1_000_000
+    }
+}
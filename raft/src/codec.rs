@@ -0,0 +1,666 @@
+use std::collections::HashSet;
+use std::io::{self, BufRead, Read};
+use std::sync::Mutex;
+
+use rustc_serialize::json::{Json, ToJson};
+
+use super::membership::Configuration;
+use super::msg::{BaseMsg, Command, DecodeError, DecodeResult, Entry, InternalMsg, Msg, MsgType,
+                 RequestId, Snapshot};
+use super::node::NodeId;
+
+/// First byte of a binary frame. It can never start a line of JSON, so both
+/// kinds of frame can share one stream.
+pub const BINARY_MARKER: u8 = 0;
+
+/// Longest frame we will read, so a bad length can't make us allocate
+/// whatever it says. Snapshots travel in one frame, so this is generous.
+pub const MAX_FRAME: usize = 64 * 1024 * 1024;
+
+/// The links on which we and the peer have both offered the binary codec.
+/// A replica with binary enabled adds `"codec": "binary"` to the JSON it
+/// sends peers, and switches a link over once the peer has offered too.
+/// Clients never see the offer and always get JSON.
+pub struct Links {
+    enabled: bool,
+    binary: Mutex<HashSet<NodeId>>,
+}
+
+impl Links {
+    pub fn new(enabled: bool) -> Links {
+        Links {
+            enabled: enabled,
+            binary: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Records that `peer` can decode binary frames
+    pub fn offered(&self, peer: NodeId) {
+        if self.enabled {
+            self.binary.lock().unwrap().insert(peer);
+        }
+    }
+
+    /// The wire format of `msg` on its link
+    pub fn encode(&self, msg: &Msg) -> Vec<u8> {
+        if !is_peer_msg(&msg.msg) {
+            encode_json(msg, false)
+        } else if self.binary.lock().unwrap().contains(&msg.base.dst) {
+            encode_binary(msg)
+        } else {
+            encode_json(msg, self.enabled)
+        }
+    }
+}
+
+/// Whether only replicas send and receive `msg`
+fn is_peer_msg(msg: &MsgType) -> bool {
+    match *msg {
+        MsgType::AppendEntries { .. }
+            | MsgType::AEResp { .. }
+            | MsgType::RequestVote { .. }
+            | MsgType::RVResp(..)
+            | MsgType::PreVote { .. }
+            | MsgType::PVResp(..)
+            | MsgType::InstallSnapshot { .. }
+            | MsgType::ISResp { .. }
+            | MsgType::TimeoutNow(_) => true,
+        _ => false,
+    }
+}
+
+/// One message as it arrived: a JSON line, or the payload of a binary frame
+pub enum Frame {
+    Json(Vec<u8>),
+    Binary(Vec<u8>),
+}
+
+impl Frame {
+    /// The message, and whether its sender can take binary frames
+    pub fn decode(&self) -> DecodeResult<(Msg, bool)> {
+        match *self {
+            Frame::Json(ref line) => {
+                let line = try!(String::from_utf8(line.clone())
+                    .map_err(|e| DecodeError::Malformed(e.utf8_error().to_string())));
+                let raw = try!(Json::from_str(&line).map_err(|e| DecodeError::Malformed(e.to_string())));
+                let offered = raw.find("codec").and_then(Json::as_string) == Some("binary");
+                Ok((try!(Msg::from_json(&raw)), offered))
+            },
+            Frame::Binary(ref payload) => decode_binary(payload).map(|msg| (msg, true)),
+        }
+    }
+
+    /// Exactly what was read off the wire, to pass the frame along unchanged
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match *self {
+            Frame::Json(ref line) => {
+                let mut bytes = line.clone();
+                bytes.push(b'\n');
+                bytes
+            },
+            Frame::Binary(ref payload) => frame_binary(payload),
+        }
+    }
+}
+
+fn oversized(len: usize) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("frame of {} bytes is over the {} byte limit", len, MAX_FRAME))
+}
+
+/// Discards the rest of the current line, newline and all, without holding
+/// on to any of it
+fn skip_line<R: BufRead>(reader: &mut R) -> io::Result<()> {
+    loop {
+        let (used, done) = {
+            let buf = try!(reader.fill_buf());
+            match buf.iter().position(|&byte| byte == b'\n') {
+                Some(i) => (i + 1, true),
+                None    => (buf.len(), buf.is_empty()),
+            }
+        };
+        reader.consume(used);
+        if done {
+            return Ok(());
+        }
+    }
+}
+
+/// Reads the next frame, or `None` at the end of the stream. A frame over
+/// `MAX_FRAME` is skipped and reported as an `InvalidData` error, so the
+/// next read starts at the frame after it.
+pub fn read_frame<R: BufRead>(reader: &mut R) -> io::Result<Option<Frame>> {
+    let first = match try!(reader.fill_buf()).first() {
+        Some(byte) => *byte,
+        None       => return Ok(None),
+    };
+    if first == BINARY_MARKER {
+        let mut header = [0u8; 5];
+        try!(reader.read_exact(&mut header));
+        let len = ((header[1] as usize) << 24) | ((header[2] as usize) << 16)
+            | ((header[3] as usize) << 8) | header[4] as usize;
+        if len > MAX_FRAME {
+            try!(io::copy(&mut reader.by_ref().take(len as u64), &mut io::sink()));
+            return Err(oversized(len));
+        }
+        let mut payload = vec![0u8; len];
+        try!(reader.read_exact(&mut payload));
+        Ok(Some(Frame::Binary(payload)))
+    } else {
+        let mut line = vec![];
+        try!(reader.by_ref().take(MAX_FRAME as u64 + 1).read_until(b'\n', &mut line));
+        let ended = line.last() == Some(&b'\n');
+        if ended {
+            line.pop();
+        }
+        if line.len() > MAX_FRAME {
+            if !ended {
+                try!(skip_line(reader));
+            }
+            return Err(oversized(line.len()));
+        }
+        Ok(Some(Frame::Json(line)))
+    }
+}
+
+pub fn encode_json(msg: &Msg, offer_binary: bool) -> Vec<u8> {
+    let mut json = msg.to_json();
+    if offer_binary {
+        if let Json::Object(ref mut d) = json {
+            d.insert("codec".to_owned(), "binary".to_json());
+        }
+    }
+    let mut bytes = json.to_string().into_bytes();
+    bytes.push(b'\n');
+    bytes
+}
+
+/// The marker byte, a big-endian u32 length, then the message
+pub fn encode_binary(msg: &Msg) -> Vec<u8> {
+    let mut w = Writer(vec![]);
+    w.base(&msg.base);
+    w.msg_type(&msg.msg);
+    frame_binary(&w.0)
+}
+
+fn frame_binary(payload: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(payload.len() + 5);
+    bytes.push(BINARY_MARKER);
+    let len = payload.len() as u32;
+    bytes.extend_from_slice(&[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8]);
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+/// Decodes the payload of a binary frame, without its marker and length
+pub fn decode_binary(payload: &[u8]) -> DecodeResult<Msg> {
+    let mut r = Reader { buf: payload, pos: 0 };
+    let base = try!(r.base());
+    let msg = try!(r.msg_type());
+    Ok(Msg::new(base, msg))
+}
+
+// Message tags. Anything else travels as its JSON in a string.
+const TAG_JSON: u8 = 0;
+const TAG_APPEND_ENTRIES: u8 = 1;
+const TAG_AE_RESP: u8 = 2;
+const TAG_REQUEST_VOTE: u8 = 3;
+const TAG_RV_RESP: u8 = 4;
+const TAG_PRE_VOTE: u8 = 5;
+const TAG_PV_RESP: u8 = 6;
+const TAG_INSTALL_SNAPSHOT: u8 = 7;
+const TAG_IS_RESP: u8 = 8;
+const TAG_TIMEOUT_NOW: u8 = 9;
+
+// Command tags
+const CMD_PUT: u8 = 0;
+const CMD_DELETE: u8 = 1;
+const CMD_CAS: u8 = 2;
+const CMD_APPEND: u8 = 3;
+const CMD_BATCH: u8 = 4;
+const CMD_CONFIG: u8 = 5;
+const CMD_NOOP: u8 = 6;
+const CMD_SPLIT: u8 = 7;
+const CMD_RETIRE: u8 = 8;
+const CMD_MERGE: u8 = 9;
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, v: u8) {
+        self.0.push(v);
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.0.extend_from_slice(&[(v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8, v as u8]);
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.u32((v >> 32) as u32);
+        self.u32(v as u32);
+    }
+
+    fn bool(&mut self, v: bool) {
+        self.u8(v as u8);
+    }
+
+    fn str(&mut self, v: &str) {
+        self.u32(v.len() as u32);
+        self.0.extend_from_slice(v.as_bytes());
+    }
+
+    fn opt_str(&mut self, v: &Option<String>) {
+        match *v {
+            Some(ref v) => {
+                self.bool(true);
+                self.str(v);
+            },
+            None => self.bool(false),
+        }
+    }
+
+    fn node_id(&mut self, id: NodeId) {
+        self.str(id.as_str());
+    }
+
+    fn base(&mut self, base: &BaseMsg) {
+        self.node_id(base.src);
+        self.node_id(base.dst);
+        self.node_id(base.leader);
+        self.str(&base.mid);
+        self.str(&base.group);
+    }
+
+    fn details(&mut self, details: &InternalMsg) {
+        self.u64(details.term);
+        self.u64(details.last_entry);
+        self.u64(details.last_entry_term);
+    }
+
+    fn msg_type(&mut self, msg: &MsgType) {
+        match *msg {
+            MsgType::AppendEntries {ref details, leader_commit, ref entries} => {
+                self.u8(TAG_APPEND_ENTRIES);
+                self.details(details);
+                self.u64(leader_commit);
+                match *entries {
+                    Some(ref entries) => {
+                        self.bool(true);
+                        self.u32(entries.len() as u32);
+                        for entry in entries {
+                            self.entry(entry);
+                        }
+                    },
+                    None => self.bool(false),
+                }
+            },
+            MsgType::AEResp {term, success, match_index, commit_idx, conflict_term, conflict_index} => {
+                self.u8(TAG_AE_RESP);
+                self.u64(term);
+                self.bool(success);
+                self.u64(match_index);
+                self.u64(commit_idx);
+                self.u64(conflict_term);
+                self.u64(conflict_index);
+            },
+            MsgType::RequestVote {ref details, candidate_id} => {
+                self.u8(TAG_REQUEST_VOTE);
+                self.details(details);
+                self.node_id(candidate_id);
+            },
+            MsgType::PreVote {ref details, candidate_id} => {
+                self.u8(TAG_PRE_VOTE);
+                self.details(details);
+                self.node_id(candidate_id);
+            },
+            MsgType::RVResp(term, vote) | MsgType::PVResp(term, vote) => {
+                self.u8(if let MsgType::RVResp(..) = *msg { TAG_RV_RESP } else { TAG_PV_RESP });
+                self.u64(term);
+                self.bool(vote);
+            },
+            MsgType::InstallSnapshot {term, ref snapshot} => {
+                self.u8(TAG_INSTALL_SNAPSHOT);
+                self.u64(term);
+                self.str(&snapshot.to_json().to_string());
+            },
+            MsgType::ISResp {term, match_index} => {
+                self.u8(TAG_IS_RESP);
+                self.u64(term);
+                self.u64(match_index);
+            },
+            MsgType::TimeoutNow(term) => {
+                self.u8(TAG_TIMEOUT_NOW);
+                self.u64(term);
+            },
+            _ => {
+                self.u8(TAG_JSON);
+                self.str(&msg.to_json().to_string());
+            },
+        }
+    }
+
+    fn entry(&mut self, entry: &Entry) {
+        self.u64(entry.term);
+        match entry.request {
+            Some(ref request) => {
+                self.bool(true);
+                self.node_id(request.client);
+                self.str(&request.mid);
+            },
+            None => self.bool(false),
+        }
+        match entry.command {
+            Command::Put(ref key, ref value) => {
+                self.u8(CMD_PUT);
+                self.str(key);
+                self.str(value);
+            },
+            Command::Delete(ref key) => {
+                self.u8(CMD_DELETE);
+                self.str(key);
+            },
+            Command::Cas {ref key, ref expected, ref new} => {
+                self.u8(CMD_CAS);
+                self.str(key);
+                self.opt_str(expected);
+                self.str(new);
+            },
+            Command::Append(ref key, ref value) => {
+                self.u8(CMD_APPEND);
+                self.str(key);
+                self.str(value);
+            },
+            Command::Batch(ref puts) => {
+                self.u8(CMD_BATCH);
+                self.u32(puts.len() as u32);
+                for &(ref key, ref value) in puts {
+                    self.str(key);
+                    self.str(value);
+                }
+            },
+            Command::Split(ref key) => {
+                self.u8(CMD_SPLIT);
+                self.str(key);
+            },
+            Command::Retire(ref key) => {
+                self.u8(CMD_RETIRE);
+                self.str(key);
+            },
+            Command::Merge {ref from, ref data, ref sessions} => {
+                self.u8(CMD_MERGE);
+                self.str(from);
+                self.str(data);
+                self.str(sessions);
+            },
+            Command::Config(ref config) => {
+                self.u8(CMD_CONFIG);
+                self.str(&config.to_json().to_string());
+            },
+            Command::Noop => self.u8(CMD_NOOP),
+        }
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl <'a>Reader<'a> {
+    fn take(&mut self, n: usize) -> DecodeResult<&'a [u8]> {
+        if self.buf.len() - self.pos < n {
+            return Err(DecodeError::Malformed("truncated binary message".to_owned()));
+        }
+        let bytes = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> DecodeResult<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u32(&mut self) -> DecodeResult<u32> {
+        let b = try!(self.take(4));
+        Ok(((b[0] as u32) << 24) | ((b[1] as u32) << 16) | ((b[2] as u32) << 8) | b[3] as u32)
+    }
+
+    fn u64(&mut self) -> DecodeResult<u64> {
+        let high = try!(self.u32()) as u64;
+        let low = try!(self.u32()) as u64;
+        Ok((high << 32) | low)
+    }
+
+    /// How many items follow. Each takes at least a byte, so a count larger
+    /// than what is left is a lie.
+    fn count(&mut self) -> DecodeResult<usize> {
+        let count = try!(self.u32()) as usize;
+        if count > self.buf.len() - self.pos {
+            return Err(DecodeError::Malformed(format!("{} items can't fit in {} bytes", count, self.buf.len() - self.pos)));
+        }
+        Ok(count)
+    }
+
+    fn bool(&mut self) -> DecodeResult<bool> {
+        self.u8().map(|b| b != 0)
+    }
+
+    fn str(&mut self) -> DecodeResult<String> {
+        let len = try!(self.u32()) as usize;
+        let bytes = try!(self.take(len));
+        String::from_utf8(bytes.to_vec()).map_err(|e| DecodeError::Malformed(e.utf8_error().to_string()))
+    }
+
+    fn opt_str(&mut self) -> DecodeResult<Option<String>> {
+        if try!(self.bool()) {
+            self.str().map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn json(&mut self) -> DecodeResult<Json> {
+        let json = try!(self.str());
+        Json::from_str(&json).map_err(|e| DecodeError::Malformed(e.to_string()))
+    }
+
+    fn node_id(&mut self) -> DecodeResult<NodeId> {
+        let id = try!(self.str());
+        NodeId::from_str(&id).ok_or(DecodeError::BadNodeId(id.clone()))
+    }
+
+    fn base(&mut self) -> DecodeResult<BaseMsg> {
+        let src = try!(self.node_id());
+        let dst = try!(self.node_id());
+        let leader = try!(self.node_id());
+        let mid = try!(self.str());
+        let mut base = BaseMsg::new(src, dst, leader, mid);
+        base.group = try!(self.str());
+        Ok(base)
+    }
+
+    fn details(&mut self) -> DecodeResult<InternalMsg> {
+        let term = try!(self.u64());
+        let last_entry = try!(self.u64());
+        let last_entry_term = try!(self.u64());
+        Ok(InternalMsg::new(term, last_entry, last_entry_term))
+    }
+
+    fn msg_type(&mut self) -> DecodeResult<MsgType> {
+        Ok(match try!(self.u8()) {
+            TAG_APPEND_ENTRIES => {
+                let details = try!(self.details());
+                let leader_commit = try!(self.u64());
+                let entries = if try!(self.bool()) {
+                    let count = try!(self.count());
+                    let mut entries = Vec::with_capacity(count);
+                    for _ in 0..count {
+                        entries.push(try!(self.entry()));
+                    }
+                    Some(entries)
+                } else {
+                    None
+                };
+                MsgType::AppendEntries {
+                    details: details,
+                    leader_commit: leader_commit,
+                    entries: entries,
+                }
+            },
+            TAG_AE_RESP => MsgType::AEResp {
+                term: try!(self.u64()),
+                success: try!(self.bool()),
+                match_index: try!(self.u64()),
+                commit_idx: try!(self.u64()),
+                conflict_term: try!(self.u64()),
+                conflict_index: try!(self.u64()),
+            },
+            TAG_REQUEST_VOTE => MsgType::RequestVote {
+                details: try!(self.details()),
+                candidate_id: try!(self.node_id()),
+            },
+            TAG_PRE_VOTE => MsgType::PreVote {
+                details: try!(self.details()),
+                candidate_id: try!(self.node_id()),
+            },
+            TAG_RV_RESP => MsgType::RVResp(try!(self.u64()), try!(self.bool())),
+            TAG_PV_RESP => MsgType::PVResp(try!(self.u64()), try!(self.bool())),
+            TAG_INSTALL_SNAPSHOT => MsgType::InstallSnapshot {
+                term: try!(self.u64()),
+                snapshot: try!(Snapshot::from_json(&try!(self.json()))),
+            },
+            TAG_IS_RESP => MsgType::ISResp {
+                term: try!(self.u64()),
+                match_index: try!(self.u64()),
+            },
+            TAG_TIMEOUT_NOW => MsgType::TimeoutNow(try!(self.u64())),
+            TAG_JSON => try!(MsgType::from_json(&try!(self.json()))),
+            tag => return Err(DecodeError::UnknownType(format!("binary tag {}", tag))),
+        })
+    }
+
+    fn entry(&mut self) -> DecodeResult<Entry> {
+        let term = try!(self.u64());
+        let request = if try!(self.bool()) {
+            let client = try!(self.node_id());
+            Some(RequestId::new(client, try!(self.str())))
+        } else {
+            None
+        };
+        let command = match try!(self.u8()) {
+            CMD_PUT => Command::Put(try!(self.str()), try!(self.str())),
+            CMD_DELETE => Command::Delete(try!(self.str())),
+            CMD_CAS => Command::Cas {
+                key: try!(self.str()),
+                expected: try!(self.opt_str()),
+                new: try!(self.str()),
+            },
+            CMD_APPEND => Command::Append(try!(self.str()), try!(self.str())),
+            CMD_BATCH => {
+                let count = try!(self.count());
+                let mut puts = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let key = try!(self.str());
+                    puts.push((key, try!(self.str())));
+                }
+                Command::Batch(puts)
+            },
+            CMD_CONFIG => Command::Config(try!(Configuration::from_json(&try!(self.json())))),
+            CMD_NOOP => Command::Noop,
+            CMD_SPLIT => Command::Split(try!(self.str())),
+            CMD_RETIRE => Command::Retire(try!(self.str())),
+            CMD_MERGE => Command::Merge {
+                from: try!(self.str()),
+                data: try!(self.str()),
+                sessions: try!(self.str()),
+            },
+            tag => return Err(DecodeError::UnknownType(format!("binary command {}", tag))),
+        };
+        Ok(Entry {
+            command: command,
+            term: term,
+            request: request,
+        })
+    }
+}
+
+#[allow(dead_code)]
+fn msg(typ: MsgType) -> Msg {
+    Msg::new(BaseMsg::new(NodeId::from("0001"), NodeId::from("0002"), NodeId::from("0001"), "hb3".to_owned()), typ)
+}
+
+#[test]
+fn test_binary_roundtrip() {
+    let config = Configuration::new(vec![NodeId::from("0001"), NodeId::from("0002")]);
+    let entries = vec![
+        Entry::new("x", "13", 1).with_request(RequestId::new(NodeId::from("C001"), "m1".to_owned())),
+        Entry::command(Command::Cas { key: "x".to_owned(), expected: None, new: "1".to_owned() }, 2),
+        Entry::command(Command::Batch(vec![("a".to_owned(), "1".to_owned())]), 2),
+        Entry::config(config.transition(vec![NodeId::from("0003")].into_iter().collect()), 2),
+        Entry::command(Command::Split("m".to_owned()), 3),
+        Entry::command(Command::Merge { from: "0.1".to_owned(), data: "{}".to_owned(), sessions: "{}".to_owned() }, 3),
+        Entry::noop(3),
+    ];
+    let msgs = vec![
+        msg(MsgType::AppendEntries {
+            details: InternalMsg::new(3, 10, 2),
+            leader_commit: 9,
+            entries: Some(entries),
+        }),
+        msg(MsgType::AEResp { term: 3, success: false, match_index: 4, commit_idx: 2,
+                                  conflict_term: 2, conflict_index: 3 }),
+        msg(MsgType::PreVote { details: InternalMsg::new(4, 10, 3), candidate_id: NodeId::from("0001") }),
+        msg(MsgType::RVResp(4, true)),
+        msg(MsgType::InstallSnapshot { term: 3, snapshot: Snapshot::default() }),
+        msg(MsgType::TimeoutNow(5)),
+        msg(MsgType::Put("k".to_owned(), "v".to_owned())),
+        Msg { base: BaseMsg { group: "0.1".to_owned(), ..msg(MsgType::Status).base }, msg: MsgType::Merge("m".to_owned()) },
+    ];
+    for msg in msgs {
+        let bytes = encode_binary(&msg);
+        let frame = read_frame(&mut &bytes[..]).unwrap().unwrap();
+        assert_eq!(frame.decode().unwrap(), (msg, true));
+        assert_eq!(frame.to_bytes(), bytes);
+    }
+    assert!(decode_binary(&encode_binary(&msg(MsgType::RVResp(1, true)))[5..9]).is_err());
+}
+
+#[test]
+fn test_lengths_and_counts_are_checked_before_allocating() {
+    let huge = [BINARY_MARKER, 0xff, 0xff, 0xff, 0xff];
+    assert_eq!(read_frame(&mut &huge[..]).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+    let mut line = vec![b'{'; MAX_FRAME + 1];
+    line.push(b'\n');
+    let next = encode_json(&msg(MsgType::Get("k".to_owned())), false);
+    line.extend_from_slice(&next);
+    let mut reader = &line[..];
+    assert!(read_frame(&mut reader).is_err());
+    // The rest of the long line went with it
+    assert_eq!(read_frame(&mut reader).unwrap().unwrap().to_bytes(), next);
+
+    let append = msg(MsgType::AppendEntries { details: InternalMsg::new(3, 10, 2), leader_commit: 9, entries: Some(vec![]) });
+    let mut bytes = encode_binary(&append);
+    // The entry count is the last thing in the frame
+    let end = bytes.len();
+    bytes[end - 4..].copy_from_slice(&[0xff, 0xff, 0xff, 0xff]);
+    match read_frame(&mut &bytes[..]).unwrap().unwrap().decode() {
+        Err(DecodeError::Malformed(_)) => {},
+        other => panic!("a count bigger than the frame was accepted: {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_links_switch_to_binary_once_offered() {
+    let links = Links::new(true);
+    let vote = msg(MsgType::RVResp(4, true));
+    let json = links.encode(&vote);
+    let (_, offered) = read_frame(&mut &json[..]).unwrap().unwrap().decode().unwrap();
+    assert!(offered);
+
+    links.offered(NodeId::from("0002"));
+    assert_eq!(links.encode(&vote)[0], BINARY_MARKER);
+    assert_eq!(links.encode(&msg(MsgType::OK("v".to_owned()))), encode_json(&msg(MsgType::OK("v".to_owned())), false));
+
+    let off = Links::new(false);
+    off.offered(NodeId::from("0002"));
+    assert_eq!(off.encode(&vote), encode_json(&vote, false));
+}
@@ -0,0 +1,395 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::usize;
+
+use rustc_serialize::json::{Json, ToJson};
+
+use super::msg::{AddJson, Msg, MsgType};
+use super::node::NodeId;
+
+/// A client-visible step: a request going out, or the reply that ends it
+#[derive(Clone, PartialEq, Debug)]
+pub enum Event {
+    Invoke {
+        client: NodeId,
+        mid: String,
+        time: u64,
+        op: MsgType,
+    },
+    Complete {
+        client: NodeId,
+        mid: String,
+        time: u64,
+        reply: MsgType,
+    },
+}
+
+impl Event {
+    fn from_json(json: &Json) -> Option<Event> {
+        let client = match NodeId::as_node_id(json.find("client").unwrap_or(&Json::Null)) {
+            Some(client) => client,
+            None         => return None,
+        };
+        let mid = match json.find("MID").and_then(Json::as_string) {
+            Some(mid) => mid.to_owned(),
+            None      => return None,
+        };
+        let time = match json.find("time").and_then(Json::as_u64) {
+            Some(time) => time,
+            None       => return None,
+        };
+        match json.find("event").and_then(Json::as_string) {
+            Some("invoke") => json.find("op").and_then(|op| MsgType::from_json(op).ok()).map(|op| Event::Invoke {
+                client: client,
+                mid: mid,
+                time: time,
+                op: op,
+            }),
+            Some("complete") => json.find("reply").and_then(|reply| MsgType::from_json(reply).ok()).map(|reply| Event::Complete {
+                client: client,
+                mid: mid,
+                time: time,
+                reply: reply,
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl ToJson for Event {
+    fn to_json(&self) -> Json {
+        let mut d = BTreeMap::new();
+        match *self {
+            Event::Invoke {client, ref mid, time, ref op} => {
+                d.add_json("event", "invoke".to_owned());
+                d.add_json("client", client);
+                d.add_json("MID", mid.to_owned());
+                d.add_json("time", time);
+                d.add_json("op", op.to_json());
+            },
+            Event::Complete {client, ref mid, time, ref reply} => {
+                d.add_json("event", "complete".to_owned());
+                d.add_json("client", client);
+                d.add_json("MID", mid.to_owned());
+                d.add_json("time", time);
+                d.add_json("reply", reply.to_json());
+            },
+        }
+        Json::Object(d)
+    }
+}
+
+/// The `Get`s and `Put`s clients made, in the order they were seen, written
+/// one JSON event per line
+pub struct History {
+    events: Vec<Event>,
+    open: HashSet<(NodeId, String)>,
+}
+
+impl History {
+    pub fn new() -> History {
+        History {
+            events: vec![],
+            open: HashSet::new(),
+        }
+    }
+
+    /// Records a client sending a `Get` or `Put`. Resending a request that is
+    /// still open is the same invocation, and other requests aren't recorded.
+    pub fn invoke(&mut self, client: NodeId, mid: &str, time: u64, op: &MsgType) {
+        match *op {
+            MsgType::Get(_) | MsgType::Put(..) => {},
+            _                                  => return,
+        }
+        if self.open.insert((client, mid.to_owned())) {
+            self.events.push(Event::Invoke {
+                client: client,
+                mid: mid.to_owned(),
+                time: time,
+                op: op.clone(),
+            });
+        }
+    }
+
+    /// Records the reply that ends an open request. A redirect doesn't: the
+    /// client still has to find the leader.
+    pub fn complete(&mut self, reply: &Msg, time: u64) {
+        if reply.msg == MsgType::Redirect {
+            return;
+        }
+        if self.open.remove(&(reply.base.dst, reply.base.mid.clone())) {
+            self.events.push(Event::Complete {
+                client: reply.base.dst,
+                mid: reply.base.mid.clone(),
+                time: time,
+                reply: reply.msg.clone(),
+            });
+        }
+    }
+
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        for event in &self.events {
+            try!(writeln!(out, "{}", event.to_json()));
+        }
+        Ok(())
+    }
+
+    pub fn read<R: BufRead>(input: R) -> io::Result<History> {
+        let mut history = History::new();
+        for line in input.lines() {
+            let line = try!(line);
+            if line.trim().is_empty() {
+                continue;
+            }
+            let event = try!(Json::from_str(&line)
+                .ok()
+                .and_then(|json| Event::from_json(&json))
+                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "corrupt history event")));
+            match event {
+                Event::Invoke {client, ref mid, ..}   => drop(history.open.insert((client, mid.clone()))),
+                Event::Complete {client, ref mid, ..} => drop(history.open.remove(&(client, mid.clone()))),
+            }
+            history.events.push(event);
+        }
+        Ok(history)
+    }
+}
+
+/// A smallest set of operations on one key that no sequential order explains
+#[derive(Debug)]
+pub struct Violation {
+    pub key: String,
+    pub events: Vec<Event>,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f, "not linearizable on key {:?}:", self.key));
+        for event in &self.events {
+            try!(writeln!(f, "{}", event.to_json()));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Effect {
+    Read(Option<String>),
+    Write(String),
+}
+
+/// A `Get` or `Put` spanning from the position of its invocation in the
+/// history to that of its reply. A put without an acknowledgement might take
+/// effect at any point after it was invoked, or never, so it has no `ret`.
+#[derive(Clone, Debug)]
+struct Operation {
+    call: usize,
+    ret: Option<usize>,
+    effect: Effect,
+    events: Vec<Event>,
+}
+
+/// Checks `history` against a sequential key-value store, Wing & Gong style:
+/// search for an order of the operations that respects real time and that
+/// the store could have produced. Keys are independent, so each is checked
+/// on its own. Gets with no reply are ignored, and a `Fail` reply to a get
+/// means the key was absent.
+pub fn check(history: &History) -> Result<(), Violation> {
+    for (key, ops) in operations(history) {
+        if !linearizable(&ops) {
+            let mut events: Vec<(usize, Event)> = shrink(ops).into_iter()
+                .flat_map(|op| {
+                    let positions = Some(op.call).into_iter().chain(op.ret);
+                    positions.zip(op.events).collect::<Vec<_>>()
+                })
+                .collect();
+            events.sort_by(|a, b| a.0.cmp(&b.0));
+            return Err(Violation {
+                key: key,
+                events: events.into_iter().map(|(_, event)| event).collect(),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn operations(history: &History) -> BTreeMap<String, Vec<Operation>> {
+    let mut invoked: HashMap<(NodeId, String), (usize, Event)> = HashMap::new();
+    let mut ops: BTreeMap<String, Vec<Operation>> = BTreeMap::new();
+    for (pos, event) in history.events().iter().enumerate() {
+        match *event {
+            Event::Invoke {client, ref mid, ..} => {
+                invoked.insert((client, mid.clone()), (pos, event.clone()));
+            },
+            Event::Complete {client, ref mid, ref reply, ..} => {
+                let (call, invoke) = match invoked.remove(&(client, mid.clone())) {
+                    Some(invoked) => invoked,
+                    None          => continue,
+                };
+                let (key, effect) = match (&invoke, reply) {
+                    (&Event::Invoke {op: MsgType::Get(ref key), ..}, &MsgType::OK(ref value)) =>
+                        (key.clone(), Effect::Read(Some(value.clone()))),
+                    (&Event::Invoke {op: MsgType::Get(ref key), ..}, &MsgType::Fail) =>
+                        (key.clone(), Effect::Read(None)),
+                    (&Event::Invoke {op: MsgType::Put(ref key, ref value), ..}, &MsgType::OK(_)) =>
+                        (key.clone(), Effect::Write(value.clone())),
+                    // Anything else tells us nothing about whether it took effect
+                    _ => {
+                        invoked.insert((client, mid.clone()), (call, invoke));
+                        continue;
+                    },
+                };
+                ops.entry(key).or_insert(vec![]).push(Operation {
+                    call: call,
+                    ret: Some(pos),
+                    effect: effect,
+                    events: vec![invoke, event.clone()],
+                });
+            },
+        }
+    }
+
+    // Whatever is still open: puts might have happened, gets can be dropped
+    let mut pending: Vec<(usize, Event)> = invoked.into_iter().map(|(_, invoked)| invoked).collect();
+    pending.sort_by(|a, b| a.0.cmp(&b.0));
+    for (call, invoke) in pending {
+        if let Event::Invoke {op: MsgType::Put(ref key, ref value), ..} = invoke {
+            ops.entry(key.clone()).or_insert(vec![]).push(Operation {
+                call: call,
+                ret: None,
+                effect: Effect::Write(value.clone()),
+                events: vec![invoke.clone()],
+            });
+        }
+    }
+    for key_ops in ops.values_mut() {
+        key_ops.sort_by(|a, b| a.call.cmp(&b.call));
+    }
+    ops
+}
+
+fn linearizable(ops: &[Operation]) -> bool {
+    search(ops, &mut vec![false; ops.len()], None, &mut HashSet::new())
+}
+
+/// Tries every operation that could come next, remembering which sets of
+/// linearized operations and values have already been ruled out
+fn search(ops: &[Operation],
+          done: &mut Vec<bool>,
+          value: Option<String>,
+          seen: &mut HashSet<(Vec<bool>, Option<String>)>) -> bool {
+    if ops.iter().zip(done.iter()).all(|(op, &done)| done || op.ret.is_none()) {
+        return true;
+    }
+    if !seen.insert((done.clone(), value.clone())) {
+        return false;
+    }
+
+    // Nothing can be ordered after an operation that returned before it began
+    let horizon = ops.iter()
+        .zip(done.iter())
+        .filter(|&(_, &done)| !done)
+        .filter_map(|(op, _)| op.ret)
+        .min()
+        .unwrap_or(usize::MAX);
+    for i in 0..ops.len() {
+        if done[i] || ops[i].call > horizon {
+            continue;
+        }
+        let next = match ops[i].effect {
+            Effect::Read(ref read) if *read == value => value.clone(),
+            Effect::Read(_)                          => continue,
+            Effect::Write(ref written)               => Some(written.clone()),
+        };
+        done[i] = true;
+        if search(ops, done, next, seen) {
+            return true;
+        }
+        done[i] = false;
+    }
+    false
+}
+
+/// Drops operations one at a time for as long as what's left still fails.
+/// Writes that a remaining read returns are kept, so a stale read shrinks to
+/// the writes it missed rather than to a read of a value nobody wrote.
+fn shrink(mut ops: Vec<Operation>) -> Vec<Operation> {
+    let keep_grounded = grounded(&ops);
+    let mut i = 0;
+    while i < ops.len() {
+        let mut fewer = ops.clone();
+        fewer.remove(i);
+        if linearizable(&fewer) || (keep_grounded && !grounded(&fewer)) {
+            i += 1;
+        } else {
+            ops = fewer;
+        }
+    }
+    ops
+}
+
+/// Whether every value read was written by one of `ops`
+fn grounded(ops: &[Operation]) -> bool {
+    ops.iter().all(|op| match op.effect {
+        Effect::Read(Some(ref read)) => ops.iter().any(|other| match other.effect {
+            Effect::Write(ref written) => written == read,
+            Effect::Read(_)            => false,
+        }),
+        _ => true,
+    })
+}
+
+#[allow(dead_code)]
+fn reply(client: &str, mid: &str, msg: MsgType) -> Msg {
+    use super::msg::BaseMsg;
+    Msg::new(BaseMsg::new(NodeId::from("0000"), NodeId::from(client), NodeId::from("0000"), mid.to_owned()), msg)
+}
+
+#[test]
+fn test_concurrent_history_is_linearizable() {
+    let s = |x: &str| x.to_owned();
+    let mut history = History::new();
+    history.invoke(NodeId::from("C001"), "a", 0, &MsgType::Put(s("x"), s("1")));
+    history.invoke(NodeId::from("C002"), "b", 1, &MsgType::Get(s("x")));
+    history.invoke(NodeId::from("C003"), "c", 2, &MsgType::Put(s("x"), s("2")));
+    history.complete(&reply("C002", "b", MsgType::OK(s("2"))), 3);
+    history.complete(&reply("C001", "a", MsgType::OK(s("1"))), 4);
+    history.invoke(NodeId::from("C002"), "d", 5, &MsgType::Get(s("x")));
+    history.complete(&reply("C002", "d", MsgType::OK(s("1"))), 6);
+    history.invoke(NodeId::from("C002"), "e", 7, &MsgType::Get(s("y")));
+    history.complete(&reply("C002", "e", MsgType::Redirect), 8);
+    history.complete(&reply("C002", "e", MsgType::Fail), 9);
+    assert!(check(&history).is_ok());
+
+    let mut written = vec![];
+    history.write(&mut written).unwrap();
+    let read = History::read(&written[..]).unwrap();
+    assert_eq!(read.events(), history.events());
+}
+
+#[test]
+fn test_stale_read_is_reported_minimally() {
+    let s = |x: &str| x.to_owned();
+    let mut history = History::new();
+    history.invoke(NodeId::from("C001"), "a", 0, &MsgType::Put(s("x"), s("1")));
+    history.complete(&reply("C001", "a", MsgType::OK(s("1"))), 1);
+    history.invoke(NodeId::from("C002"), "b", 2, &MsgType::Get(s("x")));
+    history.complete(&reply("C002", "b", MsgType::OK(s("1"))), 3);
+    history.invoke(NodeId::from("C001"), "c", 4, &MsgType::Put(s("x"), s("2")));
+    history.complete(&reply("C001", "c", MsgType::OK(s("2"))), 5);
+    history.invoke(NodeId::from("C003"), "d", 6, &MsgType::Put(s("y"), s("3")));
+    history.invoke(NodeId::from("C002"), "e", 7, &MsgType::Get(s("x")));
+    history.complete(&reply("C002", "e", MsgType::OK(s("1"))), 8);
+
+    let violation = check(&history).unwrap_err();
+    assert_eq!(violation.key, "x");
+    let mids: Vec<&str> = violation.events.iter().map(|event| match *event {
+        Event::Invoke {ref mid, ..} | Event::Complete {ref mid, ..} => &mid[..],
+    }).collect();
+    assert_eq!(mids, vec!["a", "a", "c", "c", "e", "e"]);
+}
@@ -0,0 +1,344 @@
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::mem;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::mpsc;
+
+use rand::{self, Rng};
+use rustc_serialize::json::{Json, ToJson};
+use schedule_recv::oneshot_ms;
+
+use super::clock::{Clock, SystemClock, WallClock};
+use super::msg::{Msg, MsgType, Snapshot, FIRST_GROUP};
+use super::node::{Node, NodeId};
+use super::settings::Settings;
+use super::shard::{KeyRange, RoutingTable, Shard};
+use super::state_machine::{KvStore, StateMachine};
+use super::storage::Storage;
+use super::trace::{Level, Tracer};
+use super::transport::Transport;
+
+/// Groups other than the first keep their storage in a directory of this
+/// name plus the group's, inside the first group's
+const GROUP_DIR_PREFIX: &'static str = "group-";
+
+/// MIDs of the requests a host makes of its own groups to absorb a retired
+/// neighbour
+const ABSORB_MID_PREFIX: &'static str = "absorb-";
+
+type Group = Node<Shard<KvStore>>;
+
+/// Stamps everything a group sends with the group, so the host on the other
+/// end hands it to the same group there
+struct GroupTransport {
+    group: String,
+    inner: Rc<Transport>,
+}
+
+impl Transport for GroupTransport {
+    fn send(&self, msg: &Msg) {
+        if msg.base.group == self.group {
+            self.inner.send(msg);
+        } else {
+            let mut msg = msg.clone();
+            msg.base.group = self.group.clone();
+            self.inner.send(&msg);
+        }
+    }
+}
+
+/// Every consensus group this process is a replica of, sharing its one
+/// transport. Each group has its own log, term and leader, and serves one
+/// range of keys.
+///
+/// Client requests for a key go to whichever local group serves it; other
+/// messages go to the group they name. Splitting a group is a command in its
+/// log, and every replica starts the new group from its own copy of the
+/// split. Merging retires a group through its log; the leader of the group
+/// just before it then proposes taking over its keys. Groups share a
+/// membership only from the moment they split: membership changes and
+/// leadership transfers are sent to one group at a time, and a watch keeps
+/// to the group that started it.
+pub struct Host {
+    settings: Settings,
+    transport: Rc<Transport>,
+    clock: Box<Clock>,
+    /// Make each group's clock and random numbers, and the host's own clock
+    clocks: Box<Fn() -> Box<Clock>>,
+    rngs: Box<FnMut() -> Box<Rng>>,
+    tracer: Tracer,
+    groups: BTreeMap<String, Group>,
+    routes: RoutingTable,
+    /// When to next look for retired groups to absorb
+    absorb_at: u64,
+}
+
+impl Host {
+    /// Opens the first group, serving every key until it splits, and every
+    /// other group this process has storage for
+    pub fn new(settings: Settings, transport: Rc<Transport>) -> Host {
+        Host::with_clocks(settings,
+                          transport,
+                          Box::new(|| Box::new(SystemClock) as Box<Clock>),
+                          Box::new(|| Box::new(rand::thread_rng()) as Box<Rng>))
+    }
+
+    /// Like `new`, with every clock and source of randomness made by
+    /// `clocks` and `rngs`, as a simulation needs. Traces keep to the wall
+    /// clock.
+    pub fn with_clocks(settings: Settings,
+                       transport: Rc<Transport>,
+                       clocks: Box<Fn() -> Box<Clock>>,
+                       rngs: Box<FnMut() -> Box<Rng>>) -> Host {
+        let tracer = Tracer::from_settings(&settings, Box::new(WallClock))
+            .ok()
+            .expect("could not open trace sinks");
+        let mut host = Host {
+            settings: settings,
+            transport: transport,
+            clock: clocks(),
+            clocks: clocks,
+            rngs: rngs,
+            tracer: tracer,
+            groups: BTreeMap::new(),
+            routes: RoutingTable::new(),
+            absorb_at: 0,
+        };
+        host.open_group(FIRST_GROUP, Some(KeyRange::all()));
+        let dirs = fs::read_dir(&host.settings.storage_dir)
+            .ok()
+            .expect("could not read raft storage directory");
+        for dir in dirs.filter_map(Result::ok) {
+            let name = dir.file_name().to_string_lossy().into_owned();
+            if name.starts_with(GROUP_DIR_PREFIX) {
+                host.open_group(&name[GROUP_DIR_PREFIX.len()..], None);
+            }
+        }
+        host.settle();
+        host
+    }
+
+    /// Runs every group in real time on messages from `reader` until its
+    /// sender hangs up
+    pub fn main(mut self, reader: mpsc::Receiver<Msg>) {
+        loop {
+            let wait = self.deadline().saturating_sub(self.clock.now_ms());
+            let timer = oneshot_ms(wait as u32);
+            select! {
+                msg = reader.recv() => match msg {
+                    Ok(msg) => self.receive(msg),
+                    Err(_)  => return,
+                },
+                _   = timer.recv() => self.tick()
+            }
+        }
+    }
+
+    pub fn receive(&mut self, msg: Msg) {
+        if msg.msg.is_reply() && msg.base.mid.starts_with(ABSORB_MID_PREFIX) {
+            // Whether an absorb took shows in the absorbing group's range
+            return;
+        }
+        let group = match requested_keys(&msg.msg) {
+            Some(keys) => match self.route(&keys) {
+                Ok(group) => group,
+                Err(answer) => {
+                    let mut reply = msg;
+                    mem::swap(&mut reply.base.src, &mut reply.base.dst);
+                    reply.base.leader = NodeId::broadcast();
+                    reply.msg = answer;
+                    self.transport.send(&reply);
+                    return;
+                },
+            },
+            None => {
+                let group = msg.base.group.clone();
+                if !self.groups.contains_key(&group) && !self.may_join(&group, &msg.msg) {
+                    return;
+                }
+                if !self.groups.contains_key(&group) {
+                    self.open_group(&group, None);
+                }
+                group
+            },
+        };
+        self.groups.get_mut(&group).unwrap().receive(msg);
+        self.settle();
+    }
+
+    pub fn tick(&mut self) {
+        for group in self.groups.values_mut() {
+            group.tick();
+        }
+        self.absorb_retired();
+        self.settle();
+    }
+
+    /// When `tick` next has something to do
+    pub fn deadline(&self) -> u64 {
+        self.groups.values().map(Group::deadline).fold(self.absorb_at, cmp::min)
+    }
+
+    pub fn groups(&self) -> &BTreeMap<String, Group> {
+        &self.groups
+    }
+
+    fn group_dir(&self, group: &str) -> PathBuf {
+        if group == FIRST_GROUP {
+            self.settings.storage_dir.clone()
+        } else {
+            self.settings.storage_dir.join(format!("{}{}", GROUP_DIR_PREFIX, group))
+        }
+    }
+
+    /// Starts our replica of `group` from whatever it has in storage, or as
+    /// serving `range` if nothing
+    fn open_group(&mut self, group: &str, range: Option<KeyRange>) {
+        let mut settings = self.settings.clone();
+        settings.storage_dir = self.group_dir(group);
+        if group != FIRST_GROUP {
+            // Other groups learn their members from the snapshot they start
+            // from. Until it comes we have no say in who leads.
+            settings.learner = true;
+        }
+        let storage = Storage::open(&settings.storage_dir)
+            .ok()
+            .expect("could not open raft storage directory");
+        let tracer = Tracer::from_settings(&settings, Box::new(WallClock))
+            .ok()
+            .expect("could not open trace sinks")
+            .in_group(group);
+        let transport = GroupTransport {
+            group: group.to_owned(),
+            inner: self.transport.clone(),
+        };
+        let node = Node::new(settings,
+                             storage,
+                             Shard::new(group, range, KvStore::new()),
+                             Box::new(transport),
+                             (self.clocks)(),
+                             (self.rngs)(),
+                             tracer);
+        self.tracer.event(Level::Info, node.term(), "group_opened", &[("group", group.to_json())]);
+        self.groups.insert(group.to_owned(), node);
+    }
+
+    /// The group serving all of `keys`. A key no group serves gets a
+    /// redirect, so the client tries a host that knows more; keys in
+    /// several groups can't be written together.
+    fn route(&self, keys: &[&str]) -> Result<String, MsgType> {
+        let mut routed = None;
+        for key in keys {
+            let group = try!(self.routes.route(key).ok_or(MsgType::Redirect));
+            if routed.map_or(false, |routed| routed != group) {
+                return Err(MsgType::Fail);
+            }
+            routed = Some(group);
+        }
+        Ok(routed.unwrap_or(FIRST_GROUP).to_owned())
+    }
+
+    /// Whether a message for a group we have no replica of means we should
+    /// start one: its leader is trying to bring us up to date, and it is not
+    /// a group that has since been merged away
+    fn may_join(&self, group: &str, msg: &MsgType) -> bool {
+        match *msg {
+            MsgType::AppendEntries { .. } | MsgType::InstallSnapshot { .. } => !self.absorbed(group),
+            _ => false,
+        }
+    }
+
+    fn absorbed(&self, group: &str) -> bool {
+        self.groups.values().any(|other| other.state_machine().absorbed().contains(group))
+    }
+
+    /// Starts the groups our replicas have split off, drops those merged
+    /// away, and brings the routing table up to date
+    fn settle(&mut self) {
+        let mut forks = vec![];
+        for group in self.groups.values_mut() {
+            let mut spawned: HashMap<String, Json> = group.state_machine_mut().take_spawned().into_iter().collect();
+            for fork in group.take_forks() {
+                if let Some(data) = spawned.remove(&fork.group) {
+                    forks.push((fork, data));
+                }
+            }
+        }
+        for (fork, data) in forks {
+            // Replaying a split on restart finds its group already there
+            if self.groups.contains_key(&fork.group) || self.absorbed(&fork.group) {
+                continue;
+            }
+            let mut snapshot = Snapshot::new(1, 0, data);
+            snapshot.sessions = fork.sessions;
+            snapshot.config = Some(fork.config);
+            Storage::open(self.group_dir(&fork.group))
+                .and_then(|storage| storage.save_snapshot(&snapshot))
+                .ok()
+                .expect("could not save a new group's snapshot");
+            self.open_group(&fork.group, None);
+        }
+
+        let merged: Vec<String> = self.groups.keys().filter(|group| self.absorbed(group)).cloned().collect();
+        for group in merged {
+            let node = self.groups.remove(&group).unwrap();
+            self.tracer.event(Level::Info, node.term(), "group_dropped", &[("group", group.to_json())]);
+            drop(fs::remove_dir_all(self.group_dir(&group)));
+        }
+
+        self.routes = RoutingTable::new();
+        for (name, group) in &self.groups {
+            let shard = group.state_machine();
+            if let (Some(range), false) = (shard.range(), shard.is_retired()) {
+                self.routes.insert(range.clone(), name);
+            }
+        }
+    }
+
+    /// Asks each group we lead to take over its retired right neighbour,
+    /// with the neighbour's final state as our replica of it has it
+    fn absorb_retired(&mut self) {
+        let now = self.clock.now_ms();
+        if now < self.absorb_at {
+            return;
+        }
+        self.absorb_at = now + self.settings.election_timeout_max;
+
+        let mut merges = vec![];
+        for (name, retired) in &self.groups {
+            let start = match retired.state_machine().range() {
+                Some(range) if retired.state_machine().is_retired() => Some(range.start.clone()),
+                _                                                   => continue,
+            };
+            let left = self.groups.iter().find(|&(_, left)| {
+                let shard = left.state_machine();
+                left.is_leader() && !shard.is_retired() && shard.range().map_or(false, |range| range.end == start)
+            });
+            if let Some((left, _)) = left {
+                merges.push((left.clone(), name.clone(), retired.state_machine().snapshot(), retired.sessions().clone()));
+            }
+        }
+        for (left, name, data, sessions) in merges {
+            let mid = format!("{}{}", ABSORB_MID_PREFIX, name);
+            self.groups.get_mut(&left).unwrap().propose_merge(&mid, &name, &data, &sessions);
+        }
+    }
+}
+
+/// The keys a client request is about, if it is about any
+fn requested_keys(msg: &MsgType) -> Option<Vec<&str>> {
+    match *msg {
+        MsgType::Get(ref key)
+            | MsgType::Put(ref key, _)
+            | MsgType::Delete(ref key)
+            | MsgType::Cas { ref key, .. }
+            | MsgType::Append(ref key, _)
+            | MsgType::Watch { ref key, .. }
+            | MsgType::Split(ref key)
+            | MsgType::Merge(ref key) => Some(vec![key]),
+        MsgType::Batch(ref puts) => Some(puts.iter().map(|&(ref key, _)| &key[..]).collect()),
+        _ => None,
+    }
+}
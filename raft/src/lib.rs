@@ -0,0 +1,28 @@
+#![feature(mpsc_select)]
+#![feature(slice_patterns)]
+extern crate itertools;
+extern crate rand;
+extern crate rustc_serialize;
+extern crate schedule_recv;
+extern crate time;
+extern crate unix_socket;
+
+pub mod clock;
+pub mod codec;
+pub mod history;
+pub mod host;
+pub mod membership;
+pub mod msg;
+pub mod node;
+pub mod port;
+pub mod session;
+pub mod settings;
+pub mod shard;
+pub mod sim;
+pub mod state_machine;
+pub mod status;
+pub mod storage;
+pub mod tcp;
+pub mod trace;
+pub mod transport;
+pub mod watch;
@@ -1,38 +1,53 @@
-#![feature(mpsc_select)]
-#![feature(slice_patterns)]
-extern crate itertools;
-extern crate rand;
-extern crate rustc_serialize;
-extern crate schedule_recv;
+extern crate raft;
 extern crate unix_socket;
 
-pub mod msg;
-pub mod node;
-pub mod port;
-
 use std::env;
+use std::process;
+use std::rc::Rc;
 use std::thread;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc};
 
 use unix_socket::UnixStream;
 
-use node::Node;
-use port::Port;
+use raft::clock::WallClock;
+use raft::codec::Links;
+use raft::host::Host;
+use raft::port::Port;
+use raft::settings::Settings;
+use raft::tcp::TcpTransport;
+use raft::trace::Tracer;
+use raft::transport::{SocketTransport, Transport};
 
 fn main() {
-    let mut args = env::args().skip(1);
-    let my_id = match args.next() {
-        Some(id) => id,
-        None     => panic!("first command line argument must be unix socket name")
+    let settings = match Settings::from_args(env::args().skip(1)) {
+        Ok(settings) => settings,
+        Err(e)       => {
+            println!("{}", e);
+            println!("usage: raft [--config FILE] [--addresses ID=HOST:PORT,...] [--SETTING VALUE]... ID PEER...");
+            process::exit(2)
+        },
     };
-
     let (sender, receiver) = mpsc::channel();
 
-    let right_sock = UnixStream::connect(&my_id).unwrap();
-    let left_sock = right_sock.try_clone().unwrap();
-
-    let port = Port::new(right_sock, sender);
-    thread::spawn(move || port.relay());
-    let node = Node::new(receiver, left_sock, my_id, args);
-    node.main();
+    // Peers fall back to JSON unless both ends of a link opt in
+    let links = Arc::new(Links::new(settings.binary_codec));
+
+    let transport: Rc<Transport> = if settings.addresses.is_empty() {
+        let right_sock = UnixStream::connect(&settings.socket).unwrap();
+        let left_sock = right_sock.try_clone().unwrap();
+        let port = Port::new(right_sock, sender, links.clone());
+        let tracing = settings.clone();
+        thread::spawn(move || {
+            let tracer = Tracer::from_settings(&tracing, Box::new(WallClock)).ok().expect("could not open trace sinks");
+            port.relay(&tracer)
+        });
+        Rc::new(SocketTransport::new(left_sock, links))
+    } else {
+        Rc::new(TcpTransport::start(&settings, links, sender).unwrap_or_else(|e| {
+            println!("could not listen: {}", e);
+            process::exit(1)
+        }))
+    };
+    let host = Host::new(settings, transport);
+    host.main(receiver);
 }
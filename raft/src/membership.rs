@@ -0,0 +1,200 @@
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use rustc_serialize::json::{Json, ToJson};
+
+use super::msg::{AddJson, DecodeError, DecodeResult};
+use super::node::NodeId;
+
+/// The members of the cluster. While a membership change is underway the
+/// configuration is joint: decisions need a majority of both the old and the
+/// new voters. Learners are sent the log but never vote or count toward a
+/// quorum.
+#[derive(PartialEq, Eq, Debug, Clone, Hash)]
+pub struct Configuration {
+    pub voters: BTreeSet<NodeId>,
+    pub old_voters: Option<BTreeSet<NodeId>>,
+    pub learners: BTreeSet<NodeId>,
+}
+
+impl Configuration {
+    pub fn new<I>(voters: I) -> Configuration
+        where I: IntoIterator<Item=NodeId>
+    {
+        Configuration {
+            voters: voters.into_iter().collect(),
+            old_voters: None,
+            learners: BTreeSet::new(),
+        }
+    }
+
+    pub fn is_joint(&self) -> bool {
+        self.old_voters.is_some()
+    }
+
+    /// Every node that votes in either half of the configuration, and every
+    /// learner
+    pub fn members(&self) -> BTreeSet<NodeId> {
+        let mut members = self.voters.union(&self.learners).cloned().collect::<BTreeSet<_>>();
+        if let Some(ref old) = self.old_voters {
+            members.extend(old.iter().cloned());
+        }
+        members
+    }
+
+    pub fn is_voter(&self, id: &NodeId) -> bool {
+        self.voters.contains(id)
+            || self.old_voters.as_ref().map_or(false, |old| old.contains(id))
+    }
+
+    pub fn is_learner(&self, id: &NodeId) -> bool {
+        self.learners.contains(id)
+    }
+
+    /// The joint configuration that moves from our voters to `voters`. A
+    /// learner that becomes a voter stops being a learner.
+    pub fn transition(&self, voters: BTreeSet<NodeId>) -> Configuration {
+        Configuration {
+            learners: self.learners.difference(&voters).cloned().collect(),
+            voters: voters,
+            old_voters: Some(self.voters.clone()),
+        }
+    }
+
+    /// The new half of a joint configuration on its own
+    pub fn finish(&self) -> Configuration {
+        Configuration {
+            voters: self.voters.clone(),
+            old_voters: None,
+            learners: self.learners.clone(),
+        }
+    }
+
+    /// The same voters with `learners` replacing ours. Learners don't vote,
+    /// so this never needs a joint configuration.
+    pub fn with_learners(&self, learners: BTreeSet<NodeId>) -> Configuration {
+        Configuration {
+            voters: self.voters.clone(),
+            old_voters: self.old_voters.clone(),
+            learners: learners,
+        }
+    }
+
+    pub fn is_quorum(&self, ids: &HashSet<NodeId>) -> bool {
+        is_majority(&self.voters, ids)
+            && self.old_voters.as_ref().map_or(true, |old| is_majority(old, ids))
+    }
+
+    /// Highest log index that a quorum of voters is known to hold
+    pub fn quorum_index(&self, matches: &HashMap<NodeId, u64>) -> u64 {
+        let new = majority_index(&self.voters, matches);
+        match self.old_voters {
+            Some(ref old) => cmp::min(new, majority_index(old, matches)),
+            None          => new,
+        }
+    }
+}
+
+fn is_majority(voters: &BTreeSet<NodeId>, ids: &HashSet<NodeId>) -> bool {
+    voters.iter().filter(|id| ids.contains(id)).count() * 2 > voters.len()
+}
+
+fn majority_index(voters: &BTreeSet<NodeId>, matches: &HashMap<NodeId, u64>) -> u64 {
+    let mut indices: Vec<u64> = voters.iter()
+        .map(|id| matches.get(id).cloned().unwrap_or(0))
+        .collect();
+    indices.sort_by(|a, b| b.cmp(a));
+    indices.get(indices.len() / 2).cloned().unwrap_or(0)
+}
+
+fn ids_to_json(ids: &BTreeSet<NodeId>) -> Json {
+    Json::Array(ids.iter().map(ToJson::to_json).collect())
+}
+
+fn ids_from_json(json: &Json, key: &'static str) -> DecodeResult<BTreeSet<NodeId>> {
+    let ids = try!(json.as_array().ok_or(DecodeError::WrongType(key)));
+    ids.iter()
+        .map(|id| match id.as_string() {
+            Some(name) => NodeId::from_str(name).ok_or(DecodeError::BadNodeId(name.to_owned())),
+            None       => Err(DecodeError::WrongType(key)),
+        })
+        .collect()
+}
+
+impl Configuration {
+    pub fn from_json(json: &Json) -> DecodeResult<Configuration> {
+        let voters = try!(json.find("voters").ok_or(DecodeError::MissingField("voters")));
+        Ok(Configuration {
+            voters: try!(ids_from_json(voters, "voters")),
+            old_voters: match json.find("old_voters") {
+                Some(old) => Some(try!(ids_from_json(old, "old_voters"))),
+                None      => None,
+            },
+            learners: match json.find("learners") {
+                Some(learners) => try!(ids_from_json(learners, "learners")),
+                None           => BTreeSet::new(),
+            },
+        })
+    }
+}
+
+impl ToJson for Configuration {
+    fn to_json(&self) -> Json {
+        let mut d = BTreeMap::new();
+        d.add_json("voters", ids_to_json(&self.voters));
+        if let Some(ref old) = self.old_voters {
+            d.add_json("old_voters", ids_to_json(old));
+        }
+        if !self.learners.is_empty() {
+            d.add_json("learners", ids_to_json(&self.learners));
+        }
+        Json::Object(d)
+    }
+}
+
+#[allow(dead_code)]
+fn ids(names: &[&str]) -> BTreeSet<NodeId> {
+    names.iter().map(|name| NodeId::from(*name)).collect()
+}
+
+#[test]
+fn test_joint_quorum_needs_both_halves() {
+    let config = Configuration::new(ids(&["0000", "0001", "0002"]))
+        .transition(ids(&["0001", "0002", "0003", "0004"]));
+    assert!(config.is_joint());
+    assert_eq!(config.members().len(), 5);
+
+    let votes = ["0001", "0002"].iter().map(|name| NodeId::from(*name)).collect();
+    assert!(!config.is_quorum(&votes));
+    let votes = ["0001", "0002", "0003"].iter().map(|name| NodeId::from(*name)).collect();
+    assert!(config.is_quorum(&votes));
+
+    let mut matches = HashMap::new();
+    matches.insert(NodeId::from("0000"), 9);
+    matches.insert(NodeId::from("0001"), 7);
+    matches.insert(NodeId::from("0003"), 8);
+    matches.insert(NodeId::from("0004"), 8);
+    assert_eq!(config.quorum_index(&matches), 7);
+    assert_eq!(config.finish().quorum_index(&matches), 7);
+    assert_eq!(Configuration::from_json(&config.to_json()).unwrap(), config);
+}
+
+#[test]
+fn test_learners_never_count_toward_quorum() {
+    let config = Configuration::new(ids(&["0000", "0001", "0002"])).with_learners(ids(&["0003", "0004"]));
+    assert_eq!(config.members().len(), 5);
+    assert!(!config.is_voter(&NodeId::from("0003")));
+
+    let votes = ["0000", "0003", "0004"].iter().map(|name| NodeId::from(*name)).collect();
+    assert!(!config.is_quorum(&votes));
+    let mut matches = HashMap::new();
+    matches.insert(NodeId::from("0000"), 9);
+    matches.insert(NodeId::from("0003"), 9);
+    matches.insert(NodeId::from("0004"), 9);
+    assert_eq!(config.quorum_index(&matches), 0);
+
+    let promoted = config.transition(ids(&["0000", "0001", "0002", "0003"]));
+    assert_eq!(promoted.learners, ids(&["0004"]));
+    assert_eq!(promoted.finish().learners, ids(&["0004"]));
+    assert_eq!(Configuration::from_json(&config.to_json()).unwrap(), config);
+}
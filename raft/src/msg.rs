@@ -1,21 +1,64 @@
 use std::collections::BTreeMap;
-use std::convert::From;
+use std::error::Error;
+use std::fmt;
 
 use rustc_serialize::json::{Json, Object, ToJson};
 
+use super::membership::Configuration;
 use super::node::NodeId;
+use super::session::Sessions;
+use super::status::NodeStatus;
 
 macro_rules! get {
     ($obj:ident -> $key:expr; $parser:path) => {{
-        let err = format!("Json parse failed for {}", $key);
-        $parser($obj.as_object()
-                .expect("get not provided an object")
-                .get($key)
-                .expect(&err))
-            .expect(&err)
+        let field = try!($obj.find($key).ok_or(DecodeError::MissingField($key)));
+        try!($parser(field).ok_or(DecodeError::WrongType($key)))
     }}
 }
 
+/// Why a message or one of its parts could not be decoded
+#[derive(Clone, PartialEq, Debug)]
+pub enum DecodeError {
+    /// Not valid UTF-8 or JSON, or not a JSON object
+    Malformed(String),
+    MissingField(&'static str),
+    WrongType(&'static str),
+    UnknownType(String),
+    BadNodeId(String),
+}
+
+pub type DecodeResult<T> = Result<T, DecodeError>;
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::Malformed(ref why) => write!(f, "malformed message: {}", why),
+            DecodeError::MissingField(field) => write!(f, "missing field {:?}", field),
+            DecodeError::WrongType(field) => write!(f, "field {:?} has the wrong type", field),
+            DecodeError::UnknownType(ref typ) => write!(f, "unknown message type {:?}", typ),
+            DecodeError::BadNodeId(ref id) => write!(f, "bad node id {:?}", id),
+        }
+    }
+}
+
+impl Error for DecodeError {
+    fn description(&self) -> &str {
+        match *self {
+            DecodeError::Malformed(_) => "malformed message",
+            DecodeError::MissingField(_) => "missing field",
+            DecodeError::WrongType(_) => "field has the wrong type",
+            DecodeError::UnknownType(_) => "unknown message type",
+            DecodeError::BadNodeId(_) => "bad node id",
+        }
+    }
+}
+
+/// Reads the node id in `obj[key]`
+pub fn get_node_id(obj: &Json, key: &'static str) -> DecodeResult<NodeId> {
+    let id = get!(obj -> key; Json::as_string);
+    NodeId::from_str(id).ok_or(DecodeError::BadNodeId(id.to_owned()))
+}
+
 #[derive(Clone, PartialEq, Debug)]
 pub struct Msg {
     pub base: BaseMsg,
@@ -30,14 +73,19 @@ impl Msg {
         }
     }
 
-    pub fn from_str(s: &str) -> Msg {
-        let raw = Json::from_str(s)
-            .ok()
-            .expect("parsing json from str failed");
-        let base = BaseMsg::from(&raw);
-        let msg = MsgType::from(&raw);
+    pub fn from_str(s: &str) -> DecodeResult<Msg> {
+        let raw = try!(Json::from_str(s).map_err(|e| DecodeError::Malformed(e.to_string())));
+        Msg::from_json(&raw)
+    }
+
+    pub fn from_json(raw: &Json) -> DecodeResult<Msg> {
+        if !raw.is_object() {
+            return Err(DecodeError::Malformed("not a JSON object".to_owned()));
+        }
+        let base = try!(BaseMsg::from_json(raw));
+        let msg = try!(MsgType::from_json(raw));
 
-        Msg { base: base, msg: msg }
+        Ok(Msg { base: base, msg: msg })
     }
 }
 
@@ -51,12 +99,18 @@ impl ToJson for Msg {
     }
 }
 
+/// The consensus group every process starts with. Messages for it leave
+/// out their `group`, so a single-group cluster looks as it always has.
+pub const FIRST_GROUP: &'static str = "0";
+
 #[derive(Clone, PartialEq, Debug)]
 pub struct BaseMsg {
     pub src: NodeId,
     pub dst: NodeId,
     pub leader: NodeId,
     pub mid: String,
+    /// The consensus group the message belongs to; `leader` is that group's
+    pub group: String,
 }
 
 impl BaseMsg {
@@ -65,7 +119,8 @@ impl BaseMsg {
             src: src,
             dst: dst,
             leader: leader,
-            mid: mid
+            mid: mid,
+            group: FIRST_GROUP.to_owned(),
         }
     }
 
@@ -74,17 +129,22 @@ impl BaseMsg {
         d.add_json("dst", self.dst);
         d.add_json("leader", self.leader);
         d.add_json("MID", self.mid.to_owned());
+        if self.group != FIRST_GROUP {
+            d.add_json("group", self.group.to_owned());
+        }
     }
-}
 
-impl <'a>From<&'a Json> for BaseMsg {
-    fn from(obj: &'a Json) -> BaseMsg {
-        BaseMsg {
-            leader: get!(obj -> "leader"; NodeId::as_node_id),
-            src: get!(obj -> "src"; NodeId::as_node_id),
-            dst: get!(obj -> "dst"; NodeId::as_node_id),
-            mid: get!(obj -> "MID"; Json::as_string).to_owned()
-        }
+    pub fn from_json(obj: &Json) -> DecodeResult<BaseMsg> {
+        Ok(BaseMsg {
+            leader: try!(get_node_id(obj, "leader")),
+            src: try!(get_node_id(obj, "src")),
+            dst: try!(get_node_id(obj, "dst")),
+            mid: get!(obj -> "MID"; Json::as_string).to_owned(),
+            group: match obj.find("group") {
+                Some(group) => try!(group.as_string().ok_or(DecodeError::WrongType("group"))).to_owned(),
+                None        => FIRST_GROUP.to_owned(),
+            },
+        })
     }
 }
 
@@ -94,8 +154,59 @@ pub enum MsgType {
     Fail,
     Redirect,
     OK(String),
+    NotFound,
+    CasFailed(Option<String>),
     Get(String),
     Put(String, String),
+    Delete(String),
+    Cas {
+        key: String,
+        expected: Option<String>,
+        new: String,
+    },
+    Append(String, String),
+    Batch(Vec<(String, String)>),
+    AddServer(NodeId),
+    RemoveServer(NodeId),
+    AddLearner(NodeId),
+    PromoteLearner(NodeId),
+    TransferLeader(NodeId),
+    Status,
+    StatusReport(NodeStatus),
+    /// Streams changes to `key`, or to every key starting with it if
+    /// `prefix`, applied at `from_index` or later; 0 means from now on
+    Watch {
+        key: String,
+        prefix: bool,
+        from_index: u64,
+    },
+    /// Ends the watch started with the same MID
+    Unwatch,
+    /// `key` was set to `value`, or deleted if it is `None`, by the entry
+    /// at `index`
+    WatchEvent {
+        index: u64,
+        key: String,
+        value: Option<String>,
+    },
+    /// A follower relaying `client`'s request to the leader. The leader
+    /// deduplicates it as `client_mid` from `client`, but answers the
+    /// follower, under the MID it forwarded with.
+    Forward {
+        client: NodeId,
+        client_mid: String,
+        request: Box<MsgType>,
+    },
+    /// A watch asked for changes older than the leader remembers; the
+    /// oldest index it can resume from is given
+    Compacted(u64),
+    /// Splits the group owning `key` in two at it; the reply names the new
+    /// group, which owns `key` and everything after
+    Split(String),
+    /// Folds the group owning `key` into the group just before it. The
+    /// group stops serving keys once this commits, and its neighbour takes
+    /// them over shortly after.
+    Merge(String),
     AppendEntries {
         details: InternalMsg,
         leader_commit: u64,
@@ -105,44 +216,123 @@ pub enum MsgType {
         success: bool,
         match_index: u64,
         commit_idx: u64,
+        conflict_term: u64,
+        conflict_index: u64,
     },
     RequestVote {
         details: InternalMsg,
         candidate_id: NodeId,
     },
     RVResp(u64, bool),
+    PreVote {
+        details: InternalMsg,
+        candidate_id: NodeId,
+    },
+    PVResp(u64, bool),
+    InstallSnapshot {
+        term: u64,
+        snapshot: Snapshot,
+    },
+    ISResp {
+        term: u64,
+        match_index: u64,
+    },
+    TimeoutNow(u64),
 }
 
 impl MsgType {
     fn fill(&self, d: &mut Object) {
         d.add_json("type", self.name().to_owned());
         match *self {
-            MsgType::Fail | MsgType::Redirect => return,
+            MsgType::Fail | MsgType::Redirect | MsgType::NotFound | MsgType::Status | MsgType::Unwatch => return,
+            MsgType::StatusReport(ref status) => d.add_json("status", status.to_json()),
+            MsgType::Watch {ref key, prefix, from_index} => {
+                d.add_json("key", key.to_owned());
+                d.add_json("prefix", prefix);
+                d.add_json("from_index", from_index);
+            },
+            MsgType::WatchEvent {index, ref key, ref value} => {
+                d.add_json("index", index);
+                d.add_json("key", key.to_owned());
+                d.add_json("value", value.clone());
+            },
+            MsgType::Compacted(index) => d.add_json("index", index),
+            MsgType::Split(ref key) | MsgType::Merge(ref key) => d.add_json("key", key.to_owned()),
+            MsgType::Forward {client, ref client_mid, ref request} => {
+                d.add_json("client", client);
+                d.add_json("client_mid", client_mid.to_owned());
+                let mut inner = BTreeMap::new();
+                request.fill(&mut inner);
+                d.add_json("request", Json::Object(inner));
+            },
             MsgType::OK(ref value) => d.add_json("value", value.to_owned()),
-            MsgType::Get(ref key) => d.add_json("key", key.to_owned()),
-            MsgType::Put(ref key, ref val) => {
+            MsgType::CasFailed(ref value) => d.add_json("value", value.clone()),
+            MsgType::Get(ref key) | MsgType::Delete(ref key) => d.add_json("key", key.to_owned()),
+            MsgType::Put(ref key, ref val) | MsgType::Append(ref key, ref val) => {
                 d.add_json("key", key.to_owned());
                 d.add_json("value", val.to_owned());
             },
+            MsgType::Cas {ref key, ref expected, ref new} => {
+                d.add_json("key", key.to_owned());
+                d.add_json("expected", expected.clone());
+                d.add_json("value", new.to_owned());
+            },
+            MsgType::Batch(ref puts) => d.add_json("puts", puts_to_json(puts)),
+            MsgType::AddServer(ref server)
+                | MsgType::RemoveServer(ref server)
+                | MsgType::AddLearner(ref server)
+                | MsgType::PromoteLearner(ref server)
+                | MsgType::TransferLeader(ref server) => {
+                d.add_json("server", *server);
+            },
             MsgType::AppendEntries {ref details, leader_commit, ref entries} => {
                 details.fill(d);
                 d.add_json("leader_commit", leader_commit);
                 d.add_json("entries", entries.clone());
             },
-            MsgType::AEResp {term, success, match_index, commit_idx} => {
+            MsgType::AEResp {term, success, match_index, commit_idx, conflict_term, conflict_index} => {
                 d.add_json("term", term);
                 d.add_json("success", success);
                 d.add_json("match_index", match_index);
                 d.add_json("commit_idx", commit_idx);
+                d.add_json("conflict_term", conflict_term);
+                d.add_json("conflict_index", conflict_index);
             },
             MsgType::RequestVote {ref details, ref candidate_id} => {
                 details.fill(d);
                 d.add_json("candidate_id", candidate_id.clone());
             },
-            MsgType::RVResp(term, vote) => {
+            MsgType::RVResp(term, vote) | MsgType::PVResp(term, vote) => {
                 d.add_json("term", term);
                 d.add_json("vote", vote);
-            }
+            },
+            MsgType::PreVote {ref details, ref candidate_id} => {
+                details.fill(d);
+                d.add_json("candidate_id", candidate_id.clone());
+            },
+            MsgType::InstallSnapshot {term, ref snapshot} => {
+                d.add_json("term", term);
+                d.add_json("snapshot", snapshot.to_json());
+            },
+            MsgType::ISResp {term, match_index} => {
+                d.add_json("term", term);
+                d.add_json("match_index", match_index);
+            },
+            MsgType::TimeoutNow(term) => d.add_json("term", term),
+        }
+    }
+
+    /// Whether this answers a client's request
+    pub fn is_reply(&self) -> bool {
+        match *self {
+            MsgType::OK(_)
+                | MsgType::NotFound
+                | MsgType::CasFailed(_)
+                | MsgType::Redirect
+                | MsgType::Fail
+                | MsgType::StatusReport(_)
+                | MsgType::Compacted(_) => true,
+            _ => false,
         }
     }
 
@@ -151,72 +341,171 @@ impl MsgType {
             MsgType::Fail => "fail",
             MsgType::Redirect => "redirect",
             MsgType::OK(_) => "ok",
+            MsgType::NotFound => "not_found",
+            MsgType::CasFailed(_) => "cas_failed",
             MsgType::Get(_) => "get",
             MsgType::Put(..) => "put",
+            MsgType::Delete(_) => "delete",
+            MsgType::Cas { .. } => "cas",
+            MsgType::Append(..) => "append",
+            MsgType::Batch(_) => "batch",
+            MsgType::AddServer(_) => "add_server",
+            MsgType::RemoveServer(_) => "remove_server",
+            MsgType::AddLearner(_) => "add_learner",
+            MsgType::PromoteLearner(_) => "promote_learner",
+            MsgType::TransferLeader(_) => "transfer_leader",
+            MsgType::Status => "status",
+            MsgType::StatusReport(_) => "status_report",
+            MsgType::Watch { .. } => "watch",
+            MsgType::Unwatch => "unwatch",
+            MsgType::WatchEvent { .. } => "watch_event",
+            MsgType::Compacted(_) => "compacted",
+            MsgType::Forward { .. } => "forward",
+            MsgType::Split(_) => "split",
+            MsgType::Merge(_) => "merge",
             MsgType::AppendEntries{ .. } => "append_entries",
             MsgType::AEResp { .. } => "ae_resp",
             MsgType::RequestVote{ .. } => "request_vote",
             MsgType::RVResp(..) => "rv_resp",
+            MsgType::PreVote{ .. } => "pre_vote",
+            MsgType::PVResp(..) => "pv_resp",
+            MsgType::InstallSnapshot{ .. } => "install_snapshot",
+            MsgType::ISResp { .. } => "is_resp",
+            MsgType::TimeoutNow(_) => "timeout_now",
+        }
+    }
+
+    /// The log command a client write request asks for
+    pub fn command(&self) -> Option<Command> {
+        match *self {
+            MsgType::Put(ref key, ref value) => Some(Command::Put(key.clone(), value.clone())),
+            MsgType::Delete(ref key) => Some(Command::Delete(key.clone())),
+            MsgType::Cas {ref key, ref expected, ref new} => Some(Command::Cas {
+                key: key.clone(),
+                expected: expected.clone(),
+                new: new.clone(),
+            }),
+            MsgType::Append(ref key, ref value) => Some(Command::Append(key.clone(), value.clone())),
+            MsgType::Batch(ref puts) => Some(Command::Batch(puts.clone())),
+            MsgType::Split(ref key) => Some(Command::Split(key.clone())),
+            MsgType::Merge(ref key) => Some(Command::Retire(key.clone())),
+            _ => None,
         }
     }
 
-    fn parse_append_entries(json: &Json) -> MsgType {
-        let int_msg = InternalMsg::from(json);
-        let obj = json.as_object().expect("parse_append_entries expects a JSON object");
-        let entries = obj
-            .get("entries")
-            .map_or(None, |entries| {
-                if entries.is_array() {
-                    Some(entries.as_array()
-                        .expect("entries must be an array")
-                        .iter()
-                        .map(Entry::from)
-                        .collect())
-                } else {
-                    None
-                }
-            });
-        MsgType::AppendEntries {
+    fn parse_append_entries(json: &Json) -> DecodeResult<MsgType> {
+        let int_msg = try!(InternalMsg::from_json(json));
+        let entries = match json.find("entries").and_then(Json::as_array) {
+            Some(entries) => Some(try!(entries.iter().map(Entry::from_json).collect())),
+            None          => None,
+        };
+        Ok(MsgType::AppendEntries {
             details: int_msg,
             leader_commit: get!(json -> "leader_commit"; Json::as_u64),
             entries: entries
-        }
+        })
     }
 
-    fn parse_ae_resp(json: &Json) -> MsgType {
-        MsgType::AEResp {
+    fn parse_ae_resp(json: &Json) -> DecodeResult<MsgType> {
+        Ok(MsgType::AEResp {
             term: get!(json -> "term"; Json::as_u64),
             success: get!(json -> "success"; Json::as_boolean),
             match_index: get!(json -> "match_index"; Json::as_u64),
             commit_idx: get!(json -> "commit_idx"; Json::as_u64),
-        }
+            // Replicas that predate the hints leave them out
+            conflict_term: json.find("conflict_term").and_then(Json::as_u64).unwrap_or(0),
+            conflict_index: json.find("conflict_index").and_then(Json::as_u64).unwrap_or(0),
+        })
     }
 
-    fn parse_request_vote(obj: &Json) -> MsgType {
-        let int_msg = InternalMsg::from(obj);
-        MsgType::RequestVote{
+    fn parse_request_vote(obj: &Json) -> DecodeResult<MsgType> {
+        let int_msg = try!(InternalMsg::from_json(obj));
+        Ok(MsgType::RequestVote{
             details: int_msg,
-            candidate_id: get!(obj -> "candidate_id"; NodeId::as_node_id)
-        }
+            candidate_id: try!(get_node_id(obj, "candidate_id"))
+        })
+    }
+
+    fn parse_pre_vote(obj: &Json) -> DecodeResult<MsgType> {
+        let int_msg = try!(InternalMsg::from_json(obj));
+        Ok(MsgType::PreVote{
+            details: int_msg,
+            candidate_id: try!(get_node_id(obj, "candidate_id"))
+        })
     }
-}
 
-impl <'a>From<&'a Json> for MsgType {
-    fn from(obj: &'a Json) -> MsgType {
-        match get!(obj -> "type"; Json::as_string) {
+    pub fn from_json(obj: &Json) -> DecodeResult<MsgType> {
+        Ok(match get!(obj -> "type"; Json::as_string) {
             "fail" => MsgType::Fail,
             "redirect" => MsgType::Redirect,
             "ok" => MsgType::OK(get!(obj -> "value"; Json::as_string).to_owned()),
+            "not_found" => MsgType::NotFound,
+            "cas_failed" => MsgType::CasFailed(optional_string(obj, "value")),
             "get" => MsgType::Get(get!(obj -> "key"; Json::as_string).to_owned()),
             "put" => MsgType::Put(get!(obj -> "key"; Json::as_string).to_owned(),
                                   get!(obj -> "value";Json::as_string).to_owned()),
-            "append_entries" => MsgType::parse_append_entries(obj),
-            "ae_resp" => MsgType::parse_ae_resp(obj),
-            "request_vote" => MsgType::parse_request_vote(obj),
+            "delete" => MsgType::Delete(get!(obj -> "key"; Json::as_string).to_owned()),
+            "cas" => MsgType::Cas {
+                key: get!(obj -> "key"; Json::as_string).to_owned(),
+                expected: optional_string(obj, "expected"),
+                new: get!(obj -> "value"; Json::as_string).to_owned(),
+            },
+            "append" => MsgType::Append(get!(obj -> "key"; Json::as_string).to_owned(),
+                                        get!(obj -> "value"; Json::as_string).to_owned()),
+            "batch" => MsgType::Batch(try!(puts_from_json(get!(obj -> "puts"; Some)))),
+            "add_server" => MsgType::AddServer(try!(get_node_id(obj, "server"))),
+            "remove_server" => MsgType::RemoveServer(try!(get_node_id(obj, "server"))),
+            "add_learner" => MsgType::AddLearner(try!(get_node_id(obj, "server"))),
+            "promote_learner" => MsgType::PromoteLearner(try!(get_node_id(obj, "server"))),
+            "transfer_leader" => MsgType::TransferLeader(try!(get_node_id(obj, "server"))),
+            "status" => MsgType::Status,
+            "status_report" => MsgType::StatusReport(try!(NodeStatus::from_json(get!(obj -> "status"; Some)))),
+            "watch" => MsgType::Watch {
+                key: get!(obj -> "key"; Json::as_string).to_owned(),
+                prefix: obj.find("prefix").and_then(Json::as_boolean).unwrap_or(false),
+                from_index: obj.find("from_index").and_then(Json::as_u64).unwrap_or(0),
+            },
+            "unwatch" => MsgType::Unwatch,
+            "watch_event" => MsgType::WatchEvent {
+                index: get!(obj -> "index"; Json::as_u64),
+                key: get!(obj -> "key"; Json::as_string).to_owned(),
+                value: optional_string(obj, "value"),
+            },
+            "compacted" => MsgType::Compacted(get!(obj -> "index"; Json::as_u64)),
+            "split" => MsgType::Split(get!(obj -> "key"; Json::as_string).to_owned()),
+            "merge" => MsgType::Merge(get!(obj -> "key"; Json::as_string).to_owned()),
+            "forward" => MsgType::Forward {
+                client: try!(get_node_id(obj, "client")),
+                client_mid: get!(obj -> "client_mid"; Json::as_string).to_owned(),
+                request: Box::new(try!(MsgType::from_json(get!(obj -> "request"; Some)))),
+            },
+            "append_entries" => try!(MsgType::parse_append_entries(obj)),
+            "ae_resp" => try!(MsgType::parse_ae_resp(obj)),
+            "request_vote" => try!(MsgType::parse_request_vote(obj)),
             "rv_resp" => MsgType::RVResp(get!(obj -> "term"; Json::as_u64),
                                          get!(obj -> "vote"; Json::as_boolean)),
-            _              => unreachable!("unknown message type"),
-        }
+            "pre_vote" => try!(MsgType::parse_pre_vote(obj)),
+            "pv_resp" => MsgType::PVResp(get!(obj -> "term"; Json::as_u64),
+                                         get!(obj -> "vote"; Json::as_boolean)),
+            "install_snapshot" => MsgType::InstallSnapshot {
+                term: get!(obj -> "term"; Json::as_u64),
+                snapshot: try!(Snapshot::from_json(get!(obj -> "snapshot"; Some))),
+            },
+            "is_resp" => MsgType::ISResp {
+                term: get!(obj -> "term"; Json::as_u64),
+                match_index: get!(obj -> "match_index"; Json::as_u64),
+            },
+            "timeout_now" => MsgType::TimeoutNow(get!(obj -> "term"; Json::as_u64)),
+            other => return Err(DecodeError::UnknownType(other.to_owned())),
+        })
+    }
+}
+
+impl ToJson for MsgType {
+    fn to_json(&self) -> Json {
+        let mut d = BTreeMap::new();
+        self.fill(&mut d);
+        Json::Object(d)
     }
 }
 
@@ -241,55 +530,323 @@ impl InternalMsg {
         d.add_json("last_entry", self.last_entry);
         d.add_json("last_entry_term", self.last_entry_term);
     }
-}
 
-impl <'a>From<&'a Json> for InternalMsg {
-    fn from(obj: &'a Json) -> InternalMsg {
-        InternalMsg {
+    pub fn from_json(obj: &Json) -> DecodeResult<InternalMsg> {
+        Ok(InternalMsg {
             term: get!(obj -> "term"; Json::as_u64),
             last_entry: get!(obj -> "last_entry"; Json::as_u64),
             last_entry_term: get!(obj -> "last_entry_term"; Json::as_u64),
-        }
+        })
     }
 }
 
 #[derive(PartialEq, Eq, Debug, Clone, Hash)]
 pub struct Entry {
-    pub key: String,
-    pub value: String,
+    pub command: Command,
     pub term: u64,
+    pub request: Option<RequestId>,
+}
+
+/// The client request that produced an entry, so that a retry of it can be
+/// recognized instead of applied twice
+#[derive(PartialEq, Eq, Debug, Clone, Hash)]
+pub struct RequestId {
+    pub client: NodeId,
+    pub mid: String,
+}
+
+impl RequestId {
+    pub fn new(client: NodeId, mid: String) -> RequestId {
+        RequestId {
+            client: client,
+            mid: mid,
+        }
+    }
+}
+
+/// What a log entry does once committed. Puts are encoded as bare
+/// `key`/`value` pairs, other client commands are tagged with an `op`, and
+/// configuration and no-op entries carry their own field.
+#[derive(PartialEq, Eq, Debug, Clone, Hash)]
+pub enum Command {
+    Put(String, String),
+    Delete(String),
+    /// Sets `key` to `new` only if it currently holds `expected`, where
+    /// `None` means the key must be absent
+    Cas {
+        key: String,
+        expected: Option<String>,
+        new: String,
+    },
+    Append(String, String),
+    /// Puts every pair, all in one step
+    Batch(Vec<(String, String)>),
+    /// Hands every key from the given one on to a new group
+    Split(String),
+    /// Stops the group serving keys, so that the group before it can take
+    /// them over. The key must be one the group serves.
+    Retire(String),
+    /// Takes over a retired group. Its state machine and sessions are kept
+    /// as JSON text so that commands stay hashable.
+    Merge {
+        from: String,
+        data: String,
+        sessions: String,
+    },
+    Config(Configuration),
+    Noop,
+}
+
+impl Command {
+    /// Every key the command may change
+    pub fn keys(&self) -> Vec<&str> {
+        match *self {
+            Command::Put(ref key, _)
+                | Command::Delete(ref key)
+                | Command::Cas { ref key, .. }
+                | Command::Append(ref key, _) => vec![key],
+            Command::Batch(ref puts) => puts.iter().map(|&(ref key, _)| &key[..]).collect(),
+            Command::Split(_)
+                | Command::Retire(_)
+                | Command::Merge { .. }
+                | Command::Config(_)
+                | Command::Noop => vec![],
+        }
+    }
 }
 
 impl Entry {
     pub fn new(key: &str, val: &str, term: u64) -> Entry {
         Entry {
-            key: key.to_owned(),
-            value: val.to_owned(),
+            command: Command::Put(key.to_owned(), val.to_owned()),
             term: term,
+            request: None,
         }
     }
-}
 
-impl <'a>From<&'a Json> for Entry {
-    fn from(entry: &'a Json) -> Entry {
+    pub fn command(command: Command, term: u64) -> Entry {
         Entry {
-            key: get!(entry -> "key"; Json::as_string).to_owned(),
-            value: get!(entry -> "value"; Json::as_string).to_owned(),
-            term: get!(entry -> "term"; Json::as_u64),
+            command: command,
+            term: term,
+            request: None,
+        }
+    }
+
+    pub fn config(config: Configuration, term: u64) -> Entry {
+        Entry {
+            command: Command::Config(config),
+            term: term,
+            request: None,
         }
     }
+
+    pub fn noop(term: u64) -> Entry {
+        Entry {
+            command: Command::Noop,
+            term: term,
+            request: None,
+        }
+    }
+
+    pub fn with_request(mut self, request: RequestId) -> Entry {
+        self.request = Some(request);
+        self
+    }
+
+    /// About how many bytes the entry takes up in an append, counting the
+    /// data it carries and a fixed allowance for everything else
+    pub fn size(&self) -> usize {
+        let data = match self.command {
+            Command::Put(ref key, ref value) | Command::Append(ref key, ref value) => key.len() + value.len(),
+            Command::Delete(ref key) => key.len(),
+            Command::Cas {ref key, ref expected, ref new} => {
+                key.len() + expected.as_ref().map_or(0, String::len) + new.len()
+            },
+            Command::Batch(ref puts) => puts.iter().fold(0, |sum, &(ref key, ref value)| {
+                sum + key.len() + value.len() + 16
+            }),
+            Command::Split(ref key) | Command::Retire(ref key) => key.len(),
+            Command::Merge {ref from, ref data, ref sessions} => from.len() + data.len() + sessions.len(),
+            Command::Config(ref config) => 8 * config.members().len(),
+            Command::Noop => 0,
+        };
+        let request = self.request.as_ref().map_or(0, |request| request.mid.len() + 8);
+        data + request + 32
+    }
+
+    pub fn from_json(entry: &Json) -> DecodeResult<Entry> {
+        let command = if let Some(config) = entry.find("config") {
+            Command::Config(try!(Configuration::from_json(config)))
+        } else if entry.find("noop").is_some() {
+            Command::Noop
+        } else {
+            match entry.find("op").and_then(Json::as_string) {
+                Some("delete") => Command::Delete(get!(entry -> "key"; Json::as_string).to_owned()),
+                Some("cas") => Command::Cas {
+                    key: get!(entry -> "key"; Json::as_string).to_owned(),
+                    expected: optional_string(entry, "expected"),
+                    new: get!(entry -> "value"; Json::as_string).to_owned(),
+                },
+                Some("append") => Command::Append(get!(entry -> "key"; Json::as_string).to_owned(),
+                                                  get!(entry -> "value"; Json::as_string).to_owned()),
+                Some("batch") => Command::Batch(try!(puts_from_json(get!(entry -> "puts"; Some)))),
+                Some("split") => Command::Split(get!(entry -> "key"; Json::as_string).to_owned()),
+                Some("retire") => Command::Retire(get!(entry -> "key"; Json::as_string).to_owned()),
+                Some("merge") => Command::Merge {
+                    from: get!(entry -> "from"; Json::as_string).to_owned(),
+                    data: get!(entry -> "data"; Some).to_string(),
+                    sessions: get!(entry -> "sessions"; Some).to_string(),
+                },
+                _ => Command::Put(get!(entry -> "key"; Json::as_string).to_owned(),
+                                  get!(entry -> "value"; Json::as_string).to_owned()),
+            }
+        };
+        let request = match entry.find("client") {
+            Some(_) => Some(RequestId::new(try!(get_node_id(entry, "client")),
+                                           get!(entry -> "MID"; Json::as_string).to_owned())),
+            None    => None,
+        };
+        Ok(Entry {
+            command: command,
+            term: get!(entry -> "term"; Json::as_u64),
+            request: request,
+        })
+    }
 }
 
 impl ToJson for Entry {
     fn to_json(&self) -> Json {
         let mut d = BTreeMap::new();
-        d.add_json("key", self.key.to_owned());
-        d.add_json("value", self.value.to_owned());
+        match self.command {
+            Command::Put(ref key, ref value) => {
+                d.add_json("key", key.to_owned());
+                d.add_json("value", value.to_owned());
+            },
+            Command::Delete(ref key) => {
+                d.add_json("op", "delete".to_owned());
+                d.add_json("key", key.to_owned());
+            },
+            Command::Cas {ref key, ref expected, ref new} => {
+                d.add_json("op", "cas".to_owned());
+                d.add_json("key", key.to_owned());
+                d.add_json("expected", expected.clone());
+                d.add_json("value", new.to_owned());
+            },
+            Command::Append(ref key, ref value) => {
+                d.add_json("op", "append".to_owned());
+                d.add_json("key", key.to_owned());
+                d.add_json("value", value.to_owned());
+            },
+            Command::Batch(ref puts) => {
+                d.add_json("op", "batch".to_owned());
+                d.add_json("puts", puts_to_json(puts));
+            },
+            Command::Split(ref key) => {
+                d.add_json("op", "split".to_owned());
+                d.add_json("key", key.to_owned());
+            },
+            Command::Retire(ref key) => {
+                d.add_json("op", "retire".to_owned());
+                d.add_json("key", key.to_owned());
+            },
+            Command::Merge {ref from, ref data, ref sessions} => {
+                d.add_json("op", "merge".to_owned());
+                d.add_json("from", from.to_owned());
+                d.add_json("data", Json::from_str(data).unwrap_or(Json::Null));
+                d.add_json("sessions", Json::from_str(sessions).unwrap_or(Json::Null));
+            },
+            Command::Config(ref config) => d.add_json("config", config.to_json()),
+            Command::Noop => d.add_json("noop", true),
+        }
         d.add_json("term", self.term);
+        if let Some(ref request) = self.request {
+            d.add_json("client", request.client);
+            d.add_json("MID", request.mid.to_owned());
+        }
         Json::Object(d)
     }
 }
 
+/// A compacted prefix of the log: the state machine and client sessions as
+/// of `last_index`, and the cluster configuration if one had been logged by then
+#[derive(Clone, PartialEq, Debug)]
+pub struct Snapshot {
+    pub last_index: u64,
+    pub last_term: u64,
+    pub data: Json,
+    pub sessions: Sessions,
+    pub config: Option<Configuration>,
+}
+
+impl Snapshot {
+    pub fn new(last_index: u64, last_term: u64, data: Json) -> Snapshot {
+        Snapshot {
+            last_index: last_index,
+            last_term: last_term,
+            data: data,
+            sessions: Sessions::new(),
+            config: None,
+        }
+    }
+
+    pub fn from_json(snapshot: &Json) -> DecodeResult<Snapshot> {
+        Ok(Snapshot {
+            last_index: get!(snapshot -> "last_index"; Json::as_u64),
+            last_term: get!(snapshot -> "last_term"; Json::as_u64),
+            data: get!(snapshot -> "data"; Some).clone(),
+            sessions: match snapshot.find("sessions") {
+                Some(sessions) => try!(Sessions::from_json(sessions)),
+                None           => Sessions::new(),
+            },
+            config: match snapshot.find("config") {
+                Some(config) => Some(try!(Configuration::from_json(config))),
+                None         => None,
+            },
+        })
+    }
+}
+
+impl Default for Snapshot {
+    fn default() -> Snapshot {
+        Snapshot::new(0, 0, Json::Object(BTreeMap::new()))
+    }
+}
+
+impl ToJson for Snapshot {
+    fn to_json(&self) -> Json {
+        let mut d = BTreeMap::new();
+        d.add_json("last_index", self.last_index);
+        d.add_json("last_term", self.last_term);
+        d.add_json("data", self.data.clone());
+        d.add_json("sessions", self.sessions.to_json());
+        if let Some(ref config) = self.config {
+            d.add_json("config", config.to_json());
+        }
+        Json::Object(d)
+    }
+}
+
+fn optional_string(obj: &Json, key: &str) -> Option<String> {
+    obj.find(key).and_then(Json::as_string).map(str::to_owned)
+}
+
+fn puts_to_json(puts: &[(String, String)]) -> Json {
+    Json::Array(puts.iter().map(|&(ref key, ref value)| {
+        let mut d = BTreeMap::new();
+        d.add_json("key", key.to_owned());
+        d.add_json("value", value.to_owned());
+        Json::Object(d)
+    }).collect())
+}
+
+fn puts_from_json(puts: &Json) -> DecodeResult<Vec<(String, String)>> {
+    let puts = try!(puts.as_array().ok_or(DecodeError::WrongType("puts")));
+    puts.iter()
+        .map(|put| Ok((get!(put -> "key"; Json::as_string).to_owned(),
+                       get!(put -> "value"; Json::as_string).to_owned())))
+        .collect()
+}
+
 pub trait AddJson {
     fn add_json<T: ToJson>(&mut self, key: &'static str, val: T);
 }
@@ -309,10 +866,11 @@ fn s(string: &str) -> String {
 fn test_msg_serialize() {
     let get = MsgType::Get(s("hello"));
     let base = BaseMsg {
-        src: NodeId(['1' as u8, '3' as u8, 'A' as u8, 'E' as u8]),
-        dst: NodeId(['0' as u8, '0' as u8, '1' as u8, 'E' as u8]),
-        leader: NodeId(['A' as u8, 'A' as u8, '4' as u8, '3' as u8]),
-        mid: s("BABADOOK")
+        src: NodeId::from("13AE"),
+        dst: NodeId::from("001E"),
+        leader: NodeId::from("AA43"),
+        mid: s("BABADOOK"),
+        group: s("0"),
     };
     let msg = Msg { base: base, msg: get };
     assert_eq!(msg.to_json().to_string(), s("{\"MID\":\"BABADOOK\",\"dst\":\"001E\",\"key\":\"hello\",\"leader\":\"AA43\",\"src\":\"13AE\",\"type\":\"get\"}"));
@@ -328,10 +886,11 @@ fn test_internal_msg_serialize() {
     };
 
     let base = BaseMsg {
-        src: NodeId(['1' as u8, '3' as u8, 'A' as u8, 'E' as u8]),
-        dst: NodeId(['0' as u8, '0' as u8, '1' as u8, 'E' as u8]),
-        leader: NodeId(['A' as u8, 'A' as u8, '4' as u8, '3' as u8]),
-        mid: s("BABADOOK")
+        src: NodeId::from("13AE"),
+        dst: NodeId::from("001E"),
+        leader: NodeId::from("AA43"),
+        mid: s("BABADOOK"),
+        group: s("0"),
     };
     let msg = Msg { base: base, msg: append};
     let d = msg.to_json().to_string();
@@ -342,17 +901,83 @@ fn test_internal_msg_serialize() {
     assert_eq!(msg.to_json().to_string(), s("{\"MID\":\"BABADOOK\",\"dst\":\"001E\",\"entries\":[{\"key\":\"x\",\"term\":1,\"value\":\"13\"},{\"key\":\"y\",\"term\":1,\"value\":\"27\"}],\"last_entry\":213,\"last_entry_term\":3,\"leader\":\"AA43\",\"leader_commit\":5,\"src\":\"13AE\",\"term\":4,\"type\":\"append_entries\"}"));
 }
 
+#[test]
+fn test_snapshot_roundtrip() {
+    let mut data = BTreeMap::new();
+    data.add_json("x", s("13"));
+    let install = MsgType::InstallSnapshot {
+        term: 7,
+        snapshot: Snapshot::new(1200, 6, Json::Object(data)),
+    };
+    let base = BaseMsg {
+        src: NodeId::from("13AE"),
+        dst: NodeId::from("001E"),
+        leader: NodeId::from("13AE"),
+        mid: s("snapshot"),
+        group: s("0"),
+    };
+    let msg = Msg { base: base, msg: install };
+    assert_eq!(msg.to_json().to_string(), s("{\"MID\":\"snapshot\",\"dst\":\"001E\",\"leader\":\"13AE\",\"snapshot\":{\"data\":{\"x\":\"13\"},\"last_index\":1200,\"last_term\":6,\"sessions\":{}},\"src\":\"13AE\",\"term\":7,\"type\":\"install_snapshot\"}"));
+    assert_eq!(msg, Msg::from_str(&msg.to_json().to_string()).unwrap());
+}
+
+#[test]
+fn test_write_ops_roundtrip() {
+    let base = BaseMsg {
+        src: NodeId::from("13AE"),
+        dst: NodeId::from("001E"),
+        leader: NodeId::from("AA43"),
+        mid: s("BABADOOK"),
+        group: s("0"),
+    };
+    let ops = vec![
+        MsgType::Delete(s("x")),
+        MsgType::Cas { key: s("lease"), expected: None, new: s("me") },
+        MsgType::Cas { key: s("lease"), expected: Some(s("me")), new: s("you") },
+        MsgType::Append(s("log"), s("!")),
+        MsgType::Batch(vec![(s("a"), s("1")), (s("b"), s("2"))]),
+        MsgType::CasFailed(Some(s("them"))),
+        MsgType::NotFound,
+        MsgType::Split(s("m")),
+        MsgType::Merge(s("m")),
+    ];
+    for op in ops {
+        let msg = Msg::new(base.clone(), op.clone());
+        assert_eq!(msg, Msg::from_str(&msg.to_json().to_string()).unwrap());
+        if let Some(command) = op.command() {
+            let entry = Entry { command: command, term: 2, request: None };
+            assert_eq!(entry, Entry::from_json(&entry.to_json()).unwrap());
+        }
+    }
+
+    let merge = Entry {
+        command: Command::Merge { from: s("0.1"), data: s("{\"data\":{\"x\":\"1\"}}"), sessions: s("{}") },
+        term: 2,
+        request: None,
+    };
+    assert_eq!(merge, Entry::from_json(&merge.to_json()).unwrap());
+    // Only a host proposes merges, from its own replica of the retired group
+    let absorb = "{\"MID\":\"a\",\"dst\":\"001E\",\"leader\":\"AA43\",\"src\":\"13AE\",\"type\":\"absorb\",\"from\":\"0.1\"}";
+    assert_eq!(Msg::from_str(absorb), Err(DecodeError::UnknownType(s("absorb"))));
+
+    let mut grouped = Msg::new(base, MsgType::Merge(s("m")));
+    grouped.base.group = s("0.1");
+    assert_eq!(grouped.to_json().find("group"), Some(&Json::String(s("0.1"))));
+    assert_eq!(grouped, Msg::from_str(&grouped.to_json().to_string()).unwrap());
+}
+
 #[test]
 fn test_msg_deserialize() {
     let msg = "{\"dst\":\"001E\",\"leader\":\"AA43\",\"MID\":\"BABADOOK\",\"src\":\"13AE\",\"type\":\"ok\",\"value\":\"blah\"}";
     let base = BaseMsg {
-        src: NodeId(['1' as u8, '3' as u8, 'A' as u8, 'E' as u8]),
-        dst: NodeId(['0' as u8, '0' as u8, '1' as u8, 'E' as u8]),
-        leader: NodeId(['A' as u8, 'A' as u8, '4' as u8, '3' as u8]),
-        mid: s("BABADOOK")
+        src: NodeId::from("13AE"),
+        dst: NodeId::from("001E"),
+        leader: NodeId::from("AA43"),
+        mid: s("BABADOOK"),
+        group: s("0"),
     };
     let msg_type = MsgType::OK(s("blah"));
-    assert_eq!(Msg {base: base, msg: msg_type}, Msg::from_str(msg));
+    assert_eq!(Msg {base: base, msg: msg_type}, Msg::from_str(msg).unwrap());
 }
 
 #[test]
@@ -365,11 +990,27 @@ fn test_int_msg_deserialize() {
     };
 
     let base = BaseMsg {
-        src: NodeId(['1' as u8, '3' as u8, 'A' as u8, 'E' as u8]),
-        dst: NodeId(['0' as u8, '0' as u8, '1' as u8, 'E' as u8]),
-        leader: NodeId(['A' as u8, 'A' as u8, '4' as u8, '3' as u8]),
-        mid: s("BABADOOK")
+        src: NodeId::from("13AE"),
+        dst: NodeId::from("001E"),
+        leader: NodeId::from("AA43"),
+        mid: s("BABADOOK"),
+        group: s("0"),
     };
     let msg = Msg { base: base, msg: append};
-    assert_eq!(msg, Msg::from_str("{\"MID\":\"BABADOOK\",\"dst\":\"001E\",\"entries\":[{\"key\":\"x\",\"term\":1,\"value\":\"13\"},{\"key\":\"y\",\"term\":1,\"value\":\"27\"}],\"last_entry\":213,\"last_entry_term\":3,\"leader\":\"AA43\",\"leader_commit\":5,\"src\":\"13AE\",\"term\":4,\"type\":\"append_entries\"}"));
+    assert_eq!(msg, Msg::from_str("{\"MID\":\"BABADOOK\",\"dst\":\"001E\",\"entries\":[{\"key\":\"x\",\"term\":1,\"value\":\"13\"},{\"key\":\"y\",\"term\":1,\"value\":\"27\"}],\"last_entry\":213,\"last_entry_term\":3,\"leader\":\"AA43\",\"leader_commit\":5,\"src\":\"13AE\",\"term\":4,\"type\":\"append_entries\"}").unwrap());
+}
+
+#[test]
+fn test_bad_messages_are_errors() {
+    let bad = |msg: &str| Msg::from_str(msg).unwrap_err();
+    assert!(match bad("{\"src\":") { DecodeError::Malformed(_) => true, _ => false });
+    assert_eq!(bad("[1, 2]"), DecodeError::Malformed(s("not a JSON object")));
+    assert_eq!(bad("{\"src\":\"0001\",\"dst\":\"0002\",\"MID\":\"m\",\"type\":\"get\",\"key\":\"x\"}"),
+               DecodeError::MissingField("leader"));
+    assert_eq!(bad("{\"src\":\"0001\",\"dst\":\"0002\",\"leader\":\"FFFF\",\"MID\":\"m\",\"type\":\"get\",\"key\":7}"),
+               DecodeError::WrongType("key"));
+    assert_eq!(bad("{\"src\":\"0001\",\"dst\":\"0002\",\"leader\":\"FFFF\",\"MID\":\"m\",\"type\":\"gte\"}"),
+               DecodeError::UnknownType(s("gte")));
+    assert_eq!(bad("{\"src\":\"0 1\",\"dst\":\"0002\",\"leader\":\"FFFF\",\"MID\":\"m\",\"type\":\"fail\"}"),
+               DecodeError::BadNodeId(s("0 1")));
 }
@@ -1,92 +1,238 @@
-use std::borrow::Borrow;
-use std::cell;
 use std::cmp;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::convert::From;
 use std::fmt;
-use std::io::Write;
 use std::mem;
 use std::str::from_utf8;
 use std::sync::mpsc;
 
-use itertools::Itertools;
-use rand;
 use rand::Rng;
-use rustc_serialize::json::{encode, Json, ToJson};
+use rustc_serialize::json::{Json, ToJson};
 use schedule_recv::oneshot_ms;
-use unix_socket::UnixStream;
 
-use super::msg::{BaseMsg, Entry, InternalMsg, Msg, MsgType};
+use super::clock::Clock;
+use super::membership::Configuration;
+use super::msg::{BaseMsg, Command, DecodeResult, Entry, InternalMsg, Msg, MsgType, RequestId, Snapshot};
+use super::session::Sessions;
+use super::settings::Settings;
+use super::state_machine::{KvStore, StateMachine};
+use super::status::{NodeStatus, Progress, Role};
+use super::storage::{Metadata, Storage};
+use super::trace::{Level, Tracer};
+use super::transport::Transport;
+use super::watch::{Change, ChangeLog, Watch};
+
+/// Records an event in `base`'s trace, only building its fields if the
+/// tracer will write it: `trace!(self.base, Info, "vote", "granted" => true)`
+macro_rules! trace {
+    ($base:expr, $level:ident, $event:expr $(, $key:expr => $value:expr)*) => {
+        if $base.tracer.enabled(Level::$level) {
+            $base.trace(Level::$level, $event, &[$(($key, $value.to_json())),*]);
+        }
+    }
+}
 
 enum MsgClass {
-    Timeout,
     Client(Msg),
     Node(Msg),
 }
 
-pub struct Node {
-    base: BaseNode,
+/// A single replica. It never blocks or reads the time on its own: messages
+/// arrive through `receive`, time passes through `tick`, and everything it
+/// sends, the clock and its randomness come from whatever it was built with.
+pub struct Node<S: StateMachine = KvStore> {
+    base: BaseNode<S>,
     node_type: NodeType,
+    rng: Box<Rng>,
+    deadline: u64,
+    /// Client requests relayed to the leader, by the MID they went out with
+    forwards: BTreeMap<String, PendingForward>,
+    forward_seq: u64,
 }
 
-impl Node {
-    pub fn new<I>(reader: mpsc::Receiver<Msg>, socket: UnixStream, id: String, neighbors: I) -> Node
-        where I: Iterator<Item=String>
-    {
-        Node {
-            base: BaseNode::new(reader, socket, id, neighbors),
+impl <S: StateMachine>Node<S> {
+    pub fn new(settings: Settings,
+               storage: Storage,
+               state_machine: S,
+               transport: Box<Transport>,
+               clock: Box<Clock>,
+               rng: Box<Rng>,
+               tracer: Tracer) -> Node<S> {
+        let mut base = BaseNode::new(settings, storage, state_machine, transport, clock, tracer);
+        base.recover();
+        let mut node = Node {
+            base: base,
             node_type: NodeType::Follower,
-        }
+            rng: rng,
+            deadline: 0,
+            forwards: BTreeMap::new(),
+            forward_seq: 0,
+        };
+        node.reset_timer();
+        node
     }
 
-    pub fn main(mut self) {
-        let mut rng = rand::thread_rng();
-        let mut timer = self.reset_timer(&mut rng);
+    /// Runs the replica in real time on messages from `reader` until its
+    /// sender hangs up
+    pub fn main(mut self, reader: mpsc::Receiver<Msg>) {
         loop {
-            match {
-                let chan = &self.base.reader;
-                select! {
-                    msg = chan.recv() => match msg {
-                        Ok(msg) => self.classify(msg),
-                        Err(_)  => return,
-                    },
-                    _   = timer.recv() => MsgClass::Timeout
-                }
-            } {
-                MsgClass::Timeout => {
-                    if let NodeType::Leader{ .. } = self.node_type {
-                        timer = self.reset_timer(&mut rng);
-                        self.send_heartbeat();
-                    } else {
-                        self.into_candidate();
-                        timer = self.reset_timer(&mut rng);
-                    }
-                },
-                MsgClass::Node(msg) => {
-                    self.handle_node(msg);
-                    timer = self.reset_timer(&mut rng);
-                },
-                MsgClass::Client(msg) => {
-                    self.handle_client(msg);
+            let wait = self.deadline().saturating_sub(self.base.clock.now_ms());
+            let timer = oneshot_ms(wait as u32);
+            select! {
+                msg = reader.recv() => match msg {
+                    Ok(msg) => self.receive(msg),
+                    Err(_)  => return,
                 },
+                _   = timer.recv() => self.tick()
             }
         }
     }
 
+    pub fn receive(&mut self, msg: Msg) {
+        match self.classify(msg) {
+            MsgClass::Node(msg) => {
+                self.handle_node(msg);
+                self.reset_timer();
+                self.flush_forwards();
+            },
+            MsgClass::Client(msg) => self.handle_client(msg),
+        }
+    }
 
-    fn reset_timer(&self, rng: &mut rand::ThreadRng) -> mpsc::Receiver<()> {
-        oneshot_ms(if let NodeType::Leader{..} = self.node_type {
-            100
+    /// Fires the election or heartbeat timeout if the clock has reached it
+    pub fn tick(&mut self) {
+        self.expire_forwards();
+        if self.base.clock.now_ms() < self.deadline {
+            return;
+        }
+
+        if self.is_leader() && !self.has_quorum_contact() {
+            trace!(self.base, Warn, "lost_quorum");
+            self.step_down(true);
+            self.base.leader = NodeId::broadcast();
+        } else if self.is_leader() {
+            self.send_heartbeat();
+            self.advance_transfer();
         } else {
-            150 + (rng.gen::<u32>() % 150u32)
-        })
+            self.base.heard_from_leader = false;
+            if self.base.config.is_voter(&self.base.id) {
+                self.into_pre_candidate();
+            }
+        }
+        self.reset_timer();
+        self.flush_forwards();
+    }
+
+    /// When `tick` next has something to do, in the clock's milliseconds
+    pub fn deadline(&self) -> u64 {
+        self.forwards.values().map(|forward| forward.deadline).fold(self.deadline, cmp::min)
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.base.id
+    }
+
+    pub fn term(&self) -> u64 {
+        self.base.current_term
+    }
+
+    pub fn commit_idx(&self) -> u64 {
+        self.base.commit_idx
+    }
+
+    pub fn is_leader(&self) -> bool {
+        if let NodeType::Leader{ .. } = self.node_type { true } else { false }
+    }
+
+    pub fn state_machine(&self) -> &S {
+        &self.base.state_machine
+    }
+
+    pub fn state_machine_mut(&mut self) -> &mut S {
+        &mut self.base.state_machine
+    }
+
+    pub fn sessions(&self) -> &Sessions {
+        &self.base.sessions
+    }
+
+    /// The groups split off by entries applied since the last call
+    pub fn take_forks(&mut self) -> Vec<Fork> {
+        mem::replace(&mut self.base.forks, vec![])
+    }
+
+    pub fn status(&self) -> NodeStatus {
+        let (role, followers) = match self.node_type {
+            NodeType::Follower if self.base.config.is_learner(&self.base.id) => (Role::Learner, BTreeMap::new()),
+            NodeType::Follower          => (Role::Follower, BTreeMap::new()),
+            NodeType::PreCandidate(_)   => (Role::PreCandidate, BTreeMap::new()),
+            NodeType::Candidate(_)      => (Role::Candidate, BTreeMap::new()),
+            NodeType::Leader { ref next_indicies, ref match_indicies, .. } => {
+                let followers = next_indicies.iter().map(|(id, next_idx)| (*id, Progress {
+                    next_index: *next_idx,
+                    match_index: match_indicies.get(id).cloned().unwrap_or(0),
+                })).collect();
+                (Role::Leader, followers)
+            },
+        };
+        NodeStatus {
+            id: self.base.id,
+            role: role,
+            term: self.base.current_term,
+            voted_for: self.base.voted_for,
+            leader: if self.base.leader == NodeId::broadcast() { None } else { Some(self.base.leader) },
+            commit_idx: self.base.commit_idx,
+            last_applied: self.base.last_applied,
+            last_index: self.base.last_index(),
+            log_len: self.base.log.len() as u64,
+            followers: followers,
+        }
+    }
+
+    /// The entry at `idx`, unless it is past the log or compacted away
+    pub fn entry_at(&self, idx: u64) -> Option<&Entry> {
+        if idx <= self.base.snapshot.last_index {
+            return None;
+        }
+        self.base.log.get((idx - self.base.snapshot.last_index - 1) as usize)
+    }
+
+    fn reset_timer(&mut self) {
+        let timeout = if let NodeType::Leader{..} = self.node_type {
+            self.base.settings.heartbeat_interval
+        } else {
+            let settings = &self.base.settings;
+            settings.election_timeout_min
+                + self.rng.gen::<u64>() % (settings.election_timeout_max - settings.election_timeout_min)
+        };
+        self.deadline = self.base.clock.now_ms() + timeout;
     }
 
     fn classify(&self, msg: Msg) -> MsgClass {
         match msg.msg {
             MsgType::Get(_)
                 | MsgType::Put(..)
+                | MsgType::Delete(_)
+                | MsgType::Cas { .. }
+                | MsgType::Append(..)
+                | MsgType::Batch(_)
+                | MsgType::AddServer(_)
+                | MsgType::RemoveServer(_)
+                | MsgType::AddLearner(_)
+                | MsgType::PromoteLearner(_)
+                | MsgType::TransferLeader(_)
+                | MsgType::Status
+                | MsgType::StatusReport(_)
+                | MsgType::Watch { .. }
+                | MsgType::Unwatch
+                | MsgType::WatchEvent { .. }
+                | MsgType::Compacted(_)
+                | MsgType::Forward { .. }
+                | MsgType::Split(_)
+                | MsgType::Merge(_)
                 | MsgType::OK(_)
+                | MsgType::NotFound
+                | MsgType::CasFailed(_)
                 | MsgType::Redirect
                 | MsgType::Fail => MsgClass::Client(msg),
             _  => MsgClass::Node(msg),
@@ -99,72 +245,106 @@ impl Node {
         outgoing.base.leader = self.base.leader;
         match msg.msg {
             MsgType::RequestVote { details, candidate_id } => {
-                let term = details.term;
-                let ulysses_grant_vote = self.grant_vote(details);
+                self.maybe_update_term(details.term);
+                let ulysses_grant_vote = self.grant_vote(details, candidate_id);
+                trace!(self.base, Info, "vote", "candidate" => candidate_id, "granted" => ulysses_grant_vote);
                 if ulysses_grant_vote {
-                    println!("{} is voting for {}", self.base.id, candidate_id);
                     self.base.voted_for = Some(candidate_id);
-                    self.node_type = NodeType::Follower;
+                    self.base.persist_meta();
                 }
-                self.maybe_update_term(term);
                 outgoing.msg = MsgType::RVResp(self.base.current_term, ulysses_grant_vote);
                 self.send(&outgoing);
             },
 
             MsgType::RVResp(their_term, their_vote) => {
+                self.maybe_update_term(their_term);
+
                 let leader = if let NodeType::Candidate(ref mut votes) = self.node_type {
-                    if their_vote {
+                    if their_vote && their_term == self.base.current_term {
                        votes.insert(msg.base.src);
                     }
-                    votes.len() > (self.base.neighbors.len() / 2)
+                    self.base.config.is_quorum(votes)
                 } else {
                     false
                 };
 
-                self.maybe_update_term(their_term);
-
                 if leader {
                     self.into_leader()
                 }
             },
 
+            MsgType::PreVote { details, candidate_id } => {
+                let grant = self.grant_pre_vote(&details);
+                trace!(self.base, Info, "pre_vote", "candidate" => candidate_id, "for_term" => details.term, "granted" => grant);
+                outgoing.msg = MsgType::PVResp(self.base.current_term, grant);
+                self.send(&outgoing);
+            },
+
+            MsgType::PVResp(their_term, their_vote) => {
+                if !their_vote {
+                    self.maybe_update_term(their_term);
+                }
+
+                let candidate = if let NodeType::PreCandidate(ref mut votes) = self.node_type {
+                    if their_vote {
+                        votes.insert(msg.base.src);
+                    }
+                    self.base.config.is_quorum(votes)
+                } else {
+                    false
+                };
+
+                if candidate {
+                    self.into_candidate()
+                }
+            },
+
             MsgType::AppendEntries {details, leader_commit, entries} => {
                 outgoing.msg = if details.term < self.base.current_term {
-                    println!("{} is not leader any more", msg.base.src);
+                    trace!(self.base, Debug, "stale_append", "from" => msg.base.src, "their_term" => details.term);
                     MsgType::AEResp {
                        term: self.base.current_term,
                        success: false,
                        match_index: self.base.last_index(),
                        commit_idx: self.base.commit_idx,
+                       conflict_term: 0,
+                       conflict_index: 0,
                     }
                 } else {
                     self.maybe_update_term(details.term);
                     self.node_type = NodeType::Follower;
-                    self.base.voted_for = None;
                     self.base.leader = msg.base.leader;
+                    self.base.heard_from_leader = true;
 
                     if self.base.contains_term(details.last_entry, details.last_entry_term) {
-                        println!("{} received a valid append entry, len: {}", self.base.id, self.base.log.len());
-                        self.base.log.truncate(details.last_entry as usize + 1);
-                        if let Some(entries) = entries {
-                            self.base.log.extend(entries);
-                        }
+                        let entries = entries.unwrap_or(vec![]);
+                        let count = entries.len();
+                        let match_index = self.base.merge_entries(details.last_entry, entries);
+                        trace!(self.base, Debug, "append_accepted", "leader" => msg.base.src,
+                               "prev_index" => details.last_entry, "entries" => count, "match_index" => match_index);
 
-                        self.maybe_commit_logs(leader_commit);
+                        self.maybe_commit_logs(cmp::min(leader_commit, match_index));
 
                         MsgType::AEResp {
                            term: self.base.current_term,
                            success: true,
-                           match_index: self.base.last_index(),
+                           match_index: match_index,
                            commit_idx: self.base.commit_idx,
+                           conflict_term: 0,
+                           conflict_index: 0,
                         }
                     } else {
-                        println!("{} received a bad append entry: don't have {} with {}, log len: {}, term is {}", self.base.id, details.last_entry, details.last_entry_term, self.base.log.len(), self.base.current_term);
+                        let (conflict_term, conflict_index) = self.base.find_conflict(details.last_entry);
+                        trace!(self.base, Info, "append_rejected", "leader" => msg.base.src,
+                               "prev_index" => details.last_entry, "prev_term" => details.last_entry_term,
+                               "conflict_index" => conflict_index, "conflict_term" => conflict_term);
                         MsgType::AEResp {
                            term: self.base.current_term,
                            success: false,
                            match_index: self.base.last_index(),
                            commit_idx: self.base.commit_idx,
+                           conflict_term: conflict_term,
+                           conflict_index: conflict_index,
                         }
                     }
                 };
@@ -172,42 +352,144 @@ impl Node {
                 self.send(&outgoing);
             },
 
-            MsgType::AEResp { success, match_index, term, commit_idx } => {
+            MsgType::AEResp { success, match_index, term, commit_idx, conflict_term, conflict_index } => {
                 if term > self.base.current_term {
-                    self.base.current_term = term;
-                    self.node_type = NodeType::Follower;
+                    self.maybe_update_term(term);
                     return;
                 };
 
-                if self.base.commit_idx < commit_idx {
-                    self.leader_emergency_commit(commit_idx);    
+                // Only a leader's log is sure to hold everything a follower
+                // has seen committed; a deposed one may have diverged
+                if self.base.commit_idx < commit_idx && self.is_leader() {
+                    self.leader_emergency_commit(commit_idx);
+                }
+
+                // A reply to an earlier term's append says nothing about
+                // the log we lead with now, and heartbeat rounds start over
+                // with each term
+                if term < self.base.current_term {
+                    trace!(self.base, Debug, "stale_reply", "follower" => msg.base.src, "term" => term);
+                    return;
                 }
+                self.record_contact(msg.base.src);
+                self.record_heartbeat_ack(&msg.base);
 
                 let retry_id = if let NodeType::Leader {
                     ref mut next_indicies,
                     ref mut match_indicies,
+                    ref mut in_flight,
                     ..
                 } = self.node_type {
-                    if success {
-                        println!("received success from {}, match is {} next is {}", msg.base.src, match_index, match_index + 1);
+                    if !next_indicies.contains_key(&msg.base.src) {
+                        None
+                    } else if success {
+                        let match_index = cmp::max(match_index, *match_indicies.get(&msg.base.src).unwrap());
+                        let next_idx = cmp::max(match_index + 1, *next_indicies.get(&msg.base.src).unwrap());
+                        trace!(self.base, Debug, "append_acked", "follower" => msg.base.src,
+                               "match_index" => match_index, "next_index" => next_idx);
                         match_indicies.insert(msg.base.src, match_index);
-                        next_indicies.insert(msg.base.src, cmp::min(match_index + 1, self.base.log.len() as u64));
+                        next_indicies.insert(msg.base.src, next_idx);
+                        let count = in_flight.get_mut(&msg.base.src).unwrap();
+                        *count = if next_idx == match_index + 1 { 0 } else { count.saturating_sub(1) };
                         None
                     } else {
-                        println!("leader received a fail from {} with last idx {} next is {}", msg.base.src, match_index, next_indicies.get(&msg.base.src).unwrap());
-                        let new_idx = safe_sub1(*next_indicies.get(&msg.base.src).unwrap());
+                        let old_idx = *next_indicies.get(&msg.base.src).unwrap();
+                        let hinted = if conflict_index == 0 {
+                            safe_sub1(old_idx)
+                        } else if conflict_term == 0 {
+                            conflict_index
+                        } else {
+                            self.base.last_index_of_term(conflict_term)
+                                .map_or(conflict_index, |idx| idx + 1)
+                        };
+                        // Never move backwards past what the follower already
+                        // acknowledged, or forwards past the entry it rejected
+                        let floor = *match_indicies.get(&msg.base.src).unwrap() + 1;
+                        let new_idx = cmp::max(cmp::min(hinted, safe_sub1(old_idx)), floor);
                         next_indicies.insert(msg.base.src, new_idx);
+                        trace!(self.base, Info, "append_refused", "follower" => msg.base.src,
+                               "conflict_index" => conflict_index, "conflict_term" => conflict_term,
+                               "next_index" => new_idx);
+                        // Everything else in flight was sent past the gap and
+                        // will fail too, so probe with one append at a time
+                        // until the follower accepts one
+                        in_flight.insert(msg.base.src, self.base.settings.max_in_flight - 1);
                         Some((msg.base.src, new_idx))
                     }
                 } else {
                     None
                 };
 
-                if let Some((id, new_idx)) = retry_id {
-                    self.send_retry_append(id, new_idx);
+                if let Some((id, _)) = retry_id {
+                    self.replicate(id, true);
                 } else {
                     let commit_idx = self.base.commit_idx;
                     self.maybe_commit_logs(commit_idx);
+                    self.replicate(msg.base.src, false);
+                }
+                self.serve_reads();
+                self.advance_transfer();
+            },
+
+            MsgType::InstallSnapshot { term, snapshot } => {
+                if term >= self.base.current_term {
+                    self.maybe_update_term(term);
+                    self.node_type = NodeType::Follower;
+                    self.base.leader = msg.base.leader;
+                    self.base.heard_from_leader = true;
+                    let last_index = snapshot.last_index;
+                    match self.base.install_snapshot(snapshot) {
+                        Ok(()) => trace!(self.base, Info, "snapshot_installed", "leader" => msg.base.src, "last_index" => last_index),
+                        Err(e) => trace!(self.base, Warn, "snapshot_rejected", "leader" => msg.base.src,
+                                         "last_index" => last_index, "error" => e.to_string()),
+                    }
+                }
+
+                outgoing.msg = MsgType::ISResp {
+                    term: self.base.current_term,
+                    match_index: self.base.snapshot.last_index,
+                };
+                self.send(&outgoing);
+            },
+
+            MsgType::ISResp { term, match_index } => {
+                if term > self.base.current_term {
+                    self.maybe_update_term(term);
+                    return;
+                }
+                if term == self.base.current_term {
+                    self.record_contact(msg.base.src);
+                }
+
+                if let NodeType::Leader {
+                    ref mut next_indicies,
+                    ref mut match_indicies,
+                    ref mut in_flight,
+                    ..
+                } = self.node_type {
+                    if let Some(&old_match) = match_indicies.get(&msg.base.src) {
+                        let match_index = cmp::max(match_index, old_match);
+                        match_indicies.insert(msg.base.src, match_index);
+                        next_indicies.insert(msg.base.src, match_index + 1);
+                        in_flight.insert(msg.base.src, 0);
+                    }
+                }
+
+                let commit_idx = self.base.commit_idx;
+                self.maybe_commit_logs(commit_idx);
+                self.replicate(msg.base.src, false);
+            },
+
+            MsgType::TimeoutNow(term) => {
+                self.maybe_update_term(term);
+                let eligible = term == self.base.current_term
+                    && self.base.config.is_voter(&self.base.id)
+                    && !self.is_leader();
+                if eligible {
+                    // The leader is handing over, so there is no one for a
+                    // pre-vote to protect
+                    trace!(self.base, Info, "timeout_now", "from" => msg.base.src);
+                    self.into_candidate();
                 }
             },
 
@@ -215,176 +497,849 @@ impl Node {
         }
     }
 
-    fn handle_client(&mut self, msg: Msg) {
+    /// A reply to `msg`, still to be filled in
+    fn reply_to(&self, msg: &Msg) -> Msg {
         let mut outgoing = msg.clone();
         mem::swap(&mut outgoing.base.src, &mut outgoing.base.dst);
         outgoing.base.leader = self.base.leader;
-        println!("{} got client message: {}", self.base.id, msg.to_json());
+        outgoing
+    }
+
+    fn handle_client(&mut self, msg: Msg) {
+        let mut outgoing = self.reply_to(&msg);
+        trace!(self.base, Debug, "client_request", "client" => msg.base.src, "mid" => msg.base.mid, "request" => msg.msg.name());
+        match msg.msg {
+            MsgType::Forward { client, client_mid, request } => {
+                if !forwardable(&request) {
+                    trace!(self.base, Warn, "bad_forward", "from" => msg.base.src, "request" => request.name());
+                    outgoing.msg = MsgType::Fail;
+                    self.send(&outgoing);
+                } else if self.is_leader() {
+                    let request = Msg::new(BaseMsg::new(client, self.base.id, msg.base.leader, client_mid), *request);
+                    self.serve_client(request, outgoing);
+                } else {
+                    outgoing.msg = MsgType::Redirect;
+                    self.send(&outgoing);
+                }
+            },
+            MsgType::OK(_)
+                | MsgType::NotFound
+                | MsgType::CasFailed(_)
+                | MsgType::Redirect
+                | MsgType::Fail => self.forward_reply(msg),
+            // Only replicas send these, and only to clients
+            MsgType::StatusReport(_)
+                | MsgType::WatchEvent { .. }
+                | MsgType::Compacted(_) => {
+                trace!(self.base, Warn, "unexpected_message", "from" => msg.base.src, "type" => msg.msg.name());
+            },
+            MsgType::Get(_)
+                | MsgType::Put(..)
+                | MsgType::Delete(_)
+                | MsgType::Cas { .. }
+                | MsgType::Append(..)
+                | MsgType::Batch(_) if self.base.settings.forward_requests && !self.is_leader() => {
+                self.forward_seq += 1;
+                self.forwards.insert(format!("fwd{}", self.forward_seq), PendingForward {
+                    deadline: self.base.clock.now_ms() + self.base.settings.forward_timeout,
+                    request: msg,
+                    sent_to: None,
+                });
+                self.flush_forwards();
+            },
+            _ => self.serve_client(msg, outgoing),
+        }
+    }
+
+    /// Sends queued forwards to the leader we know of, unless they already
+    /// went there. If we have become leader ourselves we answer them.
+    fn flush_forwards(&mut self) {
+        if self.forwards.is_empty() {
+            return;
+        }
+        if self.is_leader() {
+            for (_, forward) in mem::replace(&mut self.forwards, BTreeMap::new()) {
+                let outgoing = self.reply_to(&forward.request);
+                self.serve_client(forward.request, outgoing);
+            }
+            return;
+        }
+
+        let leader = self.base.leader;
+        if leader == NodeId::broadcast() || leader == self.base.id {
+            return;
+        }
+        let mut relays = vec![];
+        for (mid, forward) in self.forwards.iter_mut().filter(|&(_, ref forward)| forward.sent_to != Some(leader)) {
+            forward.sent_to = Some(leader);
+            relays.push(Msg::new(BaseMsg::new(self.base.id, leader, leader, mid.clone()), MsgType::Forward {
+                client: forward.request.base.src,
+                client_mid: forward.request.base.mid.clone(),
+                request: Box::new(forward.request.msg.clone()),
+            }));
+        }
+        for relay in relays {
+            self.send(&relay);
+        }
+    }
+
+    /// Passes the leader's answer to a forwarded request back to its client.
+    /// A redirect means whoever we asked is no longer leader, so the request
+    /// waits for the next one.
+    fn forward_reply(&mut self, reply: Msg) {
+        let mut forward = match self.forwards.remove(&reply.base.mid) {
+            Some(forward) => forward,
+            None          => {
+                trace!(self.base, Debug, "stray_reply", "from" => reply.base.src, "reply" => reply.msg.name());
+                return;
+            },
+        };
+        if reply.msg == MsgType::Redirect {
+            forward.sent_to = Some(reply.base.src);
+            self.forwards.insert(reply.base.mid, forward);
+            self.flush_forwards();
+        } else {
+            let mut outgoing = self.reply_to(&forward.request);
+            outgoing.msg = reply.msg;
+            self.send(&outgoing);
+        }
+    }
+
+    /// Fails forwarded requests that found no leader to answer them in time
+    fn expire_forwards(&mut self) {
+        let now = self.base.clock.now_ms();
+        let expired: Vec<String> = self.forwards.iter()
+            .filter(|&(_, forward)| forward.deadline <= now)
+            .map(|(mid, _)| mid.clone())
+            .collect();
+        for mid in expired {
+            let forward = self.forwards.remove(&mid).unwrap();
+            let read = if let MsgType::Get(_) = forward.request.msg { true } else { false };
+            self.abandon(self.reply_to(&forward.request), read);
+        }
+    }
+
+    fn serve_client(&mut self, msg: Msg, mut outgoing: Msg) {
         match msg.msg {
             MsgType::Get(key) => {
-                outgoing.msg = if let NodeType::Leader { .. } = self.node_type {
-                    match self.base.state_machine.get(&key) {
-                        Some(value) => MsgType::OK(value.clone()),
-                        None        => {
-                            println!("leader {} can't find key {}", self.base.id, key);
-                            MsgType::Fail
-                        }
+                let queued = if let NodeType::Leader {
+                    ref mut pending_reads,
+                    heartbeat_round,
+                    noop_idx,
+                    ..
+                } = self.node_type {
+                    pending_reads.push(PendingRead {
+                        read_idx: cmp::max(self.base.commit_idx, noop_idx),
+                        round: heartbeat_round + 1,
+                        key: key,
+                        reply: outgoing.clone(),
+                    });
+                    true
+                } else {
+                    false
+                };
+
+                if queued {
+                    self.serve_reads();
+                } else {
+                    outgoing.msg = MsgType::Redirect;
+                    self.send(&outgoing);
+                }
+            },
+            MsgType::Put(..)
+                | MsgType::Delete(_)
+                | MsgType::Cas { .. }
+                | MsgType::Append(..)
+                | MsgType::Batch(_)
+                | MsgType::Split(_)
+                | MsgType::Merge(_) => {
+                let command = msg.msg.command().expect("write requests carry a command");
+                let request = RequestId::new(msg.base.src, msg.base.mid.clone());
+                self.propose_write(command, request, outgoing);
+            },
+            MsgType::AddServer(server)
+                | MsgType::RemoveServer(server)
+                | MsgType::AddLearner(server)
+                | MsgType::PromoteLearner(server) => {
+                let in_progress = self.base.config.is_joint()
+                    || self.base.config_idx > self.base.commit_idx;
+                let caught_up = self.learner_caught_up(server);
+                let change = if let NodeType::Leader {ref mut outstanding, ..} = self.node_type {
+                    match membership_change(&self.base.config, &msg.msg, caught_up) {
+                        Ok(None) => {
+                            outgoing.msg = MsgType::OK(server.as_str().to_owned());
+                            None
+                        },
+                        Ok(Some(_)) if in_progress => {
+                            trace!(self.base, Info, "membership_refused", "request" => msg.msg.name(), "server" => server,
+                                   "why" => "another change is in progress");
+                            outgoing.msg = MsgType::Fail;
+                            None
+                        },
+                        Ok(Some(config)) => {
+                            let request = RequestId::new(msg.base.src, msg.base.mid.clone());
+                            let entry = Entry::config(config, self.base.current_term)
+                                .with_request(request.clone());
+                            outstanding.insert(request, outgoing.clone());
+                            Some(entry)
+                        },
+                        Err(why) => {
+                            trace!(self.base, Info, "membership_refused", "request" => msg.msg.name(), "server" => server,
+                                   "why" => why);
+                            outgoing.msg = MsgType::Fail;
+                            None
+                        },
                     }
                 } else {
-                    MsgType::Redirect
+                    outgoing.msg = MsgType::Redirect;
+                    None
                 };
-                ////println!("{} response is {}", self.base.id, outgoing.to_json());
-                self.send(&outgoing);
+
+                match change {
+                    Some(entry) => {
+                        trace!(self.base, Info, "membership_change", "request" => msg.msg.name(), "server" => server);
+                        self.propose(entry);
+                    },
+                    None => self.send(&outgoing),
+                }
             },
-            MsgType::Put(key, value) => {
-                let append = if let NodeType::Leader {ref mut outstanding, ..} = self.node_type {
-                    let entry = Entry {
-                        key: key.clone(),
-                        value: value.clone(),
-                        term: self.base.current_term
-                    };
-                    outstanding.insert(entry.clone(), outgoing);
-
-                    Some(entry)
+            MsgType::TransferLeader(target) => {
+                let deadline = self.base.clock.now_ms() + self.base.settings.transfer_timeout;
+                let eligible = self.base.config.is_voter(&target);
+                let id = self.base.id;
+                let started = if let NodeType::Leader { ref mut transfer, .. } = self.node_type {
+                    if target == id {
+                        outgoing.msg = MsgType::OK(target.as_str().to_owned());
+                        false
+                    } else if !eligible || transfer.is_some() {
+                        outgoing.msg = MsgType::Fail;
+                        false
+                    } else {
+                        trace!(self.base, Info, "transfer_started", "target" => target);
+                        *transfer = Some(Transfer {
+                            target: target,
+                            reply: outgoing.clone(),
+                            deadline: deadline,
+                            timeout_sent: false,
+                        });
+                        true
+                    }
                 } else {
                     outgoing.msg = MsgType::Redirect;
-                    self.send(&outgoing);
-                    ////println!("{} response is {}", self.base.id, outgoing.to_json());
+                    false
+                };
 
-                    None
+                if started {
+                    self.advance_transfer();
+                } else {
+                    self.send(&outgoing);
+                }
+            },
+            MsgType::Status => {
+                outgoing.msg = MsgType::StatusReport(self.status());
+                self.send(&outgoing);
+            },
+            MsgType::Watch { key, prefix, from_index } => {
+                let start = if from_index == 0 { self.base.last_applied + 1 } else { from_index };
+                let watch = Watch {
+                    client: msg.base.src,
+                    mid: msg.base.mid.clone(),
+                    key: key,
+                    prefix: prefix,
+                    from_index: start,
+                };
+                let replay = self.base.changes.since(start);
+                let max_watches = self.base.settings.max_watches;
+                let added = if let NodeType::Leader { ref mut watches, .. } = self.node_type {
+                    watches.retain(|w| w.client != watch.client || w.mid != watch.mid);
+                    if replay.is_none() {
+                        outgoing.msg = MsgType::Compacted(self.base.changes.complete_from());
+                        false
+                    } else if watches.len() >= max_watches {
+                        outgoing.msg = MsgType::Fail;
+                        false
+                    } else {
+                        watches.push(watch.clone());
+                        outgoing.msg = MsgType::OK(start.to_string());
+                        true
+                    }
+                } else {
+                    outgoing.msg = MsgType::Redirect;
+                    false
                 };
 
-                if let Some(entry) = append {
-                    println!("{} sending append {}", self.base.id, self.base.log.len());
-                    self.send_append_entries(entry.clone());
-                    self.base.log.push(entry);
+                self.send(&outgoing);
+                if added {
+                    for change in replay.unwrap_or(vec![]).into_iter().filter(|change| watch.matches(change)) {
+                        self.send_change(&watch, change);
+                    }
                 }
             },
-            MsgType::OK(_)
-                | MsgType::Redirect
-                | MsgType::Fail => panic!("got an external message"),
+            MsgType::Unwatch => {
+                if let NodeType::Leader { ref mut watches, .. } = self.node_type {
+                    watches.retain(|w| w.client != msg.base.src || w.mid != msg.base.mid);
+                }
+                outgoing.msg = MsgType::OK(String::new());
+                self.send(&outgoing);
+            },
             _ => unreachable!("unrecognized client message: {}", msg.msg.name())
         }
     }
 
     fn maybe_update_term(&mut self, term: u64) {
         if term > self.base.current_term {
-            println!("new term: {}, commit_idx: {}", term, self.base.commit_idx);
-            self.base.current_term = term
+            let old_term = self.base.current_term;
+            self.base.current_term = term;
+            trace!(self.base, Info, "term_changed", "from" => old_term);
+            self.base.voted_for = None;
+            self.base.persist_meta();
+            self.step_down(false);
         }
     }
 
-    fn maybe_commit_logs(&mut self, leader_commit: u64) {
-        let mut msgs = vec![];
-
-        if let NodeType::Leader {ref mut match_indicies, ref mut outstanding, .. } = self.node_type {
-            let mut matches = match_indicies.values().collect_vec();
-            matches.sort();
-            let majority_idx = matches.len() / 2;
-            ////println!("about to look at index: {}", majority_idx);
-            let committable = *(matches[majority_idx]) as usize;
-            ////println!("look in index: {} and found {}", majority_idx, committable);
-
-            if committable > leader_commit as usize 
-                && self.base.log[committable].term == self.base.current_term {
-                println!("{} about to commit and log len is {}, matches is {:?}, committable: {}", self.base.id, self.base.log.len(), matches, committable);
-                for entry in &self.base.log[leader_commit as usize .. committable + 1] {
-                    ////println!("inserting {:?}", entry);
-                    self.base.state_machine.insert(entry.key.clone(), entry.value.clone());
-                    if let Some(mut msg) = outstanding.remove(entry) {
-                        msg.msg = MsgType::OK(entry.value.clone());
-                        msgs.push(msg);
-                    }
+    /// Becomes a follower. A leader answers its pending transfer and sends
+    /// its watchers looking for the next leader; with `fail_requests` it
+    /// also gives up on every read and write it has not answered, since they
+    /// may never commit.
+    fn step_down(&mut self, fail_requests: bool) {
+        let old = mem::replace(&mut self.node_type, NodeType::Follower);
+        if let NodeType::Leader { transfer, watches, outstanding, pending_reads, .. } = old {
+            if let Some(transfer) = transfer {
+                let mut reply = transfer.reply;
+                reply.msg = if transfer.timeout_sent {
+                    MsgType::OK(transfer.target.as_str().to_owned())
+                } else {
+                    MsgType::Fail
+                };
+                self.send(&reply);
+            }
+            self.end_watches(watches);
+            if fail_requests {
+                for (_, reply) in outstanding {
+                    self.abandon(reply, false);
                 }
-                println!("success");
-               self.base.commit_idx = committable as u64;
-               self.base.last_applied = committable as u64;
-               ////println!("outstanding is: {:?}", outstanding.values().collect_vec());
+                for read in pending_reads {
+                    self.abandon(read.reply, true);
+                }
+            }
+        }
+    }
+
+    /// Answers a request we can no longer serve. A failed get reads as an
+    /// absent key, so reads are sent off to find the leader instead.
+    fn abandon(&self, mut reply: Msg, read: bool) {
+        if read {
+            reply.msg = MsgType::Redirect;
+            reply.base.leader = NodeId::broadcast();
+        } else {
+            reply.msg = MsgType::Fail;
+        }
+        self.send(&reply);
+    }
+
+    /// Moves a leadership transfer along: the target is sent whatever it is
+    /// missing, and once its log matches ours it is told to start an
+    /// election. If that has not unseated us by the deadline the transfer is
+    /// abandoned.
+    fn advance_transfer(&mut self) {
+        let now = self.base.clock.now_ms();
+        let last_index = self.base.last_index();
+        let (target, match_idx, waiting, expired) = if let NodeType::Leader {
+            ref match_indicies,
+            transfer: Some(ref transfer),
+            ..
+        } = self.node_type {
+            (transfer.target,
+             match_indicies.get(&transfer.target).cloned().unwrap_or(0),
+             !transfer.timeout_sent,
+             transfer.deadline <= now)
+        } else {
+            return;
+        };
+
+        if expired {
+            trace!(self.base, Warn, "transfer_abandoned", "target" => target);
+            let mut reply = if let NodeType::Leader { ref mut transfer, .. } = self.node_type {
+                transfer.take().unwrap().reply
+            } else {
+                return;
+            };
+            reply.msg = MsgType::Fail;
+            self.send(&reply);
+        } else if waiting && match_idx < last_index {
+            self.replicate(target, false);
+        } else if waiting {
+            if let NodeType::Leader { transfer: Some(ref mut transfer), .. } = self.node_type {
+                transfer.timeout_sent = true;
+            }
+            let base = BaseMsg::new(self.base.id, target, self.base.leader, "transfer".to_owned());
+            self.send(&Msg::new(base, MsgType::TimeoutNow(self.base.current_term)));
+        }
+    }
+
+    /// Proposes taking over the retired group `from`, whose final state
+    /// machine and sessions are given. Only the host does this, from its own
+    /// replica of `from`; the reply comes back to our id under `mid`.
+    pub fn propose_merge(&mut self, mid: &str, from: &str, data: &Json, sessions: &Sessions) {
+        let command = Command::Merge {
+            from: from.to_owned(),
+            data: data.to_string(),
+            sessions: sessions.to_json().to_string(),
+        };
+        let id = self.base.id;
+        let outgoing = Msg::new(BaseMsg::new(id, id, self.base.leader, mid.to_owned()), MsgType::Fail);
+        self.propose_write(command, RequestId::new(id, mid.to_owned()), outgoing);
+    }
+
+    /// Appends a write for `request` if we lead and aren't handing off,
+    /// answering with `outgoing` once it applies or right away if it can't
+    fn propose_write(&mut self, command: Command, request: RequestId, mut outgoing: Msg) {
+        let append = if let NodeType::Leader {ref mut outstanding, ref transfer, ..} = self.node_type {
+            if let Some(reply) = self.base.sessions.cached(&request) {
+                trace!(self.base, Info, "duplicate_request", "client" => request.client, "mid" => request.mid);
+                outgoing.msg = reply.clone();
+                self.send(&outgoing);
+                None
+            } else if transfer.is_some() {
+                outgoing.msg = MsgType::Fail;
+                self.send(&outgoing);
+                None
+            } else if outstanding.contains_key(&request) {
+                outstanding.insert(request, outgoing);
+                None
+            } else {
+                let entry = Entry::command(command, self.base.current_term)
+                    .with_request(request.clone());
+                outstanding.insert(request, outgoing);
+                Some(entry)
             }
         } else {
-            if leader_commit > self.base.last_applied {
-                ////println!("about to loop");
-                let upper_bound = cmp::min(self.base.log.len() as u64, leader_commit + 1);
-                let mut i = self.base.commit_idx;
-                for entry in &self.base.log[self.base.commit_idx as usize .. upper_bound as usize] {
-                    println!("{} is inserting {}: {} at idx: {}", self.base.id, entry.key, entry.value, i);
-                    i += 1;
-                    self.base.state_machine.insert(entry.key.clone(), entry.value.clone());
+            outgoing.msg = MsgType::Redirect;
+            self.send(&outgoing);
+            None
+        };
+
+        if let Some(entry) = append {
+            self.propose(entry);
+        }
+    }
+
+    /// Appends an entry to the leader's log and sends it to every peer
+    /// that has room in its pipeline; the rest get it in their next batch
+    fn propose(&mut self, entry: Entry) {
+        let next_idx = self.base.last_index() + 1;
+        self.base.append_log(vec![entry]);
+        self.sync_peers(next_idx);
+        for node in self.base.peers() {
+            self.replicate(node, false);
+        }
+    }
+
+    /// Starts tracking replication for peers that joined the configuration,
+    /// assuming their logs end just before `next_idx`
+    fn sync_peers(&mut self, next_idx: u64) {
+        if let NodeType::Leader {
+            ref mut next_indicies,
+            ref mut match_indicies,
+            ref mut in_flight,
+            ref mut last_contact,
+            ..
+        } = self.node_type {
+            let now = self.base.clock.now_ms();
+            for node in self.base.peers() {
+                if !next_indicies.contains_key(&node) {
+                    next_indicies.insert(node, next_idx);
+                    match_indicies.insert(node, 0);
+                    in_flight.insert(node, 0);
+                    // New members get a full timeout to answer
+                    last_contact.insert(node, now);
                 }
-                self.base.commit_idx = leader_commit;
-                self.base.last_applied = upper_bound;
-                if upper_bound < leader_commit {
-                    println!("{} DOESN'T HAVE ALL LOGS! COMMIT IDX: {} THEY ONLY HAVE {}", self.base.id, leader_commit, upper_bound);
+            }
+        }
+    }
+
+    /// Sends `node` batches of the entries it has not been sent yet until
+    /// its pipeline is full, moving its next index past each batch without
+    /// waiting to hear back. With `heartbeat` it gets one append for the
+    /// current heartbeat round even if there is nothing new to send.
+    fn replicate(&mut self, node: NodeId, heartbeat: bool) {
+        let last_index = self.base.last_index();
+        let mut heartbeat = heartbeat;
+        loop {
+            let (next_idx, mid) = if let NodeType::Leader {
+                ref next_indicies,
+                ref in_flight,
+                heartbeat_round,
+                ..
+            } = self.node_type {
+                let next_idx = match next_indicies.get(&node) {
+                    Some(next_idx) => *next_idx,
+                    None           => return,
+                };
+                let full = in_flight.get(&node).map_or(true, |n| *n >= self.base.settings.max_in_flight);
+                if full || (next_idx > last_index && !heartbeat) {
+                    return;
                 }
+                (next_idx, format!("hb{}", heartbeat_round))
+            } else {
+                return;
+            };
+
+            let sent_to = self.send_entries(node, next_idx, &mid);
+            if let NodeType::Leader { ref mut next_indicies, ref mut in_flight, .. } = self.node_type {
+                next_indicies.insert(node, sent_to);
+                *in_flight.get_mut(&node).unwrap() += 1;
             }
+            if sent_to == next_idx {
+                return;
+            }
+            heartbeat = false;
         }
+    }
 
-        for msg in msgs {
-            if let MsgType::OK(ref key) = msg.msg {
-                println!("{} commit idx is {} key is {}", self.base.id, self.base.commit_idx, key);
+    fn maybe_commit_logs(&mut self, leader_commit: u64) {
+        let commit_idx = if let NodeType::Leader { ref match_indicies, .. } = self.node_type {
+            let mut matches = match_indicies.clone();
+            matches.insert(self.base.id, self.base.last_index());
+            let committable = self.base.config.quorum_index(&matches);
+
+            if committable > self.base.commit_idx
+                && self.base.get_term(committable) == self.base.current_term {
+                committable
+            } else {
+                self.base.commit_idx
             }
+        } else {
+            cmp::max(self.base.commit_idx, cmp::min(leader_commit, self.base.last_index()))
+        };
+
+        if commit_idx > self.base.commit_idx {
+            trace!(self.base, Debug, "commit", "from" => self.base.commit_idx, "to" => commit_idx);
+            self.base.commit_idx = commit_idx;
+            self.base.persist_meta();
+            self.apply_committed();
+        }
+    }
+
+    /// Notes that a follower accepted our leadership for the heartbeat round
+    /// named in the response's MID
+    fn record_contact(&mut self, follower: NodeId) {
+        let now = self.base.clock.now_ms();
+        if let NodeType::Leader { ref mut last_contact, .. } = self.node_type {
+            last_contact.insert(follower, now);
+        }
+    }
+
+    /// Whether a quorum of voters, counting us, has answered within the
+    /// longest election timeout. If not, a majority may well have elected
+    /// someone else behind a partition.
+    fn has_quorum_contact(&self) -> bool {
+        let now = self.base.clock.now_ms();
+        let timeout = self.base.settings.election_timeout_max;
+        if let NodeType::Leader { ref last_contact, .. } = self.node_type {
+            let mut live: HashSet<NodeId> = last_contact.iter()
+                .filter(|&(_, at)| now.saturating_sub(*at) < timeout)
+                .map(|(id, _)| *id)
+                .collect();
+            live.insert(self.base.id);
+            self.base.config.is_quorum(&live)
+        } else {
+            true
+        }
+    }
+
+    fn record_heartbeat_ack(&mut self, base: &BaseMsg) {
+        let round = match heartbeat_round(&base.mid) {
+            Some(round) => round,
+            None        => return,
+        };
+        if let NodeType::Leader { ref mut acked_rounds, .. } = self.node_type {
+            let acked = cmp::max(round, acked_rounds.get(&base.src).cloned().unwrap_or(0));
+            acked_rounds.insert(base.src, acked);
+        }
+    }
+
+    /// Answers every queued read whose heartbeat round a quorum has confirmed
+    /// and whose read index has been applied, then starts a new round if
+    /// reads are waiting on one and none is in flight.
+    fn serve_reads(&mut self) {
+        let config = &self.base.config;
+        let id = self.base.id;
+        let last_applied = self.base.last_applied;
+        let (ready, start_round) = if let NodeType::Leader {
+            ref mut pending_reads,
+            ref acked_rounds,
+            heartbeat_round,
+            ..
+        } = self.node_type {
+            let (ready, waiting): (Vec<PendingRead>, Vec<PendingRead>) = pending_reads
+                .drain(..)
+                .partition(|read| read.read_idx <= last_applied
+                           && round_confirmed(config, id, acked_rounds, read.round));
+            *pending_reads = waiting;
+            let start_round = pending_reads.iter().any(|read| read.round > heartbeat_round)
+                && round_confirmed(config, id, acked_rounds, heartbeat_round);
+            (ready, start_round)
+        } else {
+            return;
+        };
+
+        for read in ready {
+            let mut reply = read.reply;
+            reply.msg = self.base.state_machine.query(&read.key);
+            self.send(&reply);
+        }
+
+        if start_round {
+            self.send_heartbeat();
+        }
+    }
+
+    fn apply_committed(&mut self) {
+        let first_applied = self.base.last_applied + 1;
+        let applied = self.base.apply_committed();
+        self.base.maybe_compact();
+        let mut msgs = vec![];
+        let mut joint_msg = None;
+
+        if let NodeType::Leader { ref mut outstanding, .. } = self.node_type {
+            for (entry, reply) in applied {
+                let request = match entry.request {
+                    Some(request) => request,
+                    None          => continue,
+                };
+                if let Some(mut msg) = outstanding.remove(&request) {
+                    match entry.command {
+                        Command::Config(ref config) if config.is_joint() => {
+                            joint_msg = Some((request, msg));
+                            continue;
+                        },
+                        Command::Config(_) => {
+                            let server = match msg.msg {
+                                MsgType::AddServer(id)
+                                    | MsgType::RemoveServer(id)
+                                    | MsgType::AddLearner(id)
+                                    | MsgType::PromoteLearner(id) => id.as_str().to_owned(),
+                                _ => String::new(),
+                            };
+                            msg.msg = MsgType::OK(server);
+                        },
+                        _ => msg.msg = reply,
+                    }
+                    msgs.push(msg);
+                }
+            }
+        }
+
+        for msg in msgs {
             self.send(&msg);
         }
+
+        self.advance_config_change(joint_msg);
+        self.serve_reads();
+        self.notify_watches(first_applied);
+    }
+
+    /// Sends every watch the changes at `from_idx` or later that it covers
+    fn notify_watches(&self, from_idx: u64) {
+        let watches = match self.node_type {
+            NodeType::Leader { ref watches, .. } if !watches.is_empty() => watches,
+            _ => return,
+        };
+        let changes = self.base.changes.since(from_idx).unwrap_or(vec![]);
+        for watch in watches {
+            for change in changes.iter().filter(|change| watch.matches(change)) {
+                self.send_change(watch, change);
+            }
+        }
     }
 
-    fn grant_vote(&self, details: InternalMsg) -> bool {
-        details.term > self.base.current_term
-            && details.last_entry >= self.base.last_index()
-            && details.last_entry_term >= self.base.log.last().map_or(0, |entry| entry.term)
+    fn send_change(&self, watch: &Watch, change: &Change) {
+        let base = BaseMsg::new(self.base.id, watch.client, self.base.leader, watch.mid.clone());
+        self.send(&Msg::new(base, MsgType::WatchEvent {
+            index: change.index,
+            key: change.key.clone(),
+            value: change.value.clone(),
+        }));
+    }
+
+    /// Tells watchers of a deposed leader to find the new one
+    fn end_watches(&self, watches: Vec<Watch>) {
+        for watch in watches {
+            let base = BaseMsg::new(self.base.id, watch.client, NodeId::broadcast(), watch.mid);
+            self.send(&Msg::new(base, MsgType::Redirect));
+        }
+    }
+
+    /// Once a joint configuration commits, the leader logs the new
+    /// configuration on its own; once that commits, a leader that is no
+    /// longer a voter steps down.
+    fn advance_config_change(&mut self, client_msg: Option<(RequestId, Msg)>) {
+        if self.base.config_idx > self.base.commit_idx {
+            return;
+        }
+        let finished = if let NodeType::Leader { ref mut outstanding, .. } = self.node_type {
+            if self.base.config.is_joint() {
+                let mut entry = Entry::config(self.base.config.finish(), self.base.current_term);
+                if let Some((request, msg)) = client_msg {
+                    outstanding.insert(request.clone(), msg);
+                    entry = entry.with_request(request);
+                }
+                Some(entry)
+            } else {
+                None
+            }
+        } else {
+            return;
+        };
+
+        match finished {
+            Some(entry) => {
+                trace!(self.base, Info, "leaving_joint");
+                self.propose(entry);
+            },
+            None => if !self.base.config.is_voter(&self.base.id) {
+                trace!(self.base, Info, "removed");
+                self.step_down(false);
+            },
+        }
+    }
+
+    /// Whether `learner` is close enough behind the commit index that
+    /// promoting it won't stall commits while it catches up
+    fn learner_caught_up(&self, learner: NodeId) -> bool {
+        if let NodeType::Leader { ref match_indicies, .. } = self.node_type {
+            let matched = match_indicies.get(&learner).cloned().unwrap_or(0);
+            matched + self.base.settings.promote_max_lag >= self.base.commit_idx
+        } else {
+            false
+        }
+    }
+
+    fn grant_vote(&self, details: InternalMsg, candidate_id: NodeId) -> bool {
+        details.term == self.base.current_term
+            && self.base.voted_for.map_or(true, |id| id == candidate_id)
+            && self.base.is_up_to_date(&details)
+    }
+
+    /// A pre-vote only asks whether we would vote in the next term; it is
+    /// refused while we still have a live leader, so a node returning from a
+    /// partition cannot drag the cluster into a new term.
+    fn grant_pre_vote(&self, details: &InternalMsg) -> bool {
+        let has_leader = match self.node_type {
+            NodeType::Leader { .. } => true,
+            _                       => self.base.heard_from_leader,
+        };
+        !has_leader
+            && details.term > self.base.current_term
+            && self.base.is_up_to_date(details)
     }
 
     fn leader_emergency_commit(&mut self, commit_idx: u64) {
-        println!("EMERGENCY COMMITING {} to {}", self.base.commit_idx, commit_idx);
-        for entry in &self.base.log[self.base.commit_idx as usize .. commit_idx as usize + 1] {
-            self.base.state_machine.insert(entry.key.clone(), entry.value.clone());
+        trace!(self.base, Warn, "emergency_commit", "from" => self.base.commit_idx, "to" => commit_idx);
+        self.base.commit_idx = cmp::min(commit_idx, self.base.last_index());
+        self.base.persist_meta();
+        self.apply_committed();
+    }
+
+    fn into_pre_candidate(&mut self) {
+        trace!(self.base, Info, "pre_vote_started", "for_term" => self.base.current_term + 1);
+        let mut votes = HashSet::new();
+        votes.insert(self.base.id);
+        let won = self.base.config.is_quorum(&votes);
+        self.node_type = NodeType::PreCandidate(votes);
+
+        if won {
+            self.into_candidate();
+        } else {
+            self.send_request_vote(true);
         }
-        self.base.commit_idx = commit_idx;
     }
 
     fn into_candidate(&mut self) {
-        println!("{} is becoming a candidate", self.base.id);
         let mut votes = HashSet::new();
         votes.insert(self.base.id);
         mem::replace(&mut self.node_type, NodeType::Candidate(votes));
         self.base.current_term += 1;
         self.base.voted_for = Some(self.base.id);
+        self.base.persist_meta();
+        trace!(self.base, Info, "election_started");
+
+        self.send_request_vote(false);
 
-        self.send_request_vote()
+        let won = if let NodeType::Candidate(ref votes) = self.node_type {
+            self.base.config.is_quorum(votes)
+        } else {
+            false
+        };
+        if won {
+            self.into_leader();
+        }
     }
 
 
     fn into_leader(&mut self) {
-        println!("{} IS THE LEADER", self.base.id);
+        trace!(self.base, Info, "became_leader");
         self.base.leader = self.base.id;
         let mut match_indicies = HashMap::new();
         let mut next_indicies = HashMap::new();
-        for node in &self.base.neighbors {
-            match_indicies.insert(*node, 0);
-            next_indicies.insert(*node, self.base.log.len() as u64);
+        let mut in_flight = HashMap::new();
+        let mut last_contact = HashMap::new();
+        let now = self.base.clock.now_ms();
+        for node in self.base.peers() {
+            match_indicies.insert(node, 0);
+            next_indicies.insert(node, self.base.last_index() + 1);
+            in_flight.insert(node, 0);
+            last_contact.insert(node, now);
         }
 
         self.node_type = NodeType::Leader {
             next_indicies: next_indicies,
             match_indicies: match_indicies,
-            outstanding: HashMap::new()
+            in_flight: in_flight,
+            outstanding: HashMap::new(),
+            noop_idx: self.base.last_index() + 1,
+            heartbeat_round: 0,
+            acked_rounds: HashMap::new(),
+            pending_reads: vec![],
+            transfer: None,
+            watches: vec![],
+            last_contact: last_contact,
         };
 
-        self.send_heartbeat();
+        // Committing an entry from our own term tells us our commit index is
+        // current, which reads depend on
+        let noop = Entry::noop(self.base.current_term);
+        self.propose(noop);
+        self.advance_config_change(None);
     }
 
-    fn send_request_vote(&self) {
-        let details = self.make_details();
-        for to in &self.base.neighbors {
+    /// Asks every peer for its vote, or with `pre_vote` whether it would vote
+    /// for us in the next term
+    fn send_request_vote(&self, pre_vote: bool) {
+        let mut details = self.make_details();
+        if pre_vote {
+            details.term += 1;
+        }
+        for to in self.base.peers().into_iter().filter(|id| self.base.config.is_voter(id)) {
             let base = BaseMsg::new(self.base.id,
-                                    *to,
+                                    to,
                                     self.base.leader,
-                                    "rv".to_owned());
-            let rv = Msg {
-                base: base,
-                msg: MsgType::RequestVote {
+                                    if pre_vote { "pv" } else { "rv" }.to_owned());
+            let request = if pre_vote {
+                MsgType::PreVote {
                    details: details.clone(),
                    candidate_id: self.base.id,
-                },
+                }
+            } else {
+                MsgType::RequestVote {
+                   details: details.clone(),
+                   candidate_id: self.base.id,
+                }
+            };
+            let rv = Msg {
+                base: base,
+                msg: request,
             };
 
             self.send(&rv)
@@ -392,96 +1347,100 @@ impl Node {
     }
 
 
-    fn send_heartbeat(&self) {
-        if let NodeType::Leader {ref next_indicies, ..} = self.node_type {
-            for node in &self.base.neighbors {
-                self.send_retry_append(*node, *next_indicies.get(node).unwrap());
-
+    /// Starts a new heartbeat round. Appends still unanswered from the last
+    /// round are given up for lost, so each follower is sent whatever it is
+    /// missing, or an empty append if it is up to date.
+    fn send_heartbeat(&mut self) {
+        if let NodeType::Leader { ref mut heartbeat_round, ref mut in_flight, .. } = self.node_type {
+            *heartbeat_round += 1;
+            for count in in_flight.values_mut() {
+                *count = 0;
             }
-            // for node in &self.base.neighbors {
-            //     let base = BaseMsg::new(self.base.id,
-            //                             *node,
-            //                             self.base.leader,
-            //                             "append".to_owned());
-            //     let heartbeat = Msg {
-            //         base: base,
-            //         msg: MsgType::AppendEntries {
-            //            details: details.clone(),
-            //            leader_commit: self.base.commit_idx,
-            //            entries: None,
-            //         },
-            //     };
-
-            //     self.send(&heartbeat);
-            // }
-        }
-    }
-
-    fn send_retry_append(&self, dst: NodeId, last_idx: u64) {
+        } else {
+            return;
+        }
+
+        for node in self.base.peers() {
+            self.replicate(node, true);
+        }
+    }
+
+    /// Sends `dst` one append starting at `next_idx` with as many entries as
+    /// fit in a batch, or the snapshot if `next_idx` has been compacted away.
+    /// Returns the index after the last entry sent.
+    fn send_entries(&self, dst: NodeId, next_idx: u64, mid: &str) -> u64 {
         let base = BaseMsg::new(self.base.id,
                                 dst,
                                 self.base.leader,
-                                "retry".to_owned());
+                                mid.to_owned());
+        let prev_idx = safe_sub1(next_idx);
+        if prev_idx < self.base.snapshot.last_index {
+            self.send_snapshot(dst);
+            return next_idx;
+        }
+        let from = (prev_idx - self.base.snapshot.last_index) as usize;
+        let to = self.batch_end(from);
         let details = InternalMsg::new(self.base.current_term,
-                                       safe_sub1(last_idx),
-                                       self.base.get_term(safe_sub1(last_idx)));
-        println!("sending retry append to {} with last as {}", dst, last_idx);
-        let retry = Msg {
+                                       prev_idx,
+                                       self.base.get_term(prev_idx));
+        trace!(self.base, Debug, "append_sent", "to" => dst, "prev_index" => prev_idx, "entries" => to - from);
+        let append = Msg {
             base: base,
             msg: MsgType::AppendEntries {
                 details: details,
                 leader_commit: self.base.commit_idx,
-                entries: Some(self.base.log[last_idx as usize..self.get_chunk_index(last_idx as usize)].to_vec())
+                entries: Some(self.base.log[from..to].to_vec())
             }
         };
-        ////println!("found");
-        self.send(&retry);
-    }
-
-    fn send_append_entries(&self, entry: Entry) {
-        if let NodeType::Leader {..} = self.node_type {
-            let details = self.make_details();
-            for node in &self.base.neighbors {
-                let base = BaseMsg::new(self.base.id,
-                                        *node,
-                                        self.base.leader,
-                                        "append".to_owned());
-                let append = Msg {
-                    base: base,
-                    msg: MsgType::AppendEntries {
-                       details: details.clone(),
-                       leader_commit: self.base.commit_idx,
-                       entries: Some(vec![entry.clone()]),
-                    },
-                };
-                
-                println!("sending normal append to {} with last as {}", node, details.last_entry);
-                self.send(&append);
+        self.send(&append);
+        next_idx + (to - from) as u64
+    }
+
+    fn send_snapshot(&self, dst: NodeId) {
+        let base = BaseMsg::new(self.base.id,
+                                dst,
+                                self.base.leader,
+                                "snapshot".to_owned());
+        trace!(self.base, Info, "snapshot_sent", "to" => dst, "last_index" => self.base.snapshot.last_index);
+        let install = Msg {
+            base: base,
+            msg: MsgType::InstallSnapshot {
+                term: self.base.current_term,
+                snapshot: self.base.snapshot.clone(),
             }
-        }
+        };
+        self.send(&install);
     }
 
-    fn get_chunk_index(&self, idx: usize) -> usize {
-        cmp::min(idx + 35, self.base.log.len())
+    /// Where a batch of log entries starting at position `from` ends
+    fn batch_end(&self, from: usize) -> usize {
+        let settings = &self.base.settings;
+        let mut bytes = 0;
+        for (i, entry) in self.base.log[from..].iter().enumerate() {
+            bytes += entry.size();
+            if (bytes > settings.max_batch_bytes && i > 0) || i == settings.max_batch_entries {
+                return from + i;
+            }
+        }
+        self.base.log.len()
     }
 
     fn make_details(&self) -> InternalMsg {
-       let last_entry_term = self.base.log
-            .last()
-            .map_or(0, |entry| entry.term);
-
        InternalMsg::new(self.base.current_term,
                         self.base.last_index(),
-                        last_entry_term)
+                        self.base.get_term(self.base.last_index()))
     }
 
     fn send(&self, msg: &Msg) {
-        drop((*self.base.writer.borrow_mut())
-            .write_all((encode(&msg.to_json()).unwrap() + "\n").as_bytes()))
+        if msg.msg.is_reply() {
+            trace!(self.base, Debug, "client_reply", "client" => msg.base.dst, "mid" => msg.base.mid,
+                   "reply" => msg.msg.name());
+        }
+        self.base.transport.send(msg);
     }
 }
 
-struct BaseNode {
+struct BaseNode<S: StateMachine> {
     id: NodeId,
     current_term: u64,
     voted_for: Option<NodeId>,
@@ -489,50 +1448,342 @@ struct BaseNode {
     log: Vec<Entry>,
     commit_idx: u64,
     last_applied: u64,
-    neighbors: Vec<NodeId>,
-    reader: mpsc::Receiver<Msg>,
-    writer: cell::RefCell<UnixStream>,
-    state_machine: HashMap<String, String>,
+    initial_config: Configuration,
+    config: Configuration,
+    config_idx: u64,
+    heard_from_leader: bool,
+    transport: Box<Transport>,
+    clock: Box<Clock>,
+    tracer: Tracer,
+    state_machine: S,
+    sessions: Sessions,
+    snapshot: Snapshot,
+    storage: Storage,
+    settings: Settings,
+    changes: ChangeLog,
+    forks: Vec<Fork>,
 }
 
-impl BaseNode {
-    fn new<I>(reader: mpsc::Receiver<Msg>, writer: UnixStream, id: String, neighbors: I) -> BaseNode
-        where I: Iterator<Item=String>
-    {
+impl <S: StateMachine>BaseNode<S> {
+    fn new(settings: Settings,
+           storage: Storage,
+           state_machine: S,
+           transport: Box<Transport>,
+           clock: Box<Clock>,
+           tracer: Tracer) -> BaseNode<S> {
+        let id = settings.id;
+        let initial_config = if settings.learner {
+            let mut learners = BTreeSet::new();
+            learners.insert(id);
+            Configuration::new(settings.peers.iter().cloned()).with_learners(learners)
+        } else {
+            Configuration::new(settings.peers.iter().cloned().chain(Some(id)))
+        };
         BaseNode {
-            id: NodeId::from(id.borrow()),
+            id: id,
             current_term: 0,
             voted_for: None,
             leader: NodeId::broadcast(),
             log: vec![],
             commit_idx: 0,
             last_applied: 0,
-            neighbors: neighbors.map(NodeId::from).collect(),
-            reader: reader,
-            writer: cell::RefCell::new(writer),
-            state_machine: HashMap::new(),
+            config: initial_config.clone(),
+            initial_config: initial_config,
+            config_idx: 0,
+            heard_from_leader: false,
+            transport: transport,
+            clock: clock,
+            tracer: tracer,
+            state_machine: state_machine,
+            sessions: Sessions::new(),
+            snapshot: Snapshot::default(),
+            storage: storage,
+            changes: ChangeLog::new(settings.watch_history),
+            settings: settings,
+            forks: vec![],
         }
     }
 
-    fn get_term(&self, idx: u64) -> u64 {
-        self.log.get(idx as usize).map_or(0, |entry| entry.term)
+    /// Reloads the persisted snapshot, term, vote and log, then replays every
+    /// committed entry past the snapshot into the state machine
+    fn recover(&mut self) {
+        self.snapshot = self.storage.read_snapshot().expect("reading raft snapshot failed");
+        let meta = self.storage.read_meta().expect("reading raft metadata failed");
+        let log = self.storage.read_log().expect("reading raft log failed");
+
+        // A crash between saving a snapshot and compacting the log leaves
+        // entries the snapshot already covers at the front of the file
+        let covered = self.snapshot.last_index.saturating_sub(self.storage.log_start()) as usize;
+        self.log = if covered > 0 {
+            let keep = log[cmp::min(covered, log.len())..].to_vec();
+            self.storage.compact(self.snapshot.last_index, &keep).expect("compacting raft log failed");
+            keep
+        } else {
+            log
+        };
+
+        self.current_term = meta.current_term;
+        self.voted_for = meta.voted_for;
+        self.state_machine.restore(&self.snapshot.data).expect("restoring raft snapshot failed");
+        self.sessions = self.snapshot.sessions.clone();
+        self.last_applied = self.snapshot.last_index;
+        self.changes.reset(self.snapshot.last_index);
+        self.refresh_config();
+        self.commit_idx = cmp::max(self.snapshot.last_index,
+                                   cmp::min(meta.commit_idx, self.last_index()));
+        self.apply_committed();
+        trace!(self, Info, "recovered", "entries" => self.log.len(), "commit_idx" => self.commit_idx);
     }
 
-    fn last_index(&self) -> u64 {
-        safe_sub1(self.log.len() as u64)
+    fn trace(&self, level: Level, event: &str, fields: &[(&str, Json)]) {
+        self.tracer.event(level, self.current_term, event, fields);
     }
 
+    fn persist_meta(&self) {
+        let meta = Metadata {
+            current_term: self.current_term,
+            voted_for: self.voted_for,
+            commit_idx: self.commit_idx,
+        };
+        self.storage.save_meta(&meta).expect("persisting raft metadata failed");
+    }
 
-    fn contains_term(&self, index: u64, term: u64) -> bool {
-        if index == 0 {
-            self.log.len() == 0 || self.log[0].term == term
+    fn append_log(&mut self, entries: Vec<Entry>) {
+        self.storage.append(&entries).expect("appending to raft log failed");
+        let reconfigured = entries.iter().any(|entry| match entry.command {
+            Command::Config(_) => true,
+            _                  => false,
+        });
+        self.log.extend(entries);
+        if reconfigured {
+            self.refresh_config();
+        }
+    }
+
+    /// Drops every entry after `last_idx`
+    fn truncate_log(&mut self, last_idx: u64) {
+        self.storage.truncate(last_idx).expect("truncating raft log failed");
+        self.log.truncate((last_idx - self.snapshot.last_index) as usize);
+        self.refresh_config();
+    }
+
+    /// Every other member of the active configuration
+    fn peers(&self) -> Vec<NodeId> {
+        self.config.members().into_iter().filter(|id| *id != self.id).collect()
+    }
+
+    /// A configuration takes effect as soon as it is in the log, committed or not
+    fn refresh_config(&mut self) {
+        let (idx, config) = self.config_at(self.last_index());
+        if config != self.config {
+            trace!(self, Info, "config_changed", "config" => config);
+        }
+        self.config_idx = idx;
+        self.config = config;
+    }
+
+    /// The latest configuration logged at or before `idx`, and its index
+    fn config_at(&self, idx: u64) -> (u64, Configuration) {
+        let upto = (idx - self.snapshot.last_index) as usize;
+        for (i, entry) in self.log[..upto].iter().enumerate().rev() {
+            if let Command::Config(ref config) = entry.command {
+                return (self.snapshot.last_index + i as u64 + 1, config.clone());
+            }
+        }
+        match self.snapshot.config {
+            Some(ref config) => (self.snapshot.last_index, config.clone()),
+            None             => (0, self.initial_config.clone()),
+        }
+    }
+
+    /// Folds the applied prefix of the log into a snapshot once it grows past
+    /// `snapshot_threshold` entries
+    fn maybe_compact(&mut self) {
+        if self.last_applied - self.snapshot.last_index < self.settings.snapshot_threshold {
+            return;
+        }
+        let mut snapshot = Snapshot::new(self.last_applied,
+                                         self.get_term(self.last_applied),
+                                         self.state_machine.snapshot());
+        snapshot.sessions = self.sessions.clone();
+        snapshot.config = Some(self.config_at(self.last_applied).1);
+        let keep = self.log[(self.last_applied - self.snapshot.last_index) as usize..].to_vec();
+        self.storage.save_snapshot(&snapshot).expect("saving raft snapshot failed");
+        self.storage.compact(snapshot.last_index, &keep).expect("compacting raft log failed");
+        self.log = keep;
+        self.snapshot = snapshot;
+    }
+
+    /// Replaces our state with a leader's snapshot, keeping any log entries
+    /// that follow it if our log agrees with the snapshot's last entry. A
+    /// snapshot the state machine can't restore changes nothing.
+    fn install_snapshot(&mut self, snapshot: Snapshot) -> DecodeResult<()> {
+        if snapshot.last_index <= self.snapshot.last_index {
+            return Ok(());
+        }
+        let restore = snapshot.last_index > self.last_applied;
+        if restore {
+            try!(self.state_machine.restore(&snapshot.data));
+        }
+        let keep = if snapshot.last_index <= self.last_index()
+            && self.get_term(snapshot.last_index) == snapshot.last_term {
+            self.log[(snapshot.last_index - self.snapshot.last_index) as usize..].to_vec()
         } else {
-            match self.log.get(index as usize) {
-                Some(entry) => entry.term == term,
-                None => false
+            vec![]
+        };
+        self.storage.save_snapshot(&snapshot).expect("saving raft snapshot failed");
+        self.storage.compact(snapshot.last_index, &keep).expect("compacting raft log failed");
+        self.log = keep;
+
+        if restore {
+            self.sessions = snapshot.sessions.clone();
+            self.last_applied = snapshot.last_index;
+            self.changes.reset(snapshot.last_index);
+        }
+        self.commit_idx = cmp::max(self.commit_idx, snapshot.last_index);
+        self.snapshot = snapshot;
+        self.refresh_config();
+        self.persist_meta();
+        Ok(())
+    }
+
+    /// Writes a leader's entries in after `prev_idx`, dropping our suffix only
+    /// from the first entry whose term disagrees. Returns the last index known
+    /// to match the leader.
+    fn merge_entries(&mut self, prev_idx: u64, entries: Vec<Entry>) -> u64 {
+        let mut idx = prev_idx;
+        let mut fresh = vec![];
+        for entry in entries {
+            idx += 1;
+            if idx <= self.snapshot.last_index {
+                continue;
+            }
+            if fresh.is_empty() && idx <= self.last_index() {
+                if self.get_term(idx) == entry.term {
+                    continue;
+                }
+                self.truncate_log(idx - 1);
             }
+            fresh.push(entry);
+        }
+        self.append_log(fresh);
+        idx
+    }
+
+    /// Applies entries up through `commit_idx` and returns them along with
+    /// the reply each one earned
+    fn apply_committed(&mut self) -> Vec<(Entry, MsgType)> {
+        let entries = self.log[(self.last_applied - self.snapshot.last_index) as usize
+                               .. (self.commit_idx - self.snapshot.last_index) as usize].to_vec();
+        let mut applied = vec![];
+        for (i, entry) in entries.into_iter().enumerate() {
+            let reply = self.apply(self.last_applied + i as u64 + 1, &entry);
+            applied.push((entry, reply));
+        }
+        self.last_applied = self.commit_idx;
+        applied
+    }
+
+    /// Applies the entry at `idx` to the state machine, unless its request
+    /// was already applied, in which case the original reply is returned
+    fn apply(&mut self, idx: u64, entry: &Entry) -> MsgType {
+        if let Some(reply) = entry.request.as_ref().and_then(|request| self.sessions.cached(request)) {
+            return reply.clone();
+        }
+
+        match entry.command {
+            Command::Config(_) | Command::Noop => MsgType::OK(String::new()),
+            ref command => {
+                let reply = self.state_machine.apply(command);
+                if let MsgType::OK(ref value) = reply {
+                    for key in command.keys() {
+                        let value = match self.state_machine.query(key) {
+                            MsgType::OK(value) => Some(value),
+                            _                  => None,
+                        };
+                        self.changes.record(Change { index: idx, key: key.to_owned(), value: value });
+                    }
+                    match *command {
+                        Command::Split(_) => self.forks.push(Fork {
+                            group: value.clone(),
+                            sessions: self.sessions.clone(),
+                            config: self.config_at(idx).1,
+                        }),
+                        Command::Merge { ref sessions, .. } => {
+                            let absorbed = Json::from_str(sessions).ok()
+                                .and_then(|sessions| Sessions::from_json(&sessions).ok());
+                            if let Some(absorbed) = absorbed {
+                                self.sessions.absorb(&absorbed);
+                            }
+                        },
+                        _ => {},
+                    }
+                }
+                if let Some(ref request) = entry.request {
+                    // A redirected request never happened here, and its
+                    // retry may find the group that now serves its keys
+                    if reply != MsgType::Redirect {
+                        self.sessions.record(request.clone(), reply.clone());
+                    }
+                }
+                reply
+            },
         }
     }
+
+    /// Term of the entry at `idx`; indices start at 1, and 0 stands for the
+    /// empty log. Entries inside the snapshot have no term on record.
+    fn get_term(&self, idx: u64) -> u64 {
+        if idx <= self.snapshot.last_index {
+            if idx == self.snapshot.last_index { self.snapshot.last_term } else { 0 }
+        } else {
+            self.log.get((idx - self.snapshot.last_index) as usize - 1).map_or(0, |entry| entry.term)
+        }
+    }
+
+    fn last_index(&self) -> u64 {
+        self.snapshot.last_index + self.log.len() as u64
+    }
+
+
+    /// Whether a candidate whose log ends as `details` describes is at least
+    /// as current as ours
+    fn is_up_to_date(&self, details: &InternalMsg) -> bool {
+        let last_term = self.get_term(self.last_index());
+        details.last_entry_term > last_term
+            || (details.last_entry_term == last_term && details.last_entry >= self.last_index())
+    }
+
+    /// Where a follower's log stops agreeing with an append whose previous
+    /// entry is at `prev_idx`: the term of our entry there and the first
+    /// index we hold in that term, or no term and the end of our log when
+    /// the entry is missing altogether
+    fn find_conflict(&self, prev_idx: u64) -> (u64, u64) {
+        if prev_idx > self.last_index() {
+            return (0, self.last_index() + 1);
+        }
+        let term = self.get_term(prev_idx);
+        let mut first = prev_idx;
+        while first > self.snapshot.last_index + 1 && self.get_term(first - 1) == term {
+            first -= 1;
+        }
+        (term, first)
+    }
+
+    /// The last index in our log holding an entry from `term`
+    fn last_index_of_term(&self, term: u64) -> Option<u64> {
+        let in_log = self.log.iter().rposition(|entry| entry.term == term);
+        match in_log {
+            Some(pos) => Some(self.snapshot.last_index + pos as u64 + 1),
+            None if term == self.snapshot.last_term && self.snapshot.last_index > 0 => Some(self.snapshot.last_index),
+            None => None,
+        }
+    }
+
+    fn contains_term(&self, index: u64, term: u64) -> bool {
+        index <= self.snapshot.last_index
+            || (index <= self.last_index() && self.get_term(index) == term)
+    }
 }
 
 fn safe_sub1(i: u64) -> u64 {
@@ -541,40 +1792,203 @@ fn safe_sub1(i: u64) -> u64 {
 
 enum NodeType {
     Follower,
+    PreCandidate(HashSet<NodeId>),
     Candidate(HashSet<NodeId>),
     Leader {
         next_indicies: HashMap<NodeId, u64>,
         match_indicies: HashMap<NodeId, u64>,
-        outstanding: HashMap<Entry, Msg>,
+        in_flight: HashMap<NodeId, usize>,
+        outstanding: HashMap<RequestId, Msg>,
+        noop_idx: u64,
+        heartbeat_round: u64,
+        acked_rounds: HashMap<NodeId, u64>,
+        pending_reads: Vec<PendingRead>,
+        transfer: Option<Transfer>,
+        watches: Vec<Watch>,
+        /// When each peer last answered an append or snapshot in our term
+        last_contact: HashMap<NodeId, u64>,
+    }
+}
+
+/// A group split off from ours by the entry at some index, with the
+/// sessions and configuration it starts from: ours as of that entry
+pub struct Fork {
+    pub group: String,
+    pub sessions: Sessions,
+    pub config: Configuration,
+}
+
+/// A client request a follower has relayed, or will relay once it knows
+/// who leads
+struct PendingForward {
+    request: Msg,
+    deadline: u64,
+    sent_to: Option<NodeId>,
+}
+
+/// A leadership handover the leader is waiting on
+struct Transfer {
+    target: NodeId,
+    reply: Msg,
+    deadline: u64,
+    timeout_sent: bool,
+}
+
+/// A `Get` waiting for the leader to confirm it is still in charge
+struct PendingRead {
+    read_idx: u64,
+    round: u64,
+    key: String,
+    reply: Msg,
+}
+
+/// Whether a follower could have relayed `msg` for a client. Anything else
+/// in a forward is someone's mistake, and the leader must not act on it.
+fn forwardable(msg: &MsgType) -> bool {
+    match *msg {
+        MsgType::Get(_)
+            | MsgType::Put(..)
+            | MsgType::Delete(_)
+            | MsgType::Cas { .. }
+            | MsgType::Append(..)
+            | MsgType::Batch(_)
+            | MsgType::AddServer(_)
+            | MsgType::RemoveServer(_)
+            | MsgType::AddLearner(_)
+            | MsgType::PromoteLearner(_)
+            | MsgType::TransferLeader(_) => true,
+        _ => false,
     }
 }
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone, Copy)]
-pub struct NodeId(pub [u8; 4]);
+fn heartbeat_round(mid: &str) -> Option<u64> {
+    if mid.starts_with("hb") {
+        mid[2..].parse().ok()
+    } else {
+        None
+    }
+}
+
+fn round_confirmed(config: &Configuration,
+                   id: NodeId,
+                   acked_rounds: &HashMap<NodeId, u64>,
+                   round: u64) -> bool {
+    let mut acks: HashSet<NodeId> = acked_rounds.iter()
+        .filter(|&(_, acked)| *acked >= round)
+        .map(|(node, _)| *node)
+        .collect();
+    acks.insert(id);
+    config.is_quorum(&acks)
+}
+
+/// The configuration a membership request asks for: `None` if it is already
+/// in place, or why it can't be made
+fn membership_change(config: &Configuration, request: &MsgType, caught_up: bool)
+                     -> Result<Option<Configuration>, &'static str> {
+    let mut voters = config.voters.clone();
+    let mut learners = config.learners.clone();
+    match *request {
+        MsgType::AddServer(server) => {
+            if !voters.insert(server) {
+                return Ok(None);
+            }
+            Ok(Some(config.transition(voters)))
+        },
+        MsgType::RemoveServer(server) => {
+            if learners.remove(&server) {
+                Ok(Some(config.with_learners(learners)))
+            } else if !voters.remove(&server) {
+                Ok(None)
+            } else if voters.is_empty() {
+                Err("it is the last voter")
+            } else {
+                Ok(Some(config.transition(voters)))
+            }
+        },
+        MsgType::AddLearner(server) => {
+            if voters.contains(&server) {
+                Err("it is already a voter")
+            } else if !learners.insert(server) {
+                Ok(None)
+            } else {
+                Ok(Some(config.with_learners(learners)))
+            }
+        },
+        MsgType::PromoteLearner(server) => {
+            if voters.contains(&server) {
+                Ok(None)
+            } else if !learners.contains(&server) {
+                Err("it is not a learner")
+            } else if !caught_up {
+                Err("it has not caught up")
+            } else {
+                voters.insert(server);
+                Ok(Some(config.transition(voters)))
+            }
+        },
+        _ => Ok(None),
+    }
+}
+
+/// Longest node id, in bytes
+pub const MAX_NODE_ID_LEN: usize = 32;
+
+/// A node or client name of 1 to `MAX_NODE_ID_LEN` printable ASCII
+/// characters. It is stored inline so ids stay `Copy`.
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+pub struct NodeId {
+    len: u8,
+    bytes: [u8; MAX_NODE_ID_LEN],
+}
 
 impl NodeId {
     pub fn as_node_id(json: &Json) -> Option<NodeId> {
-        NodeId::from_bytes(json
-                           .as_string()
-                           .expect("node id must be string")
-                           .as_bytes())
+        json.as_string().and_then(NodeId::from_str)
+    }
+
+    pub fn from_str(s: &str) -> Option<NodeId> {
+        NodeId::from_bytes(s.as_bytes())
     }
 
     fn from_bytes(bytes: &[u8]) -> Option<NodeId> {
-        match bytes {
-            [a, b, c, d] => Some(NodeId([a, b, c, d])),
-            _            => None,
+        if bytes.is_empty() || bytes.len() > MAX_NODE_ID_LEN || bytes.iter().any(|b| *b <= b' ' || *b > b'~') {
+            return None;
         }
+        let mut id = NodeId { len: bytes.len() as u8, bytes: [0; MAX_NODE_ID_LEN] };
+        id.bytes[..bytes.len()].copy_from_slice(bytes);
+        Some(id)
+    }
+
+    pub fn as_str(&self) -> &str {
+        from_utf8(&self.bytes[..self.len as usize]).unwrap()
+    }
+
+    pub fn broadcast() -> NodeId {
+        NodeId::from("FFFF")
+    }
+}
+
+impl PartialOrd for NodeId {
+    fn partial_cmp(&self, other: &NodeId) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
     }
+}
 
-    fn broadcast() -> NodeId {
-        NodeId(['F' as u8, 'F' as u8, 'F' as u8, 'F' as u8])
+impl Ord for NodeId {
+    fn cmp(&self, other: &NodeId) -> cmp::Ordering {
+        self.as_str().cmp(other.as_str())
+    }
+}
+
+impl fmt::Debug for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "NodeId({:?})", self.as_str())
     }
 }
 
 impl ToJson for NodeId {
     fn to_json(&self) -> Json {
-        from_utf8(&self.0).unwrap().to_json()
+        self.as_str().to_json()
     }
 }
 
@@ -593,10 +2007,6 @@ impl From<String> for NodeId {
 
 impl fmt::Display for NodeId {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        fmt::Display::fmt(&format!("[{}{}{}{}]", self.0[0] as char,
-                                  self.0[1] as char,
-                                  self.0[2] as char,
-                                  self.0[3] as char),
-                          f)
+        fmt::Display::fmt(&format!("[{}]", self.as_str()), f)
     }
 }
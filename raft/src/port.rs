@@ -1,73 +1,112 @@
-use std::io::{Read, Write};
+use std::io::{self, BufReader, Read};
 use std::mem;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc};
 
+use rustc_serialize::json::ToJson;
 use unix_socket::UnixStream;
 
-use super::msg::Msg;
+use super::codec::{self, Links};
+use super::msg::{DecodeError, Msg};
+use super::trace::{Level, Tracer};
 
-pub struct Port {
-    socket: Option<UnixStream>,
+/// How much of an undecodable frame its trace event shows
+const DROPPED_PREFIX: usize = 256;
+
+/// Reads frames off a stream, the harness's socket or a peer's connection,
+/// and hands the messages in them to the replica
+pub struct Port<S: Read = UnixStream> {
+    socket: Option<S>,
     sender: mpsc::Sender<Msg>,
+    links: Arc<Links>,
+    /// Sees each message before the replica does
+    on_receive: Option<Box<FnMut(&Msg) + Send>>,
+    dropped: u64,
 }
 
-impl Port {
-    pub fn new(socket: UnixStream, sender: mpsc::Sender<Msg>) -> Port {
+impl <S: Read>Port<S> {
+    pub fn new(socket: S, sender: mpsc::Sender<Msg>, links: Arc<Links>) -> Port<S> {
         Port {
             socket: Some(socket),
             sender: sender,
+            links: links,
+            on_receive: None,
+            dropped: 0,
         }
     }
 
-    pub fn relay(mut self) {
-        let reader = mem::replace(&mut self.socket, None).unwrap();
-        let msgs = reader.bytes()
-            .map(Result::unwrap)
-            .split(|byte| '\n' != *byte as char);
-        for byte_block in msgs {
-            match String::from_utf8(byte_block) {
-                Ok(msg) => self.handle_message(msg),
-                Err(e)  => panic!("error! {}", e),
+    pub fn on_receive<F: FnMut(&Msg) + Send + 'static>(mut self, f: F) -> Port<S> {
+        self.on_receive = Some(Box::new(f));
+        self
+    }
+
+    /// Reads until the stream ends. Ports run on their own threads, so each
+    /// gets a `tracer` of its own.
+    pub fn relay(mut self, tracer: &Tracer) {
+        let mut reader = BufReader::new(mem::replace(&mut self.socket, None).unwrap());
+        loop {
+            let frame = match codec::read_frame(&mut reader) {
+                Ok(Some(frame)) => frame,
+                Ok(None)        => return,
+                // Only the oversized frame is lost; the stream is already
+                // past it
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+                    self.drop_message(tracer, DecodeError::Malformed(e.to_string()), "<oversized frame>");
+                    continue;
+                },
+                Err(e)          => {
+                    self.drop_message(tracer, DecodeError::Malformed(e.to_string()), "<unreadable frame>");
+                    return;
+                },
+            };
+            match frame.decode() {
+                Ok((msg, offered)) => {
+                    if offered {
+                        self.links.offered(msg.base.src);
+                    }
+                    if let Some(ref mut f) = self.on_receive {
+                        f(&msg);
+                    }
+                    drop(self.sender.send(msg));
+                },
+                Err(e) => {
+                    let bytes = frame.to_bytes();
+                    self.drop_message(tracer, e, String::from_utf8_lossy(&bytes).trim_right());
+                },
             }
         }
     }
 
-    fn handle_message(&self, req: String) {
-        //println!("message is {}", req);
-        let msg = Msg::from_str(&req);
-        drop(self.sender.send(msg));
+    /// Traces and counts a message that could not be decoded, then forgets it
+    fn drop_message(&mut self, tracer: &Tracer, err: DecodeError, req: &str) {
+        self.dropped += 1;
+        let shown: String = req.chars().take(DROPPED_PREFIX).collect();
+        tracer.event(Level::Warn, 0, "message_dropped", &[("count", self.dropped.to_json()),
+                                                          ("error", err.to_string().to_json()),
+                                                          ("message", shown.to_json())]);
     }
 }
 
-struct Split<I, F> {
-    iter: I,
-    f: F,
-}
-
-impl <I: Iterator, F>Iterator for Split<I, F> where F: FnMut(&I::Item) -> bool {
-    type Item = Vec<I::Item>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        let mut out = vec![];
-        for item in self.iter.by_ref() {
-            if (self.f)(&item) {
-                out.push(item);
-            } else {
-                return Some(out);
-            }
-        }
-        None
-    }
-}
+#[test]
+fn test_relay_skips_oversized_frames() {
+    use super::clock::WallClock;
+    use super::codec::{encode_binary, encode_json, BINARY_MARKER, MAX_FRAME};
+    use super::msg::{BaseMsg, MsgType};
+    use super::node::NodeId;
 
-trait Splittable<R> : Iterator<Item=R> + Sized {
-    fn split<F>(self, f: F) -> Split<Self, F>
-        where F: FnMut(&R) -> bool;
-}
+    let msg = |mid: &str| Msg::new(BaseMsg::new(NodeId::from("CCCC"), NodeId::from("0000"), NodeId::broadcast(),
+                                                mid.to_owned()),
+                                   MsgType::Get("k".to_owned()));
+    let mut stream = vec![b'{'; MAX_FRAME + 1];
+    stream.push(b'\n');
+    stream.extend_from_slice(&encode_json(&msg("1"), false));
+    let len = MAX_FRAME + 1;
+    stream.extend_from_slice(&[BINARY_MARKER, (len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8]);
+    stream.extend(vec![0u8; len]);
+    stream.extend_from_slice(&encode_binary(&msg("2")));
 
-impl <R, I: Iterator<Item=R>>Splittable<R> for I {
-    fn split<F>(self, f: F) -> Split<Self, F>
-        where F: FnMut(&R) -> bool {
-            Split{ iter: self, f: f }
-    }
+    let (sender, receiver) = mpsc::channel();
+    let tracer = Tracer::new(NodeId::from("0000"), Level::Warn, Box::new(WallClock));
+    Port::new(&stream[..], sender, Arc::new(Links::new(true))).relay(&tracer);
+    let relayed: Vec<String> = receiver.iter().map(|msg| msg.base.mid).collect();
+    assert_eq!(relayed, vec!["1", "2"]);
 }
@@ -0,0 +1,100 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use rustc_serialize::json::{Json, ToJson};
+
+use super::msg::{AddJson, DecodeError, DecodeResult, MsgType, RequestId};
+use super::node::NodeId;
+
+/// How many of each client's most recent replies are remembered
+const SESSION_WINDOW: usize = 32;
+
+/// The replies to each client's most recently applied requests, keyed by
+/// `MID`. This is replicated state: every replica builds the same table by
+/// applying the same entries, so any leader can recognize a retry.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Sessions {
+    clients: HashMap<NodeId, VecDeque<(String, MsgType)>>,
+}
+
+impl Sessions {
+    pub fn new() -> Sessions {
+        Sessions {
+            clients: HashMap::new(),
+        }
+    }
+
+    pub fn cached(&self, request: &RequestId) -> Option<&MsgType> {
+        self.clients
+            .get(&request.client)
+            .and_then(|replies| replies.iter().find(|&&(ref mid, _)| *mid == request.mid))
+            .map(|&(_, ref reply)| reply)
+    }
+
+    pub fn record(&mut self, request: RequestId, reply: MsgType) {
+        let replies = self.clients.entry(request.client).or_insert(VecDeque::new());
+        if replies.len() == SESSION_WINDOW {
+            replies.pop_front();
+        }
+        replies.push_back((request.mid, reply));
+    }
+
+    /// Adds the replies another group gave, for when it hands its keys over
+    /// to us and its clients' retries start arriving here
+    pub fn absorb(&mut self, other: &Sessions) {
+        for (client, replies) in &other.clients {
+            for &(ref mid, ref reply) in replies {
+                let request = RequestId::new(*client, mid.clone());
+                if self.cached(&request).is_none() {
+                    self.record(request, reply.clone());
+                }
+            }
+        }
+    }
+}
+
+impl ToJson for Sessions {
+    fn to_json(&self) -> Json {
+        let mut d = BTreeMap::new();
+        for (client, replies) in &self.clients {
+            let replies = replies.iter().map(|&(ref mid, ref reply)| {
+                let mut r = BTreeMap::new();
+                r.add_json("MID", mid.to_owned());
+                r.add_json("reply", reply.to_json());
+                Json::Object(r)
+            }).collect();
+            d.insert(client.as_str().to_owned(), Json::Array(replies));
+        }
+        Json::Object(d)
+    }
+}
+
+impl Sessions {
+    pub fn from_json(json: &Json) -> DecodeResult<Sessions> {
+        let mut sessions = Sessions::new();
+        for (client, replies) in try!(json.as_object().ok_or(DecodeError::WrongType("sessions"))) {
+            let client = try!(NodeId::from_str(client).ok_or(DecodeError::BadNodeId(client.clone())));
+            for reply in try!(replies.as_array().ok_or(DecodeError::WrongType("sessions"))) {
+                let mid = try!(reply.find("MID")
+                    .ok_or(DecodeError::MissingField("MID"))
+                    .and_then(|mid| mid.as_string().ok_or(DecodeError::WrongType("MID"))));
+                let msg = try!(reply.find("reply").ok_or(DecodeError::MissingField("reply")));
+                sessions.record(RequestId::new(client, mid.to_owned()), try!(MsgType::from_json(msg)));
+            }
+        }
+        Ok(sessions)
+    }
+}
+
+#[test]
+fn test_sessions_remember_recent_replies() {
+    let client = NodeId::from("C001");
+    let mut sessions = Sessions::new();
+    for i in 0..SESSION_WINDOW + 1 {
+        sessions.record(RequestId::new(client, format!("m{}", i)), MsgType::OK(i.to_string()));
+    }
+    assert_eq!(sessions.cached(&RequestId::new(client, "m0".to_owned())), None);
+    assert_eq!(sessions.cached(&RequestId::new(client, "m5".to_owned())),
+               Some(&MsgType::OK("5".to_owned())));
+    assert_eq!(sessions.cached(&RequestId::new(NodeId::from("C002"), "m5".to_owned())), None);
+    assert_eq!(Sessions::from_json(&sessions.to_json()).unwrap(), sessions);
+}
@@ -0,0 +1,426 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+
+use rustc_serialize::json::Json;
+
+use super::node::{NodeId, MAX_NODE_ID_LEN};
+use super::trace::Level;
+
+/// Everything a replica can be tuned with. Times are in milliseconds.
+///
+/// Settings come from, in increasing precedence: the defaults below, a JSON
+/// file named by `--config`, and command line flags. The file's keys are the
+/// field names; each flag is the field name with dashes, e.g.
+/// `--heartbeat-interval 50`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Settings {
+    pub id: NodeId,
+    pub peers: Vec<NodeId>,
+    /// Where the replica finds the network; defaults to its id
+    pub socket: PathBuf,
+    /// The `host:port` each replica, this one included, listens on. Given
+    /// any, peers talk over TCP directly instead of through `socket`.
+    pub addresses: BTreeMap<NodeId, String>,
+    /// How long to wait before dialing a peer again after failing to reach
+    /// it, doubling on each failure up to `reconnect_max`
+    pub reconnect_min: u64,
+    pub reconnect_max: u64,
+    /// Defaults to `raft-<id>` in the working directory
+    pub storage_dir: PathBuf,
+    /// Followers wait a random time in `[min, max)` for a leader
+    pub election_timeout_min: u64,
+    pub election_timeout_max: u64,
+    pub heartbeat_interval: u64,
+    /// How long a leadership transfer may take before the leader gives up on
+    /// it and goes back to serving clients
+    pub transfer_timeout: u64,
+    /// Most bytes of entries, as `Entry::size` counts them, sent in one
+    /// append; an entry bigger than this still goes out on its own
+    pub max_batch_bytes: usize,
+    pub max_batch_entries: usize,
+    /// Appends a leader keeps outstanding to one follower before it waits
+    /// for acknowledgements and lets new entries pile up into the next batch
+    pub max_in_flight: usize,
+    /// Applied entries kept in the log before they are folded into a snapshot
+    pub snapshot_threshold: u64,
+    /// Start as a learner: receive the log but never vote until promoted
+    pub learner: bool,
+    /// How many entries behind the commit index a learner may be when it
+    /// is promoted to voter
+    pub promote_max_lag: u64,
+    /// Followers relay reads and writes to the leader instead of answering
+    /// with a redirect, and fail them if no leader answers in
+    /// `forward_timeout`
+    pub forward_requests: bool,
+    pub forward_timeout: u64,
+    /// Recent changes each replica remembers so watches can resume
+    pub watch_history: usize,
+    pub max_watches: usize,
+    /// Offer the binary codec to peers
+    pub binary_codec: bool,
+    pub trace_level: Level,
+    /// Where trace events go: `stdout`, `stderr`, or files to append to
+    pub trace_sinks: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum SettingsError {
+    /// The config file could not be read or is not a JSON object
+    Unreadable(String),
+    Unknown(String),
+    BadValue(String, String),
+    /// Every setting parsed but together they make no sense
+    Invalid(String),
+}
+
+pub type SettingsResult<T> = Result<T, SettingsError>;
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SettingsError::Unreadable(ref why) => write!(f, "could not read config: {}", why),
+            SettingsError::Unknown(ref key) => write!(f, "unknown setting {:?}", key),
+            SettingsError::BadValue(ref key, ref value) => write!(f, "bad value {} for {:?}", value, key),
+            SettingsError::Invalid(ref why) => write!(f, "invalid settings: {}", why),
+        }
+    }
+}
+
+impl Error for SettingsError {
+    fn description(&self) -> &str {
+        match *self {
+            SettingsError::Unreadable(_) => "could not read config",
+            SettingsError::Unknown(_) => "unknown setting",
+            SettingsError::BadValue(..) => "bad setting value",
+            SettingsError::Invalid(_) => "invalid settings",
+        }
+    }
+}
+
+impl Settings {
+    /// The lab's defaults for a replica of a cluster of `id` and `peers`
+    pub fn new(id: NodeId, peers: Vec<NodeId>) -> Settings {
+        Settings {
+            id: id,
+            peers: peers,
+            socket: PathBuf::from(id.as_str()),
+            addresses: BTreeMap::new(),
+            reconnect_min: 50,
+            reconnect_max: 2000,
+            storage_dir: PathBuf::from(format!("raft-{}", id.as_str())),
+            election_timeout_min: 150,
+            election_timeout_max: 300,
+            heartbeat_interval: 100,
+            transfer_timeout: 300,
+            max_batch_bytes: 32 * 1024,
+            max_batch_entries: 512,
+            max_in_flight: 4,
+            snapshot_threshold: 1000,
+            learner: false,
+            promote_max_lag: 64,
+            forward_requests: false,
+            forward_timeout: 1000,
+            watch_history: 10000,
+            max_watches: 1024,
+            binary_codec: false,
+            trace_level: Level::Info,
+            trace_sinks: vec!["stdout".to_owned()],
+        }
+    }
+
+    /// Reads `raft [--config FILE] [--setting VALUE]... ID PEER...`. The id
+    /// and peers may come from the file instead of the command line; an id
+    /// given alone keeps the file's peers.
+    pub fn from_args<I: Iterator<Item=String>>(args: I) -> SettingsResult<Settings> {
+        let mut flags = vec![];
+        let mut positional = vec![];
+        let mut file = None;
+        let mut args = args;
+        while let Some(arg) = args.next() {
+            if arg.starts_with("--") {
+                let value = try!(args.next().ok_or(SettingsError::BadValue(arg.clone(), "(missing)".to_owned())));
+                if arg == "--config" {
+                    file = Some(value);
+                } else {
+                    flags.push((arg[2..].replace("-", "_"), value));
+                }
+            } else {
+                positional.push(arg);
+            }
+        }
+
+        let mut raw = match file {
+            Some(path) => try!(read_file(&path)),
+            None       => BTreeMap::new(),
+        };
+        for (key, value) in flags {
+            raw.insert(key, Raw::Flag(value));
+        }
+        let mut positional = positional.into_iter();
+        if let Some(id) = positional.next() {
+            raw.insert("id".to_owned(), Raw::Flag(id));
+            let peers: Vec<String> = positional.collect();
+            if !peers.is_empty() {
+                raw.insert("peers".to_owned(), Raw::Flag(peers.join(",")));
+            }
+        }
+        Settings::from_raw(raw)
+    }
+
+    fn from_raw(mut raw: BTreeMap<String, Raw>) -> SettingsResult<Settings> {
+        let id = match raw.remove("id") {
+            Some(id) => try!(id.node_id("id")),
+            None     => return Err(SettingsError::Invalid("no node id given".to_owned())),
+        };
+        let peers = match raw.remove("peers") {
+            Some(peers) => try!(peers.node_ids("peers")),
+            None        => vec![],
+        };
+        let mut settings = Settings::new(id, peers);
+        for (key, value) in raw {
+            match &key[..] {
+                "socket"               => settings.socket = PathBuf::from(try!(value.string(&key))),
+                "addresses"            => settings.addresses = try!(value.addresses(&key)),
+                "reconnect_min"        => settings.reconnect_min = try!(value.number(&key)),
+                "reconnect_max"        => settings.reconnect_max = try!(value.number(&key)),
+                "storage_dir"          => settings.storage_dir = PathBuf::from(try!(value.string(&key))),
+                "election_timeout_min" => settings.election_timeout_min = try!(value.number(&key)),
+                "election_timeout_max" => settings.election_timeout_max = try!(value.number(&key)),
+                "heartbeat_interval"   => settings.heartbeat_interval = try!(value.number(&key)),
+                "transfer_timeout"     => settings.transfer_timeout = try!(value.number(&key)),
+                "max_batch_bytes"      => settings.max_batch_bytes = try!(value.number(&key)) as usize,
+                "max_batch_entries"    => settings.max_batch_entries = try!(value.number(&key)) as usize,
+                "max_in_flight"        => settings.max_in_flight = try!(value.number(&key)) as usize,
+                "snapshot_threshold"   => settings.snapshot_threshold = try!(value.number(&key)),
+                "learner"              => settings.learner = try!(value.boolean(&key)),
+                "promote_max_lag"      => settings.promote_max_lag = try!(value.number(&key)),
+                "forward_requests"     => settings.forward_requests = try!(value.boolean(&key)),
+                "forward_timeout"      => settings.forward_timeout = try!(value.number(&key)),
+                "watch_history"        => settings.watch_history = try!(value.number(&key)) as usize,
+                "max_watches"          => settings.max_watches = try!(value.number(&key)) as usize,
+                "binary_codec"         => settings.binary_codec = try!(value.boolean(&key)),
+                "trace_level"          => settings.trace_level = try!(Level::from_str(&try!(value.string(&key)))
+                                                                   .ok_or(value.bad(&key))),
+                "trace_sinks"          => settings.trace_sinks = try!(value.strings(&key)),
+                _ => return Err(SettingsError::Unknown(key)),
+            }
+        }
+        try!(settings.validate());
+        Ok(settings)
+    }
+
+    pub fn validate(&self) -> SettingsResult<()> {
+        let invalid = |why: String| Err(SettingsError::Invalid(why));
+        if self.election_timeout_min == 0 || self.election_timeout_max <= self.election_timeout_min {
+            return invalid(format!("the election timeout must be a nonempty range, not {}-{}",
+                                   self.election_timeout_min, self.election_timeout_max));
+        }
+        // Followers would start elections against a live leader
+        if self.heartbeat_interval == 0 || self.heartbeat_interval >= self.election_timeout_min {
+            return invalid(format!("the heartbeat interval {} must be shorter than the election timeout {}",
+                                   self.heartbeat_interval, self.election_timeout_min));
+        }
+        if self.max_batch_bytes == 0 || self.max_batch_entries == 0 || self.max_in_flight == 0 {
+            return invalid("batch sizes and max_in_flight must be positive".to_owned());
+        }
+        if self.forward_requests && self.forward_timeout == 0 {
+            return invalid("forward_timeout must be positive".to_owned());
+        }
+        if self.snapshot_threshold == 0 {
+            return invalid("snapshot_threshold must be positive".to_owned());
+        }
+        let mut ids = self.peers.clone();
+        ids.push(self.id);
+        ids.sort();
+        if ids.windows(2).any(|pair| pair[0] == pair[1]) {
+            return invalid("node ids must be unique".to_owned());
+        }
+        if ids.contains(&NodeId::broadcast()) {
+            return invalid(format!("{} is the broadcast address", NodeId::broadcast().as_str()));
+        }
+        if !self.addresses.is_empty() {
+            if let Some(id) = ids.iter().find(|id| !self.addresses.contains_key(id)) {
+                return invalid(format!("no address for {}", id.as_str()));
+            }
+            if self.reconnect_min == 0 || self.reconnect_max < self.reconnect_min {
+                return invalid(format!("reconnect backoff must be a nonempty range, not {}-{}",
+                                       self.reconnect_min, self.reconnect_max));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A setting before it is checked: flags are always strings, while the file
+/// can use any JSON type
+enum Raw {
+    Flag(String),
+    Json(Json),
+}
+
+impl Raw {
+    fn bad(&self, key: &str) -> SettingsError {
+        let value = match *self {
+            Raw::Flag(ref s) => format!("{:?}", s),
+            Raw::Json(ref j) => j.to_string(),
+        };
+        SettingsError::BadValue(key.to_owned(), value)
+    }
+
+    fn string(&self, key: &str) -> SettingsResult<String> {
+        match *self {
+            Raw::Flag(ref s) => Ok(s.clone()),
+            Raw::Json(ref j) => j.as_string().map(str::to_owned).ok_or(self.bad(key)),
+        }
+    }
+
+    fn number(&self, key: &str) -> SettingsResult<u64> {
+        match *self {
+            Raw::Flag(ref s) => s.parse().map_err(|_| self.bad(key)),
+            Raw::Json(ref j) => j.as_u64().ok_or(self.bad(key)),
+        }
+    }
+
+    fn boolean(&self, key: &str) -> SettingsResult<bool> {
+        match *self {
+            Raw::Flag(ref s) => s.parse().map_err(|_| self.bad(key)),
+            Raw::Json(ref j) => j.as_boolean().ok_or(self.bad(key)),
+        }
+    }
+
+    fn node_id(&self, key: &str) -> SettingsResult<NodeId> {
+        let id = try!(self.string(key));
+        NodeId::from_str(&id).ok_or(self.bad(key))
+    }
+
+    /// A comma separated list on the command line, an array in the file
+    fn strings(&self, key: &str) -> SettingsResult<Vec<String>> {
+        match *self {
+            Raw::Flag(ref s) => Ok(s.split(',').filter(|s| !s.is_empty()).map(str::to_owned).collect()),
+            Raw::Json(ref j) => try!(j.as_array().ok_or(self.bad(key))).iter()
+                .map(|s| s.as_string().map(str::to_owned).ok_or(self.bad(key)))
+                .collect(),
+        }
+    }
+
+    fn node_ids(&self, key: &str) -> SettingsResult<Vec<NodeId>> {
+        let ids = try!(self.strings(key));
+        ids.iter()
+            .map(|id| NodeId::from_str(id).ok_or(SettingsError::BadValue(
+                key.to_owned(), format!("{:?} (ids are 1 to {} printable characters)", id, MAX_NODE_ID_LEN))))
+            .collect()
+    }
+
+    /// `ID=HOST:PORT,...` on the command line, an object of addresses by id
+    /// in the file
+    fn addresses(&self, key: &str) -> SettingsResult<BTreeMap<NodeId, String>> {
+        let pairs = match *self {
+            Raw::Flag(ref s) => try!(s.split(',').filter(|s| !s.is_empty())
+                .map(|pair| {
+                    let mut parts = pair.splitn(2, '=');
+                    match (parts.next(), parts.next()) {
+                        (Some(id), Some(addr)) => Ok((id.to_owned(), addr.to_owned())),
+                        _                      => Err(self.bad(key)),
+                    }
+                })
+                .collect::<SettingsResult<Vec<_>>>()),
+            Raw::Json(ref j) => try!(try!(j.as_object().ok_or(self.bad(key))).iter()
+                .map(|(id, addr)| addr.as_string().map(|addr| (id.clone(), addr.to_owned())).ok_or(self.bad(key)))
+                .collect::<SettingsResult<Vec<_>>>()),
+        };
+        pairs.into_iter()
+            .map(|(id, addr)| match NodeId::from_str(&id) {
+                Some(id) if !addr.is_empty() => Ok((id, addr)),
+                _                            => Err(self.bad(key)),
+            })
+            .collect()
+    }
+}
+
+fn read_file(path: &str) -> SettingsResult<BTreeMap<String, Raw>> {
+    let mut text = String::new();
+    try!(File::open(path)
+         .and_then(|mut file| file.read_to_string(&mut text))
+         .map_err(|e| SettingsError::Unreadable(format!("{}: {}", path, e))));
+    match Json::from_str(&text) {
+        Ok(Json::Object(obj)) => Ok(obj.into_iter().map(|(k, v)| (k, Raw::Json(v))).collect()),
+        Ok(_)                 => Err(SettingsError::Unreadable(format!("{}: not a JSON object", path))),
+        Err(e)                => Err(SettingsError::Unreadable(format!("{}: {}", path, e))),
+    }
+}
+
+#[allow(dead_code)]
+fn args(line: &str) -> ::std::vec::IntoIter<String> {
+    line.split_whitespace().map(str::to_owned).collect::<Vec<_>>().into_iter()
+}
+
+#[test]
+fn test_flags_override_the_file() {
+    let path = ::std::env::temp_dir().join(format!("raft-settings-{}.json", ::std::process::id()));
+    {
+        use std::io::Write;
+        let mut file = File::create(&path).unwrap();
+        file.write_all(b"{\"id\": \"east-1\", \"peers\": [\"east-2\", \"west-1\"],
+                          \"heartbeat_interval\": 40, \"election_timeout_min\": 400,
+                          \"election_timeout_max\": 800, \"storage_dir\": \"/var/lib/raft\",
+                          \"trace_sinks\": [\"stderr\", \"/var/log/raft.jsonl\"]}").unwrap();
+    }
+    let settings = Settings::from_args(args(&format!("--config {} --heartbeat-interval 60 --trace-level debug", path.display()))).unwrap();
+    let renamed = Settings::from_args(args(&format!("--config {} east-3", path.display()))).unwrap();
+    let moved = Settings::from_args(args(&format!("--config {} east-3 east-1", path.display()))).unwrap();
+    ::std::fs::remove_file(&path).unwrap();
+
+    assert_eq!((renamed.id, renamed.peers), (NodeId::from("east-3"), settings.peers.clone()));
+    assert_eq!(moved.peers, vec![NodeId::from("east-1")]);
+
+    assert_eq!(settings.id, NodeId::from("east-1"));
+    assert_eq!(settings.peers, vec![NodeId::from("east-2"), NodeId::from("west-1")]);
+    assert_eq!(settings.socket, PathBuf::from("east-1"));
+    assert_eq!(settings.storage_dir, PathBuf::from("/var/lib/raft"));
+    assert_eq!((settings.election_timeout_min, settings.election_timeout_max), (400, 800));
+    assert_eq!(settings.heartbeat_interval, 60);
+    assert_eq!(settings.trace_level, Level::Debug);
+    assert_eq!(settings.trace_sinks, vec!["stderr".to_owned(), "/var/log/raft.jsonl".to_owned()]);
+    assert!(settings.addresses.is_empty());
+}
+
+#[test]
+fn test_addresses_name_every_replica() {
+    let settings = Settings::from_args(
+        args("--addresses 0000=10.0.0.1:7000,0001=10.0.0.2:7000,0002=[::1]:7000 --reconnect-max 500 0000 0001 0002"))
+        .unwrap();
+    assert_eq!(settings.addresses.get(&NodeId::from("0000")).map(|a| &a[..]), Some("10.0.0.1:7000"));
+    assert_eq!(settings.addresses.get(&NodeId::from("0002")).map(|a| &a[..]), Some("[::1]:7000"));
+    assert_eq!((settings.reconnect_min, settings.reconnect_max), (50, 500));
+
+    match Settings::from_args(args("--addresses 0000=10.0.0.1:7000,0001=10.0.0.2:7000 0000 0001 0002")) {
+        Err(SettingsError::Invalid(_)) => {},
+        other => panic!("a peer without an address was accepted: {:?}", other),
+    }
+    assert!(Settings::from_args(args("--addresses 0000 0000")).is_err());
+    assert!(Settings::from_args(args("--addresses 0000=a:1 --reconnect-min 0 0000")).is_err());
+}
+
+#[test]
+fn test_bad_settings_are_rejected() {
+    assert_eq!(Settings::from_args(args("0000 0001 0002")).unwrap(),
+               Settings::new(NodeId::from("0000"), vec![NodeId::from("0001"), NodeId::from("0002")]));
+    match Settings::from_args(args("--heartbeat-interval 200 0000 0001")) {
+        Err(SettingsError::Invalid(_)) => {},
+        other => panic!("heartbeat slower than elections was accepted: {:?}", other),
+    }
+    assert_eq!(Settings::from_args(args("--max-batch-bytes lots 0000")),
+               Err(SettingsError::BadValue("max_batch_bytes".to_owned(), "\"lots\"".to_owned())));
+    assert_eq!(Settings::from_args(args("--election-timout-min 5 0000")),
+               Err(SettingsError::Unknown("election_timout_min".to_owned())));
+    assert!(Settings::from_args(args("--binary-codec true 0000")).unwrap().binary_codec);
+    assert_eq!(Settings::from_args(args("--codec binary 0000")), Err(SettingsError::Unknown("codec".to_owned())));
+    assert_eq!(Settings::from_args(args("--trace-level loud 0000")),
+               Err(SettingsError::BadValue("trace_level".to_owned(), "\"loud\"".to_owned())));
+    assert!(Settings::from_args(args("0000 0001 0000")).is_err());
+    assert!(Settings::from_args(args("0000 a-very-long-node-name-that-will-not-fit")).is_err());
+}
@@ -0,0 +1,325 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use rustc_serialize::json::{Json, ToJson};
+
+use super::msg::{AddJson, Command, DecodeResult, MsgType};
+use super::state_machine::StateMachine;
+
+/// The keys from `start` up to but not including `end`, or through the last
+/// key if there is no `end`
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct KeyRange {
+    pub start: String,
+    pub end: Option<String>,
+}
+
+impl KeyRange {
+    pub fn new(start: &str, end: Option<&str>) -> KeyRange {
+        KeyRange {
+            start: start.to_owned(),
+            end: end.map(str::to_owned),
+        }
+    }
+
+    /// Every key there is
+    pub fn all() -> KeyRange {
+        KeyRange::new("", None)
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        key >= &self.start[..] && self.end.as_ref().map_or(true, |end| key < &end[..])
+    }
+}
+
+/// One consensus group's share of the key space, kept in front of the state
+/// machine holding its keys. Splits and merges are log entries like any
+/// other, so every replica of the group agrees on which keys it served at
+/// each index; a command or read for a key it doesn't serve is redirected
+/// without touching the state machine.
+///
+/// A replica that hasn't yet got its group's first snapshot has no range at
+/// all and serves nothing.
+pub struct Shard<S: StateMachine> {
+    group: String,
+    range: Option<KeyRange>,
+    /// Set once the group has agreed to hand its range to its left neighbour
+    retired: bool,
+    /// How many groups have split off, for naming the next one
+    splits: u64,
+    /// Groups whose ranges this one has taken over, directly or not
+    absorbed: BTreeSet<String>,
+    inner: S,
+    /// Groups split off since the host last looked, with their state. This
+    /// is not replicated state: each replica hands on its own.
+    spawned: Vec<(String, Json)>,
+}
+
+impl <S: StateMachine>Shard<S> {
+    pub fn new(group: &str, range: Option<KeyRange>, inner: S) -> Shard<S> {
+        Shard {
+            group: group.to_owned(),
+            range: range,
+            retired: false,
+            splits: 0,
+            absorbed: BTreeSet::new(),
+            inner: inner,
+            spawned: vec![],
+        }
+    }
+
+    pub fn group(&self) -> &str {
+        &self.group
+    }
+
+    /// `None` until the group's first snapshot arrives
+    pub fn range(&self) -> Option<&KeyRange> {
+        self.range.as_ref()
+    }
+
+    pub fn is_retired(&self) -> bool {
+        self.retired
+    }
+
+    pub fn absorbed(&self) -> &BTreeSet<String> {
+        &self.absorbed
+    }
+
+    pub fn serves(&self, key: &str) -> bool {
+        !self.retired && self.range.as_ref().map_or(false, |range| range.contains(key))
+    }
+
+    /// The state each newly split off group starts from, by group
+    pub fn take_spawned(&mut self) -> Vec<(String, Json)> {
+        self.spawned.drain(..).collect()
+    }
+
+    fn split(&mut self, at: &str) -> MsgType {
+        if !self.serves(at) {
+            return MsgType::Redirect;
+        }
+        let range = self.range.clone().unwrap();
+        if at == range.start {
+            return MsgType::Fail;
+        }
+        let child = format!("{}.{}", self.group, self.splits);
+        self.splits += 1;
+        let state = shard_state(&child, &KeyRange { start: at.to_owned(), end: range.end }, false, 0,
+                                &BTreeSet::new(), self.inner.split_off(at));
+        self.spawned.push((child.clone(), state));
+        self.range = Some(KeyRange { start: range.start, end: Some(at.to_owned()) });
+        MsgType::OK(child)
+    }
+
+    fn retire(&mut self, key: &str) -> MsgType {
+        if !self.serves(key) {
+            return MsgType::Redirect;
+        }
+        if self.range.as_ref().unwrap().start.is_empty() {
+            // Nothing comes before the first key to take it over
+            return MsgType::Fail;
+        }
+        self.retired = true;
+        MsgType::OK(String::new())
+    }
+
+    fn merge(&mut self, from: &str, data: &str) -> MsgType {
+        if self.absorbed.contains(from) {
+            return MsgType::OK(String::new());
+        }
+        let end = match self.range {
+            Some(ref range) if !self.retired => range.end.clone(),
+            _                                => return MsgType::Redirect,
+        };
+        let other = match Json::from_str(data).ok().and_then(|data| ShardState::from_json(&data)) {
+            Some(other) => other,
+            None        => return MsgType::Fail,
+        };
+        if !other.retired || end.as_ref() != Some(&other.range.start) {
+            return MsgType::Fail;
+        }
+        if self.inner.absorb(&other.data).is_err() {
+            return MsgType::Fail;
+        }
+        self.range.as_mut().unwrap().end = other.range.end;
+        self.absorbed.insert(from.to_owned());
+        self.absorbed.extend(other.absorbed);
+        MsgType::OK(String::new())
+    }
+}
+
+impl <S: StateMachine>StateMachine for Shard<S> {
+    fn apply(&mut self, command: &Command) -> MsgType {
+        match *command {
+            Command::Split(ref at) => self.split(at),
+            Command::Retire(ref key) => self.retire(key),
+            Command::Merge {ref from, ref data, ..} => self.merge(from, data),
+            ref command => {
+                if command.keys().iter().all(|key| self.serves(key)) {
+                    self.inner.apply(command)
+                } else {
+                    MsgType::Redirect
+                }
+            },
+        }
+    }
+
+    fn query(&self, key: &str) -> MsgType {
+        if self.serves(key) { self.inner.query(key) } else { MsgType::Redirect }
+    }
+
+    fn snapshot(&self) -> Json {
+        match self.range {
+            Some(ref range) => shard_state(&self.group, range, self.retired, self.splits, &self.absorbed,
+                                           self.inner.snapshot()),
+            None            => self.inner.snapshot(),
+        }
+    }
+
+    /// Snapshots taken before the group had a range, including those of a
+    /// replica older than sharding, are the inner state machine's alone
+    fn restore(&mut self, snapshot: &Json) -> DecodeResult<()> {
+        match ShardState::from_json(snapshot) {
+            Some(state) => {
+                try!(self.inner.restore(&state.data));
+                self.group = state.group;
+                self.range = Some(state.range);
+                self.retired = state.retired;
+                self.splits = state.splits;
+                self.absorbed = state.absorbed;
+            },
+            None => try!(self.inner.restore(snapshot)),
+        }
+        self.spawned.clear();
+        Ok(())
+    }
+
+    fn split_off(&mut self, at: &str) -> Json {
+        self.inner.split_off(at)
+    }
+
+    fn absorb(&mut self, snapshot: &Json) -> DecodeResult<()> {
+        self.inner.absorb(snapshot)
+    }
+}
+
+/// A shard's snapshot: `{"shard": {...}, "data": ...}`
+struct ShardState {
+    group: String,
+    range: KeyRange,
+    retired: bool,
+    splits: u64,
+    absorbed: BTreeSet<String>,
+    data: Json,
+}
+
+impl ShardState {
+    fn from_json(json: &Json) -> Option<ShardState> {
+        let shard = match json.find("shard") {
+            Some(shard) if shard.is_object() => shard,
+            _                                => return None,
+        };
+        let string = |key: &str| shard.find(key).and_then(Json::as_string).map(str::to_owned);
+        Some(ShardState {
+            group: match string("group") { Some(group) => group, None => return None },
+            range: KeyRange {
+                start: match string("start") { Some(start) => start, None => return None },
+                end: string("end"),
+            },
+            retired: shard.find("retired").and_then(Json::as_boolean).unwrap_or(false),
+            splits: shard.find("splits").and_then(Json::as_u64).unwrap_or(0),
+            absorbed: shard.find("absorbed")
+                .and_then(Json::as_array)
+                .map_or(BTreeSet::new(), |groups| groups.iter()
+                        .filter_map(|group| group.as_string().map(str::to_owned))
+                        .collect()),
+            data: match json.find("data") { Some(data) => data.clone(), None => return None },
+        })
+    }
+}
+
+fn shard_state(group: &str, range: &KeyRange, retired: bool, splits: u64, absorbed: &BTreeSet<String>,
+               data: Json) -> Json {
+    let mut shard = BTreeMap::new();
+    shard.add_json("group", group.to_owned());
+    shard.add_json("start", range.start.clone());
+    shard.add_json("end", range.end.clone());
+    shard.add_json("retired", retired);
+    shard.add_json("splits", splits);
+    shard.add_json("absorbed", absorbed.iter().cloned().collect::<Vec<_>>());
+    let mut d = BTreeMap::new();
+    d.add_json("shard", Json::Object(shard));
+    d.add_json("data", data);
+    d.to_json()
+}
+
+/// Which group serves each key, by where its range starts
+pub struct RoutingTable {
+    ranges: BTreeMap<String, (Option<String>, String)>,
+}
+
+impl RoutingTable {
+    pub fn new() -> RoutingTable {
+        RoutingTable {
+            ranges: BTreeMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, range: KeyRange, group: &str) {
+        self.ranges.insert(range.start, (range.end, group.to_owned()));
+    }
+
+    /// The group serving `key`, if any range holds it
+    pub fn route(&self, key: &str) -> Option<&str> {
+        match self.ranges.iter().rev().find(|&(start, _)| &start[..] <= key) {
+            Some((_, &(ref end, ref group))) if end.as_ref().map_or(true, |end| key < &end[..]) => Some(group),
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn test_shards_split_and_merge_through_their_commands() {
+    use super::state_machine::KvStore;
+
+    let s = |x: &str| x.to_owned();
+    let mut left = Shard::new("0", Some(KeyRange::all()), KvStore::new());
+    left.apply(&Command::Put(s("a"), s("1")));
+    left.apply(&Command::Put(s("m"), s("2")));
+
+    assert_eq!(left.apply(&Command::Split(s("m"))), MsgType::OK(s("0.0")));
+    assert_eq!(left.apply(&Command::Split(s(""))), MsgType::Fail, "nothing would be left behind");
+    assert_eq!(left.query("m"), MsgType::Redirect);
+    assert_eq!(left.apply(&Command::Put(s("z"), s("3"))), MsgType::Redirect);
+    let (group, state) = left.take_spawned().pop().unwrap();
+    let mut right = Shard::new(&group, None, KvStore::new());
+    assert_eq!(right.query("m"), MsgType::Redirect, "a shard without a range serves nothing");
+    right.restore(&state).unwrap();
+    assert_eq!(right.range(), Some(&KeyRange::new("m", None)));
+    assert_eq!(right.query("m"), MsgType::OK(s("2")));
+    assert_eq!(right.apply(&Command::Put(s("z"), s("3"))), MsgType::OK(s("3")));
+
+    let mut routes = RoutingTable::new();
+    routes.insert(left.range().unwrap().clone(), left.group());
+    routes.insert(right.range().unwrap().clone(), right.group());
+    assert_eq!(routes.route("a"), Some("0"));
+    assert_eq!(routes.route("zz"), Some("0.0"));
+
+    assert_eq!(left.apply(&Command::Retire(s("a"))), MsgType::Fail, "the first group has no left neighbour");
+    let merge = Command::Merge { from: s("0.0"), data: right.snapshot().to_string(), sessions: s("{}") };
+    assert_eq!(left.apply(&merge), MsgType::Fail, "only a retired group can be absorbed");
+    assert_eq!(right.apply(&Command::Retire(s("m"))), MsgType::OK(s("")));
+    assert_eq!(right.query("z"), MsgType::Redirect);
+    let merge = Command::Merge { from: s("0.0"), data: right.snapshot().to_string(), sessions: s("{}") };
+    assert_eq!(left.apply(&merge), MsgType::OK(s("")));
+    assert_eq!(left.apply(&merge), MsgType::OK(s("")), "absorbing again changes nothing");
+    assert_eq!(left.query("z"), MsgType::OK(s("3")));
+    assert!(left.absorbed().contains("0.0"));
+
+    let mut copy = Shard::new("0", Some(KeyRange::all()), KvStore::new());
+    copy.restore(&left.snapshot()).unwrap();
+    assert_eq!(copy.apply(&Command::Split(s("q"))), MsgType::OK(s("0.1")), "split counts survive snapshots");
+
+    let mut legacy = Shard::new("0", Some(KeyRange::all()), KvStore::new());
+    legacy.restore(&Json::from_str("{\"x\":\"1\"}").unwrap()).unwrap();
+    assert_eq!(legacy.query("x"), MsgType::OK(s("1")));
+}
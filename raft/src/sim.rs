@@ -0,0 +1,1330 @@
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::Entry as MapEntry;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+use super::clock::Clock;
+use super::history::History;
+use super::host::Host;
+use super::msg::{BaseMsg, Entry, Msg, MsgType};
+use super::node::{Node, NodeId};
+use super::settings::Settings;
+use super::state_machine::KvStore;
+use super::storage::Storage;
+use super::trace::Tracer;
+use super::transport::Transport;
+
+/// Queues a simulated replica's messages until the simulation routes them
+pub struct SimTransport {
+    outbox: Rc<RefCell<Vec<Msg>>>,
+}
+
+impl Transport for SimTransport {
+    fn send(&self, msg: &Msg) {
+        self.outbox.borrow_mut().push(msg.clone());
+    }
+}
+
+/// The simulation's virtual clock, which only moves when the simulation runs
+pub struct SimClock {
+    now: Rc<Cell<u64>>,
+}
+
+impl Clock for SimClock {
+    fn now_ms(&self) -> u64 {
+        self.now.get()
+    }
+}
+
+/// How badly the simulated network behaves. Every message is independently
+/// dropped, duplicated and delayed by between `min_delay` and `max_delay` ms.
+#[derive(Clone, Debug)]
+pub struct Faults {
+    pub drop_rate: f64,
+    pub duplicate_rate: f64,
+    pub min_delay: u64,
+    pub max_delay: u64,
+}
+
+impl Faults {
+    pub fn reliable() -> Faults {
+        Faults {
+            drop_rate: 0.0,
+            duplicate_rate: 0.0,
+            min_delay: 1,
+            max_delay: 10,
+        }
+    }
+
+    pub fn lossy() -> Faults {
+        Faults {
+            drop_rate: 0.05,
+            duplicate_rate: 0.05,
+            min_delay: 1,
+            max_delay: 40,
+        }
+    }
+}
+
+struct Replica {
+    node: Option<Node>,
+    outbox: Rc<RefCell<Vec<Msg>>>,
+    dir: PathBuf,
+    checked_idx: u64,
+    learner: bool,
+}
+
+enum Event {
+    Deliver((u64, u64)),
+    Timeout(NodeId),
+}
+
+/// A whole cluster run on one thread against a virtual clock. Everything the
+/// replicas do follows from the seed, so a failing seed replays exactly.
+/// Election safety and agreement on committed entries are checked after every
+/// event, and violations panic with the seed.
+pub struct Simulation {
+    name: String,
+    tune: fn(&mut Settings),
+    seed: u64,
+    now: Rc<Cell<u64>>,
+    rng: XorShiftRng,
+    faults: Faults,
+    replicas: BTreeMap<NodeId, Replica>,
+    in_flight: BTreeMap<(u64, u64), Msg>,
+    sent: u64,
+    groups: HashMap<NodeId, usize>,
+    replies: Vec<Msg>,
+    history: History,
+    trace: Vec<String>,
+    leaders: HashMap<u64, NodeId>,
+    committed: HashMap<u64, Entry>,
+}
+
+impl Simulation {
+    /// A fresh cluster of `size` replicas named "0000", "0001", ... whose
+    /// storage lives in temporary directories prefixed with `name`
+    pub fn new(name: &str, seed: u64, size: usize, faults: Faults) -> Simulation {
+        fn defaults(_: &mut Settings) {}
+        Simulation::with_settings(name, seed, size, faults, defaults)
+    }
+
+    /// Like `new`, with every replica's settings passed through `tune`
+    pub fn with_settings(name: &str, seed: u64, size: usize, faults: Faults, tune: fn(&mut Settings)) -> Simulation {
+        let mut sim = Simulation {
+            name: name.to_owned(),
+            tune: tune,
+            seed: seed,
+            now: Rc::new(Cell::new(0)),
+            rng: XorShiftRng::from_seed([seed as u32, (seed >> 32) as u32, 0x9e37_79b9, 0x7f4a_7c15]),
+            faults: faults,
+            replicas: BTreeMap::new(),
+            in_flight: BTreeMap::new(),
+            sent: 0,
+            groups: HashMap::new(),
+            replies: vec![],
+            history: History::new(),
+            trace: vec![],
+            leaders: HashMap::new(),
+            committed: HashMap::new(),
+        };
+        let ids: Vec<String> = (0..size).map(|i| format!("{:04}", i)).collect();
+        for id in &ids {
+            let dir = env::temp_dir().join(format!("raft-sim-{}-{}", name, id));
+            drop(fs::remove_dir_all(&dir));
+            sim.replicas.insert(NodeId::from(&id[..]), Replica {
+                node: None,
+                outbox: Rc::new(RefCell::new(vec![])),
+                dir: dir,
+                checked_idx: 0,
+                learner: false,
+            });
+        }
+        for id in ids {
+            sim.restart(NodeId::from(id));
+        }
+        sim
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn now(&self) -> u64 {
+        self.now.get()
+    }
+
+    /// The gets and puts clients have made so far
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    /// Every delivery, drop and timeout so far, in order
+    pub fn trace(&self) -> &[String] {
+        &self.trace
+    }
+
+    pub fn node(&self, id: NodeId) -> Option<&Node> {
+        self.replicas.get(&id).and_then(|replica| replica.node.as_ref())
+    }
+
+    /// The running leader with the highest term, if there is one
+    pub fn leader(&self) -> Option<NodeId> {
+        self.replicas.values()
+            .filter_map(|replica| replica.node.as_ref())
+            .filter(|node| node.is_leader())
+            .max_by_key(|node| node.term())
+            .map(|node| node.id())
+    }
+
+    /// Stops a replica, losing everything it has not persisted
+    pub fn crash(&mut self, id: NodeId) {
+        let replica = self.replicas.get_mut(&id).expect("no such replica");
+        replica.node = None;
+        replica.outbox.borrow_mut().clear();
+        self.trace.push(format!("{} crash {}", self.now.get(), id));
+    }
+
+    /// Starts a replica from whatever its storage holds
+    pub fn restart(&mut self, id: NodeId) {
+        let peers = self.replicas.iter()
+            .filter(|&(peer, replica)| *peer != id && !replica.learner)
+            .map(|(peer, _)| *peer)
+            .collect();
+        let node_seed = [self.rng.next_u32() | 1, self.rng.next_u32(), self.rng.next_u32(), self.rng.next_u32()];
+        let replica = self.replicas.get_mut(&id).expect("no such replica");
+        let storage = Storage::open(&replica.dir).ok().expect("could not open simulated storage");
+        replica.outbox.borrow_mut().clear();
+        replica.checked_idx = 0;
+        let mut settings = Settings::new(id, peers);
+        settings.learner = replica.learner;
+        settings.trace_sinks = vec![];
+        (self.tune)(&mut settings);
+        let tracer = Tracer::from_settings(&settings, Box::new(SimClock { now: self.now.clone() }))
+            .ok()
+            .expect("could not open trace sinks");
+        replica.node = Some(Node::new(settings,
+                                      storage,
+                                      KvStore::new(),
+                                      Box::new(SimTransport { outbox: replica.outbox.clone() }),
+                                      Box::new(SimClock { now: self.now.clone() }),
+                                      Box::new(XorShiftRng::from_seed(node_seed)),
+                                      tracer));
+        self.trace.push(format!("{} start {}", self.now.get(), id));
+    }
+
+    /// Starts a new, empty replica that expects to join as a learner; the
+    /// leader still has to be asked to add it
+    pub fn add_learner(&mut self, id: NodeId) {
+        let dir = env::temp_dir().join(format!("raft-sim-{}-{}", self.name, id.as_str()));
+        drop(fs::remove_dir_all(&dir));
+        self.replicas.insert(id, Replica {
+            node: None,
+            outbox: Rc::new(RefCell::new(vec![])),
+            dir: dir,
+            checked_idx: 0,
+            learner: true,
+        });
+        self.restart(id);
+    }
+
+    /// Cuts the network into `groups`. Replicas left out of every group form
+    /// one more group; clients can always reach everyone.
+    pub fn partition(&mut self, groups: &[&[NodeId]]) {
+        self.groups.clear();
+        for (i, group) in groups.iter().enumerate() {
+            for id in group.iter() {
+                self.groups.insert(*id, i + 1);
+            }
+        }
+        self.trace.push(format!("{} partition {:?}", self.now.get(), groups));
+    }
+
+    pub fn heal(&mut self) {
+        self.groups.clear();
+        self.trace.push(format!("{} heal", self.now.get()));
+    }
+
+    /// Sends `msg` to replica `dst` on behalf of `client`. Replies addressed
+    /// to clients collect in `take_replies`.
+    pub fn request(&mut self, client: NodeId, dst: NodeId, mid: &str, msg: MsgType) {
+        self.history.invoke(client, mid, self.now.get(), &msg);
+        let base = BaseMsg::new(client, dst, NodeId::broadcast(), mid.to_owned());
+        self.route(Msg::new(base, msg));
+    }
+
+    pub fn take_replies(&mut self) -> Vec<Msg> {
+        self.replies.drain(..).collect()
+    }
+
+    /// Runs every delivery and timeout due in the next `ms` milliseconds
+    pub fn run_for(&mut self, ms: u64) {
+        let until = self.now.get() + ms;
+        while let Some((at, event)) = self.next_event() {
+            if at > until {
+                break;
+            }
+            self.now.set(cmp::max(self.now.get(), at));
+            match event {
+                Event::Deliver(key) => {
+                    let msg = self.in_flight.remove(&key).unwrap();
+                    self.deliver(msg);
+                },
+                Event::Timeout(id) => {
+                    self.trace.push(format!("{} timeout {}", self.now.get(), id));
+                    self.replicas.get_mut(&id).unwrap().node.as_mut().unwrap().tick();
+                    self.after(id);
+                },
+            }
+        }
+        self.now.set(until);
+    }
+
+    fn next_event(&self) -> Option<(u64, Event)> {
+        let delivery = self.in_flight.keys().next().map(|&key| (key.0, Event::Deliver(key)));
+        let timeout = self.replicas.iter()
+            .filter_map(|(id, replica)| replica.node.as_ref().map(|node| (node.deadline(), Event::Timeout(*id))))
+            .min_by_key(|&(at, _)| at);
+        match (delivery, timeout) {
+            (Some(delivery), Some(timeout)) => Some(if delivery.0 <= timeout.0 { delivery } else { timeout }),
+            (delivery, timeout) => delivery.or(timeout),
+        }
+    }
+
+    fn connected(&self, a: NodeId, b: NodeId) -> bool {
+        if !self.replicas.contains_key(&a) || !self.replicas.contains_key(&b) {
+            return true;
+        }
+        self.groups.get(&a) == self.groups.get(&b)
+    }
+
+    fn deliver(&mut self, msg: Msg) {
+        let (src, dst) = (msg.base.src, msg.base.dst);
+        if !self.connected(src, dst) {
+            self.trace.push(format!("{} cut {} -> {} {}", self.now.get(), src, dst, msg.msg.name()));
+            return;
+        }
+
+        self.trace.push(format!("{} deliver {} -> {} {}", self.now.get(), src, dst, msg.msg.name()));
+        match self.replicas.get_mut(&dst) {
+            Some(replica) => match replica.node {
+                Some(ref mut node) => node.receive(msg),
+                None               => return,
+            },
+            None => {
+                self.history.complete(&msg, self.now.get());
+                return self.replies.push(msg);
+            },
+        }
+        self.after(dst);
+    }
+
+    /// Routes what `id` sent while handling an event and checks it stayed safe
+    fn after(&mut self, id: NodeId) {
+        let sent: Vec<Msg> = self.replicas[&id].outbox.borrow_mut().drain(..).collect();
+        for msg in sent {
+            self.route(msg);
+        }
+        self.check(id);
+    }
+
+    fn route(&mut self, msg: Msg) {
+        if self.rng.gen::<f64>() < self.faults.drop_rate {
+            self.trace.push(format!("{} drop {} -> {} {}", self.now.get(), msg.base.src, msg.base.dst, msg.msg.name()));
+            return;
+        }
+        let copies = if self.rng.gen::<f64>() < self.faults.duplicate_rate { 2 } else { 1 };
+        for _ in 0..copies {
+            let delay = self.rng.gen_range(self.faults.min_delay, self.faults.max_delay + 1);
+            self.sent += 1;
+            self.in_flight.insert((self.now.get() + delay, self.sent), msg.clone());
+        }
+    }
+
+    fn check(&mut self, id: NodeId) {
+        let seed = self.seed;
+        let replica = self.replicas.get_mut(&id).unwrap();
+        let node = match replica.node {
+            Some(ref node) => node,
+            None           => return,
+        };
+
+        if node.is_leader() {
+            match self.leaders.entry(node.term()) {
+                MapEntry::Occupied(leader) => assert!(*leader.get() == id,
+                    "seed {}: {} and {} both led term {}", seed, leader.get(), id, node.term()),
+                MapEntry::Vacant(slot) => drop(slot.insert(id)),
+            }
+        }
+
+        for idx in replica.checked_idx + 1..node.commit_idx() + 1 {
+            let entry = match node.entry_at(idx) {
+                Some(entry) => entry,
+                None        => continue,
+            };
+            match self.committed.entry(idx) {
+                MapEntry::Occupied(committed) => assert!(committed.get() == entry,
+                    "seed {}: {} committed {:?} at {} over {:?}", seed, id, entry, idx, committed.get()),
+                MapEntry::Vacant(slot) => drop(slot.insert(entry.clone())),
+            }
+        }
+        replica.checked_idx = cmp::max(replica.checked_idx, node.commit_idx());
+    }
+}
+
+impl Drop for Simulation {
+    fn drop(&mut self) {
+        for replica in self.replicas.values() {
+            drop(fs::remove_dir_all(&replica.dir));
+        }
+    }
+}
+
+struct HostReplica {
+    host: Option<Host>,
+    outbox: Rc<RefCell<Vec<Msg>>>,
+    dir: PathBuf,
+}
+
+/// Like `Simulation`, but each replica is a whole `Host`, with every group it
+/// has split into or taken over. Only election safety is checked, per group.
+pub struct HostSimulation {
+    seed: u64,
+    now: Rc<Cell<u64>>,
+    rng: XorShiftRng,
+    faults: Faults,
+    hosts: BTreeMap<NodeId, HostReplica>,
+    in_flight: BTreeMap<(u64, u64), Msg>,
+    sent: u64,
+    replies: Vec<Msg>,
+    leaders: HashMap<(String, u64), NodeId>,
+}
+
+impl HostSimulation {
+    /// A fresh cluster of `size` hosts named "0000", "0001", ... whose
+    /// storage lives in temporary directories prefixed with `name`
+    pub fn new(name: &str, seed: u64, size: usize, faults: Faults) -> HostSimulation {
+        let mut sim = HostSimulation {
+            seed: seed,
+            now: Rc::new(Cell::new(0)),
+            rng: XorShiftRng::from_seed([seed as u32, (seed >> 32) as u32, 0x85eb_ca6b, 0xc2b2_ae35]),
+            faults: faults,
+            hosts: BTreeMap::new(),
+            in_flight: BTreeMap::new(),
+            sent: 0,
+            replies: vec![],
+            leaders: HashMap::new(),
+        };
+        let ids: Vec<String> = (0..size).map(|i| format!("{:04}", i)).collect();
+        for id in &ids {
+            let dir = env::temp_dir().join(format!("raft-hostsim-{}-{}", name, id));
+            drop(fs::remove_dir_all(&dir));
+            sim.hosts.insert(NodeId::from(&id[..]), HostReplica {
+                host: None,
+                outbox: Rc::new(RefCell::new(vec![])),
+                dir: dir,
+            });
+        }
+        for id in ids {
+            sim.restart(NodeId::from(id));
+        }
+        sim
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn host(&self, id: NodeId) -> Option<&Host> {
+        self.hosts.get(&id).and_then(|replica| replica.host.as_ref())
+    }
+
+    /// The running host leading `group` with the highest term, if any
+    pub fn leader(&self, group: &str) -> Option<NodeId> {
+        self.hosts.iter()
+            .filter_map(|(id, replica)| replica.host.as_ref().map(|host| (id, host)))
+            .filter_map(|(id, host)| host.groups().get(group).map(|node| (id, node)))
+            .filter(|&(_, node)| node.is_leader())
+            .max_by_key(|&(_, node)| node.term())
+            .map(|(id, _)| *id)
+    }
+
+    /// Stops a host, losing everything its groups have not persisted
+    pub fn crash(&mut self, id: NodeId) {
+        let replica = self.hosts.get_mut(&id).expect("no such host");
+        replica.host = None;
+        replica.outbox.borrow_mut().clear();
+    }
+
+    /// Starts a host from whatever its storage holds
+    pub fn restart(&mut self, id: NodeId) {
+        let peers = self.hosts.keys().cloned().filter(|peer| *peer != id).collect();
+        let mut rng = XorShiftRng::from_seed([self.rng.next_u32() | 1, self.rng.next_u32(), self.rng.next_u32(),
+                                              self.rng.next_u32()]);
+        let now = self.now.clone();
+        let replica = self.hosts.get_mut(&id).expect("no such host");
+        replica.outbox.borrow_mut().clear();
+        let mut settings = Settings::new(id, peers);
+        settings.storage_dir = replica.dir.clone();
+        settings.trace_sinks = vec![];
+        let transport = Rc::new(SimTransport { outbox: replica.outbox.clone() });
+        replica.host = Some(Host::with_clocks(
+            settings,
+            transport,
+            Box::new(move || Box::new(SimClock { now: now.clone() }) as Box<Clock>),
+            Box::new(move || {
+                let seed = [rng.next_u32() | 1, rng.next_u32(), rng.next_u32(), rng.next_u32()];
+                Box::new(XorShiftRng::from_seed(seed)) as Box<Rng>
+            })));
+        self.after(id);
+    }
+
+    /// Sends `msg` to host `dst` on behalf of `client`. Replies addressed to
+    /// clients collect in `take_replies`.
+    pub fn request(&mut self, client: NodeId, dst: NodeId, mid: &str, msg: MsgType) {
+        let base = BaseMsg::new(client, dst, NodeId::broadcast(), mid.to_owned());
+        self.route(Msg::new(base, msg));
+    }
+
+    pub fn take_replies(&mut self) -> Vec<Msg> {
+        self.replies.drain(..).collect()
+    }
+
+    /// Runs every delivery and timeout due in the next `ms` milliseconds
+    pub fn run_for(&mut self, ms: u64) {
+        let until = self.now.get() + ms;
+        loop {
+            let delivery = self.in_flight.keys().next().cloned();
+            let timeout = self.hosts.iter()
+                .filter_map(|(id, replica)| replica.host.as_ref().map(|host| (host.deadline(), *id)))
+                .min_by_key(|&(at, _)| at);
+            let at = match (delivery, timeout) {
+                (Some(key), Some((at, _))) => cmp::min(key.0, at),
+                (Some(key), None)          => key.0,
+                (None, Some((at, _)))      => at,
+                (None, None)               => break,
+            };
+            if at > until {
+                break;
+            }
+            self.now.set(cmp::max(self.now.get(), at));
+            match (delivery, timeout) {
+                (Some(key), timeout) if timeout.map_or(true, |(at, _)| key.0 <= at) => {
+                    let msg = self.in_flight.remove(&key).unwrap();
+                    self.deliver(msg);
+                },
+                (_, Some((_, id))) => {
+                    self.hosts.get_mut(&id).unwrap().host.as_mut().unwrap().tick();
+                    self.after(id);
+                },
+                _ => unreachable!(),
+            }
+        }
+        self.now.set(until);
+    }
+
+    fn deliver(&mut self, msg: Msg) {
+        let dst = msg.base.dst;
+        match self.hosts.get_mut(&dst) {
+            Some(replica) => match replica.host {
+                Some(ref mut host) => host.receive(msg),
+                None               => return,
+            },
+            None => return self.replies.push(msg),
+        }
+        self.after(dst);
+    }
+
+    /// Routes what `id` sent while handling an event and checks no group
+    /// has two leaders in a term
+    fn after(&mut self, id: NodeId) {
+        let sent: Vec<Msg> = self.hosts[&id].outbox.borrow_mut().drain(..).collect();
+        for msg in sent {
+            self.route(msg);
+        }
+        let seed = self.seed;
+        let host = match self.hosts[&id].host {
+            Some(ref host) => host,
+            None           => return,
+        };
+        for (group, node) in host.groups().iter().filter(|&(_, node)| node.is_leader()) {
+            match self.leaders.entry((group.clone(), node.term())) {
+                MapEntry::Occupied(leader) => assert!(*leader.get() == id,
+                    "seed {}: {} and {} both led group {} in term {}", seed, leader.get(), id, group, node.term()),
+                MapEntry::Vacant(slot) => drop(slot.insert(id)),
+            }
+        }
+    }
+
+    fn route(&mut self, msg: Msg) {
+        if self.rng.gen::<f64>() < self.faults.drop_rate {
+            return;
+        }
+        let copies = if self.rng.gen::<f64>() < self.faults.duplicate_rate { 2 } else { 1 };
+        for _ in 0..copies {
+            let delay = self.rng.gen_range(self.faults.min_delay, self.faults.max_delay + 1);
+            self.sent += 1;
+            self.in_flight.insert((self.now.get() + delay, self.sent), msg.clone());
+        }
+    }
+}
+
+impl Drop for HostSimulation {
+    fn drop(&mut self) {
+        for replica in self.hosts.values() {
+            drop(fs::remove_dir_all(&replica.dir));
+        }
+    }
+}
+
+/// Keeps offering a put to whoever leads until one acknowledges it
+#[allow(dead_code)]
+fn put(sim: &mut Simulation, mid: &str, key: &str, value: &str) {
+    let client = NodeId::from("CCCC");
+    for _ in 0..50 {
+        let dst = sim.leader().unwrap_or(NodeId::from("0000"));
+        sim.request(client, dst, mid, MsgType::Put(key.to_owned(), value.to_owned()));
+        sim.run_for(300);
+        let acked = sim.take_replies().iter()
+            .any(|reply| reply.base.mid == mid && reply.msg == MsgType::OK(value.to_owned()));
+        if acked {
+            return;
+        }
+    }
+    panic!("seed {}: put {} was never acknowledged", sim.seed(), mid);
+}
+
+/// Hands leadership from `leader` to `target` and waits for it to take over
+#[allow(dead_code)]
+fn transfer(sim: &mut Simulation, leader: NodeId, target: NodeId) {
+    sim.request(NodeId::from("A000"), leader, "transfer", MsgType::TransferLeader(target));
+    sim.run_for(300);
+    sim.take_replies();
+    assert_eq!(sim.leader(), Some(target), "seed {}: leadership never moved", sim.seed());
+}
+
+/// Runs until `follower` has committed everything `leader` has, and returns
+/// how many appends that took
+#[allow(dead_code)]
+fn appends_until_caught_up(sim: &mut Simulation, leader: NodeId, follower: NodeId) -> usize {
+    let start = sim.trace().len();
+    for _ in 0..500 {
+        sim.run_for(10);
+        let commit_idx = sim.node(leader).unwrap().commit_idx();
+        if sim.node(follower).unwrap().commit_idx() == commit_idx {
+            let sent = format!("deliver {} -> {} append_entries", leader, follower);
+            return sim.trace()[start..].iter().filter(|line| line.ends_with(&sent)).count();
+        }
+    }
+    panic!("seed {}: {} never caught up with {}", sim.seed(), follower, leader);
+}
+
+/// Hands `msg` straight to its destination, past any partition, as if the
+/// network had been holding on to it all along
+#[allow(dead_code)]
+fn deliver_late(sim: &mut Simulation, msg: Msg) {
+    let dst = msg.base.dst;
+    sim.replicas.get_mut(&dst).unwrap().node.as_mut().expect("destination is down").receive(msg);
+    sim.after(dst);
+}
+
+/// Offers a request to each host in turn until one gives an answer other
+/// than a redirect, and returns that answer
+#[allow(dead_code)]
+fn ask_hosts(sim: &mut HostSimulation, mid: &str, msg: MsgType) -> Msg {
+    let ids: Vec<NodeId> = sim.hosts.keys().cloned().collect();
+    for attempt in 0..50 {
+        sim.request(NodeId::from("CCCC"), ids[attempt % ids.len()], mid, msg.clone());
+        sim.run_for(300);
+        let answer = sim.take_replies().into_iter()
+            .find(|reply| reply.base.mid == mid && reply.msg != MsgType::Redirect);
+        if let Some(answer) = answer {
+            return answer;
+        }
+    }
+    panic!("seed {}: {} was never answered", sim.seed(), mid);
+}
+
+/// Splits a fresh cluster's keys at "g", with "a" below and "m" above
+#[allow(dead_code)]
+fn split_hosts(name: &str, seed: u64) -> HostSimulation {
+    let mut sim = HostSimulation::new(name, seed, 3, Faults::reliable());
+    sim.run_for(1000);
+    for &(mid, key, value) in &[("m0", "a", "1"), ("m1", "m", "2")] {
+        let answer = ask_hosts(&mut sim, mid, MsgType::Put(key.to_owned(), value.to_owned()));
+        assert_eq!(answer.msg, MsgType::OK(value.to_owned()), "seed {}", seed);
+    }
+    let answer = ask_hosts(&mut sim, "s0", MsgType::Split("g".to_owned()));
+    assert_eq!(answer.msg, MsgType::OK("0.0".to_owned()), "seed {}", seed);
+    sim
+}
+
+#[test]
+fn test_elects_a_leader_despite_faults() {
+    for seed in 0..20 {
+        let mut sim = Simulation::new("elect", seed, 5, Faults::lossy());
+        sim.run_for(3000);
+        assert!(sim.leader().is_some(), "seed {}: no leader after 3s", seed);
+    }
+}
+
+#[test]
+fn test_seeds_replay_exactly() {
+    let run = |name| {
+        let mut sim = Simulation::new(name, 7, 3, Faults::lossy());
+        sim.run_for(1000);
+        put(&mut sim, "m1", "x", "1");
+        sim.partition(&[&[NodeId::from("0000")]]);
+        sim.run_for(1000);
+        sim.heal();
+        put(&mut sim, "m2", "y", "2");
+        sim.trace().to_vec()
+    };
+    assert_eq!(run("replay-a"), run("replay-b"));
+}
+
+#[test]
+fn test_writes_survive_partitions_and_crashes() {
+    for seed in 0..5 {
+        let mut sim = Simulation::new("survive", seed, 5, Faults::lossy());
+        sim.run_for(1000);
+        put(&mut sim, "m1", "x", "1");
+
+        let old_leader = sim.leader().unwrap();
+        sim.partition(&[&[old_leader]]);
+        sim.run_for(1000);
+        put(&mut sim, "m2", "y", "2");
+        sim.heal();
+        sim.crash(old_leader);
+        put(&mut sim, "m3", "z", "3");
+        sim.restart(old_leader);
+        sim.run_for(2000);
+
+        let leader = sim.leader().unwrap();
+        let commit_idx = sim.node(leader).unwrap().commit_idx();
+        assert!(sim.node(old_leader).unwrap().commit_idx() == commit_idx,
+                "seed {}: restarted replica did not catch up", seed);
+    }
+}
+
+#[test]
+fn test_leadership_transfers_to_a_lagging_follower() {
+    for seed in 0..5 {
+        let mut sim = Simulation::new("transfer", seed, 5, Faults::reliable());
+        sim.run_for(1000);
+        let old_leader = sim.leader().unwrap();
+        let target = if old_leader == NodeId::from("0000") { NodeId::from("0001") } else { NodeId::from("0000") };
+        sim.partition(&[&[target]]);
+        for i in 0..50 {
+            put(&mut sim, &format!("m{}", i), "x", &i.to_string());
+        }
+        sim.heal();
+
+        let admin = NodeId::from("A000");
+        sim.request(admin, old_leader, "t1", MsgType::TransferLeader(target));
+        sim.run_for(300);
+        let replies = sim.take_replies();
+        assert!(replies.iter().any(|reply| reply.base.mid == "t1" && reply.msg == MsgType::OK(target.as_str().to_owned())),
+                "seed {}: transfer was not acknowledged", seed);
+        assert_eq!(sim.leader(), Some(target), "seed {}", seed);
+        put(&mut sim, "after", "y", "1");
+    }
+}
+
+#[test]
+fn test_divergent_log_is_repaired_a_term_at_a_time() {
+    let client = NodeId::from("CCCC");
+    for seed in 0..5 {
+        let mut sim = Simulation::new("diverge", seed, 5, Faults::reliable());
+        sim.run_for(1000);
+        put(&mut sim, "m0", "x", "0");
+
+        // The old leader keeps taking writes it can never commit while the
+        // rest of the cluster moves on without it
+        let old_leader = sim.leader().unwrap();
+        sim.partition(&[&[old_leader]]);
+        for i in 0..200 {
+            sim.request(client, old_leader, &format!("lost{}", i), MsgType::Put("x".to_owned(), "lost".to_owned()));
+        }
+        put(&mut sim, "m1", "y", "first");
+        let leader = sim.leader().unwrap();
+        for i in 0..200 {
+            sim.request(client, leader, &format!("m{}", i + 2), MsgType::Put("y".to_owned(), i.to_string()));
+        }
+        sim.run_for(1000);
+
+        // A fresh leader assumes everyone's log ends where its own does
+        let successor = sim.replicas.keys().cloned().find(|id| *id != leader && *id != old_leader).unwrap();
+        transfer(&mut sim, leader, successor);
+        sim.heal();
+        let appends = appends_until_caught_up(&mut sim, successor, old_leader);
+        assert!(appends < 30, "seed {}: took {} appends to repair 200 divergent entries", seed, appends);
+    }
+}
+
+#[test]
+fn test_lagging_follower_skips_to_its_last_entry() {
+    let client = NodeId::from("CCCC");
+    for seed in 0..5 {
+        let mut sim = Simulation::new("lagging", seed, 3, Faults::reliable());
+        sim.run_for(1000);
+        let leader = sim.leader().unwrap();
+        let mut others = sim.replicas.keys().cloned().filter(|id| *id != leader);
+        let (follower, successor) = (others.next().unwrap(), others.next().unwrap());
+        sim.crash(follower);
+        for i in 0..300 {
+            sim.request(client, leader, &format!("m{}", i), MsgType::Put("x".to_owned(), i.to_string()));
+        }
+        sim.run_for(1000);
+
+        transfer(&mut sim, leader, successor);
+        sim.restart(follower);
+        let appends = appends_until_caught_up(&mut sim, successor, follower);
+        assert!(appends < 30, "seed {}: took {} appends to send 300 missing entries", seed, appends);
+    }
+}
+
+#[test]
+fn test_concurrent_writes_are_batched() {
+    let client = NodeId::from("CCCC");
+    for seed in 0..3 {
+        let mut sim = Simulation::new("batched", seed, 3, Faults::reliable());
+        sim.run_for(1000);
+        let leader = sim.leader().unwrap();
+        let start = sim.trace().len();
+        for i in 0..500 {
+            sim.request(client, leader, &format!("m{}", i), MsgType::Put(format!("k{}", i % 10), i.to_string()));
+        }
+        sim.run_for(500);
+
+        let acked = sim.take_replies().iter().filter(|reply| reply.msg != MsgType::Fail).count();
+        assert_eq!(acked, 500, "seed {}", seed);
+        let appends = sim.trace()[start..].iter().filter(|line| line.ends_with("append_entries")).count();
+        assert!(appends < 100, "seed {}: {} appends for 500 writes to 2 followers", seed, appends);
+    }
+}
+
+#[test]
+fn test_learner_catches_up_without_voting_until_promoted() {
+    use super::status::Role;
+
+    let mut sim = Simulation::new("learner", 3, 3, Faults::reliable());
+    sim.run_for(1000);
+    for i in 0..20 {
+        put(&mut sim, &format!("m{}", i), &format!("k{}", i % 4), &i.to_string());
+    }
+    let leader = sim.leader().expect("no leader");
+    let learner = NodeId::from("learner-1");
+    sim.add_learner(learner);
+    sim.request(NodeId::from("A000"), leader, "add", MsgType::AddLearner(learner));
+    sim.run_for(500);
+    assert!(sim.take_replies().iter().any(|reply| reply.base.mid == "add" && reply.msg == MsgType::OK("learner-1".to_owned())));
+    assert_eq!(sim.node(learner).unwrap().commit_idx(), sim.node(leader).unwrap().commit_idx());
+
+    // The leader and the learner are half the nodes but only a third of the
+    // voters, so nothing commits and the learner never campaigns
+    let voters: Vec<NodeId> = ["0000", "0001", "0002"].iter().map(|id| NodeId::from(*id)).filter(|id| *id != leader).collect();
+    let committed = sim.node(leader).unwrap().commit_idx();
+    sim.partition(&[&[leader, learner], &voters]);
+    sim.request(NodeId::from("CCCC"), leader, "stuck", MsgType::Put("k".to_owned(), "v".to_owned()));
+    sim.run_for(2000);
+    assert_eq!(sim.node(learner).unwrap().commit_idx(), committed);
+    assert!(sim.node(learner).unwrap().term() <= sim.node(leader).unwrap().term());
+    assert!(!sim.node(learner).unwrap().is_leader());
+
+    sim.heal();
+    put(&mut sim, "after", "k", "w");
+    let leader = sim.leader().expect("no leader");
+    sim.request(NodeId::from("A000"), leader, "promote", MsgType::PromoteLearner(learner));
+    sim.run_for(1000);
+    assert!(sim.take_replies().iter().any(|reply| reply.base.mid == "promote" && reply.msg == MsgType::OK("learner-1".to_owned())));
+    assert_eq!(sim.node(learner).unwrap().status().role, Role::Follower);
+    transfer(&mut sim, leader, learner);
+}
+
+#[test]
+fn test_watch_resumes_on_a_new_leader() {
+    let mut sim = Simulation::new("watch", 5, 3, Faults::reliable());
+    sim.run_for(1000);
+    let leader = sim.leader().expect("no leader");
+    let watcher = NodeId::from("W000");
+    let events = |replies: Vec<Msg>| -> Vec<(u64, String, Option<String>)> {
+        replies.into_iter()
+            .filter_map(|reply| match reply.msg {
+                MsgType::WatchEvent { index, key, value } => Some((index, key, value)),
+                _ => None,
+            })
+            .collect()
+    };
+
+    sim.request(watcher, leader, "w1", MsgType::Watch { key: "svc/".to_owned(), prefix: true, from_index: 0 });
+    sim.run_for(100);
+    let writes = [MsgType::Put("svc/a".to_owned(), "1".to_owned()),
+                  MsgType::Put("other".to_owned(), "x".to_owned()),
+                  MsgType::Put("svc/b".to_owned(), "2".to_owned()),
+                  MsgType::Delete("svc/a".to_owned())];
+    for (i, write) in writes.iter().enumerate() {
+        sim.request(NodeId::from("CCCC"), leader, &format!("m{}", i), write.clone());
+        sim.run_for(300);
+    }
+    let seen = events(sim.take_replies());
+    let changes: Vec<(&str, Option<&str>)> = seen.iter()
+        .map(|&(_, ref key, ref value)| (&key[..], value.as_ref().map(|v| &v[..])))
+        .collect();
+    assert_eq!(changes, vec![("svc/a", Some("1")), ("svc/b", Some("2")), ("svc/a", None)]);
+    assert!(seen.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+    // Nothing reaches the watcher while it has no leader to watch
+    sim.crash(leader);
+    put(&mut sim, "m4", "svc/c", "3");
+    let new_leader = sim.leader().expect("no new leader");
+    let resume_from = seen.last().unwrap().0 + 1;
+    sim.request(watcher, new_leader, "w2", MsgType::Watch { key: "svc/".to_owned(), prefix: true, from_index: resume_from });
+    sim.run_for(100);
+    let replies = sim.take_replies();
+    assert!(replies.iter().any(|reply| reply.base.mid == "w2" && reply.msg == MsgType::OK(resume_from.to_string())));
+    let resumed = events(replies);
+    assert_eq!(resumed.len(), 1, "seed {}: resumed with {:?}", sim.seed(), resumed);
+    assert_eq!((&resumed[0].1[..], resumed[0].2.as_ref().map(|v| &v[..])), ("svc/c", Some("3")));
+}
+
+#[test]
+fn test_followers_forward_requests_to_the_leader() {
+    use super::msg::Command;
+
+    fn forwarding(settings: &mut Settings) {
+        settings.forward_requests = true;
+        settings.forward_timeout = 600;
+    }
+    let mut sim = Simulation::with_settings("forward", 7, 3, Faults::reliable(), forwarding);
+    sim.run_for(1000);
+    let leader = sim.leader().expect("no leader");
+    let follower = ["0000", "0001", "0002"].iter().map(|id| NodeId::from(*id)).find(|id| *id != leader).unwrap();
+    let client = NodeId::from("CCCC");
+
+    sim.request(client, follower, "p1", MsgType::Put("k".to_owned(), "v1".to_owned()));
+    sim.run_for(200);
+    sim.request(client, follower, "g1", MsgType::Get("k".to_owned()));
+    // A retry through another replica is still applied only once
+    sim.request(client, leader, "p1", MsgType::Put("k".to_owned(), "v1".to_owned()));
+    sim.run_for(200);
+    let replies = sim.take_replies();
+    assert!(replies.iter().all(|reply| reply.msg != MsgType::Redirect));
+    assert_eq!(replies.iter().filter(|reply| reply.base.mid == "p1" && reply.msg == MsgType::OK("v1".to_owned())).count(), 2);
+    assert!(replies.iter().any(|reply| reply.base.mid == "g1" && reply.msg == MsgType::OK("v1".to_owned())
+                                       && reply.base.src == follower));
+    let puts = (1..sim.node(leader).unwrap().commit_idx() + 1)
+        .filter_map(|idx| sim.node(leader).unwrap().entry_at(idx))
+        .filter(|entry| entry.command == Command::Put("k".to_owned(), "v1".to_owned()))
+        .count();
+    assert_eq!(puts, 1);
+
+    // Cut off from any leader, the follower gives up at the deadline
+    let others: Vec<NodeId> = ["0000", "0001", "0002"].iter().map(|id| NodeId::from(*id)).filter(|id| *id != follower).collect();
+    sim.partition(&[&[follower], &others]);
+    sim.run_for(1000);
+    sim.take_replies();
+    sim.request(client, follower, "p2", MsgType::Put("k".to_owned(), "v2".to_owned()));
+    sim.run_for(500);
+    assert!(sim.take_replies().is_empty());
+    sim.run_for(200);
+    assert_eq!(sim.take_replies().iter().map(|reply| (&reply.base.mid[..], reply.msg.clone())).collect::<Vec<_>>(),
+               vec![("p2", MsgType::Fail)]);
+}
+
+#[test]
+fn test_leader_in_a_minority_steps_down() {
+    let mut sim = Simulation::new("check-quorum", 11, 5, Faults::reliable());
+    sim.run_for(1000);
+    put(&mut sim, "m0", "k", "v0");
+    let leader = sim.leader().expect("no leader");
+    let ids: Vec<NodeId> = (0..5).map(|i| NodeId::from(format!("{:04}", i))).collect();
+    let others: Vec<NodeId> = ids.iter().cloned().filter(|id| *id != leader).collect();
+    let minority = [leader, others[0]];
+    sim.partition(&[&minority, &others[1..]]);
+
+    let client = NodeId::from("CCCC");
+    sim.request(client, leader, "m1", MsgType::Put("k".to_owned(), "v1".to_owned()));
+    sim.request(client, leader, "g1", MsgType::Get("k".to_owned()));
+    sim.run_for(500);
+    // A failed get would claim the key is absent, so the read is redirected
+    let mut answers: Vec<(String, MsgType)> = sim.take_replies().into_iter()
+        .map(|reply| (reply.base.mid, reply.msg))
+        .collect();
+    answers.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(answers, vec![("g1".to_owned(), MsgType::Redirect), ("m1".to_owned(), MsgType::Fail)],
+               "seed {}", sim.seed());
+    assert!(!sim.node(leader).unwrap().is_leader());
+
+    // The majority carries on, and the old leader rejoins it once healed
+    put(&mut sim, "m2", "k", "v2");
+    assert!(!minority.contains(&sim.leader().unwrap()));
+    sim.heal();
+    sim.run_for(1000);
+    assert_eq!(sim.node(leader).unwrap().commit_idx(), sim.node(sim.leader().unwrap()).unwrap().commit_idx());
+}
+
+#[test]
+fn test_client_histories_are_linearizable() {
+    let clients: Vec<NodeId> = ["C000", "C001", "C002"].iter().map(|id| NodeId::from(*id)).collect();
+    for seed in 0..5 {
+        let mut sim = Simulation::new("linearizable", seed, 3, Faults::lossy());
+        sim.run_for(1000);
+        for round in 0..30 {
+            if round == 10 {
+                let leader = sim.leader().unwrap_or(NodeId::from("0000"));
+                sim.partition(&[&[leader]]);
+            } else if round == 20 {
+                sim.heal();
+            }
+            for (i, client) in clients.iter().enumerate() {
+                let key = if (round + i) % 2 == 0 { "x" } else { "y" };
+                let op = if (round * 3 + i) % 2 == 0 {
+                    MsgType::Get(key.to_owned())
+                } else {
+                    MsgType::Put(key.to_owned(), format!("{}-{}", client.as_str(), round))
+                };
+                let dst = sim.leader().unwrap_or(NodeId::from("0000"));
+                sim.request(*client, dst, &format!("r{}", round), op);
+            }
+            sim.run_for(200);
+        }
+        sim.run_for(1000);
+        if let Err(violation) = super::history::check(sim.history()) {
+            panic!("seed {}: {}", seed, violation);
+        }
+    }
+}
+
+#[test]
+fn test_deposed_leader_does_not_serve_stale_reads() {
+    for seed in 0..3 {
+        let mut sim = Simulation::new("stale-read", seed, 5, Faults::reliable());
+        sim.run_for(1000);
+        put(&mut sim, "m0", "k", "v0");
+        let old_leader = sim.leader().expect("no leader");
+
+        // Still leader as far as it knows, but it can't confirm that with
+        // anyone before answering
+        sim.partition(&[&[old_leader]]);
+        sim.request(NodeId::from("CCCC"), old_leader, "g1", MsgType::Get("k".to_owned()));
+        sim.run_for(100);
+        assert!(sim.take_replies().is_empty(), "seed {}: read answered without a quorum", seed);
+        put(&mut sim, "m1", "k", "v1");
+        sim.run_for(1000);
+        let answers: Vec<MsgType> = sim.take_replies().into_iter()
+            .filter(|reply| reply.base.mid == "g1")
+            .map(|reply| reply.msg)
+            .collect();
+        assert!(answers.iter().all(|answer| *answer == MsgType::Redirect), "seed {}: {:?}", seed, answers);
+    }
+}
+
+#[test]
+fn test_new_leader_reads_wait_for_its_first_commit() {
+    for seed in 0..3 {
+        let mut sim = Simulation::new("noop-read", seed, 3, Faults::reliable());
+        sim.run_for(1000);
+        put(&mut sim, "m0", "k", "v0");
+        // Crashing the leader as soon as it acknowledges a write leaves the
+        // others holding the write without knowing it is committed
+        let old_leader = sim.leader().expect("no leader");
+        sim.request(NodeId::from("CCCC"), old_leader, "m1", MsgType::Put("k".to_owned(), "v1".to_owned()));
+        while sim.take_replies().is_empty() {
+            sim.run_for(1);
+        }
+        sim.crash(old_leader);
+        let mut new_leader = None;
+        for _ in 0..2000 {
+            sim.run_for(1);
+            new_leader = sim.leader();
+            if new_leader.is_some() {
+                break;
+            }
+        }
+        let new_leader = new_leader.expect("no new leader");
+
+        // Its no-op can't commit alone, and until it does the leader doesn't
+        // know how much of its log is committed
+        sim.partition(&[&[new_leader]]);
+        sim.request(NodeId::from("CCCC"), new_leader, "g1", MsgType::Get("k".to_owned()));
+        sim.run_for(100);
+        assert!(sim.take_replies().is_empty(), "seed {}: read answered before the no-op committed", seed);
+        let node = sim.node(new_leader).unwrap();
+        assert!(node.entry_at(node.commit_idx()).map_or(true, |entry| entry.term < node.term()));
+
+        sim.heal();
+        sim.run_for(100);
+        let answers: Vec<MsgType> = sim.take_replies().into_iter().map(|reply| reply.msg).collect();
+        assert_eq!(answers, vec![MsgType::OK("v1".to_owned())], "seed {}", seed);
+        let node = sim.node(new_leader).unwrap();
+        assert_eq!(node.entry_at(node.commit_idx()).map(|entry| entry.term), Some(node.term()));
+    }
+}
+
+#[test]
+fn test_rejoining_follower_leaves_the_leader_alone() {
+    for seed in 0..3 {
+        let mut sim = Simulation::new("rejoin", seed, 5, Faults::reliable());
+        sim.run_for(1000);
+        put(&mut sim, "m0", "k", "v0");
+        let leader = sim.leader().expect("no leader");
+        let term = sim.node(leader).unwrap().term();
+        let follower = sim.replicas.keys().cloned().find(|id| *id != leader).unwrap();
+
+        // Alone, the follower times out again and again, but without a
+        // quorum for its pre-votes it never raises its term
+        sim.partition(&[&[follower]]);
+        sim.run_for(3000);
+        assert_eq!(sim.node(follower).unwrap().term(), term, "seed {}", seed);
+        sim.heal();
+        sim.run_for(1000);
+        assert_eq!(sim.leader(), Some(leader), "seed {}", seed);
+        assert_eq!(sim.node(leader).unwrap().term(), term, "seed {}", seed);
+        put(&mut sim, "m1", "k", "v1");
+    }
+}
+
+#[test]
+fn test_follower_rejects_a_snapshot_it_cannot_restore() {
+    use rustc_serialize::json::Json;
+    use super::msg::Snapshot;
+
+    let mut sim = Simulation::new("bad-snapshot", 2, 3, Faults::reliable());
+    sim.run_for(1000);
+    put(&mut sim, "m0", "k", "v0");
+    let leader = sim.leader().expect("no leader");
+    let follower = sim.replicas.keys().cloned().find(|id| *id != leader).unwrap();
+    let (term, commit_idx) = (sim.node(leader).unwrap().term(), sim.node(follower).unwrap().commit_idx());
+
+    let snapshot = Snapshot::new(commit_idx + 100, term, Json::String("not a store".to_owned()));
+    sim.request(NodeId::from("X000"), follower, "is", MsgType::InstallSnapshot { term: term, snapshot: snapshot });
+    sim.run_for(20);
+    let replies: Vec<MsgType> = sim.take_replies().into_iter().map(|reply| reply.msg).collect();
+    assert_eq!(replies, vec![MsgType::ISResp { term: term, match_index: 0 }]);
+    assert_eq!(sim.node(follower).unwrap().commit_idx(), commit_idx);
+    assert!(sim.node(follower).unwrap().entry_at(commit_idx).is_some(), "the log was left alone");
+
+    put(&mut sim, "m1", "k", "v1");
+    sim.run_for(200);
+    assert_eq!(sim.node(follower).unwrap().commit_idx(), sim.node(leader).unwrap().commit_idx());
+}
+
+#[test]
+fn test_replies_meant_for_clients_are_dropped() {
+    let mut sim = Simulation::new("client-only", 4, 3, Faults::reliable());
+    sim.run_for(1000);
+    let leader = sim.leader().expect("no leader");
+    let status = sim.node(leader).unwrap().status();
+    let stray = NodeId::from("X000");
+    for (i, msg) in vec![MsgType::StatusReport(status),
+                         MsgType::WatchEvent { index: 1, key: "k".to_owned(), value: None },
+                         MsgType::Compacted(1)].into_iter().enumerate() {
+        sim.request(stray, leader, &format!("x{}", i), msg);
+    }
+    sim.run_for(100);
+    assert!(sim.take_replies().is_empty());
+    assert_eq!(sim.leader(), Some(leader));
+    put(&mut sim, "m0", "k", "v0");
+}
+
+#[test]
+fn test_leader_refuses_forwards_of_anything_but_client_requests() {
+    use super::msg::InternalMsg;
+
+    let mut sim = Simulation::new("bad-forward", 6, 3, Faults::reliable());
+    sim.run_for(1000);
+    let leader = sim.leader().expect("no leader");
+    let forward = |request: MsgType| MsgType::Forward {
+        client: NodeId::from("CCCC"),
+        client_mid: "c1".to_owned(),
+        request: Box::new(request),
+    };
+    let requests = vec![
+        forward(MsgType::OK("v".to_owned())),
+        forward(MsgType::Redirect),
+        forward(MsgType::AppendEntries { details: InternalMsg::new(99, 0, 0), leader_commit: 0, entries: None }),
+        forward(forward(MsgType::Get("k".to_owned()))),
+    ];
+    for (i, request) in requests.into_iter().enumerate() {
+        sim.request(NodeId::from("X000"), leader, &format!("f{}", i), request);
+    }
+    sim.run_for(100);
+    let answers: Vec<MsgType> = sim.take_replies().into_iter().map(|reply| reply.msg).collect();
+    assert_eq!(answers, vec![MsgType::Fail; 4]);
+    assert_eq!(sim.leader(), Some(leader));
+    put(&mut sim, "m0", "k", "v0");
+}
+
+#[test]
+fn test_old_term_heartbeat_acks_do_not_confirm_reads() {
+    for seed in 0..3 {
+        let mut sim = Simulation::new("stale-ack", seed, 3, Faults::reliable());
+        sim.run_for(1000);
+        put(&mut sim, "m0", "k", "v0");
+        let leader = sim.leader().expect("no leader");
+        let old_term = sim.node(leader).unwrap().term();
+        let peers: Vec<NodeId> = sim.replicas.keys().cloned().filter(|id| *id != leader).collect();
+        // Leading again in a later term starts its heartbeat rounds over
+        transfer(&mut sim, leader, peers[0]);
+        transfer(&mut sim, peers[0], leader);
+        assert!(sim.node(leader).unwrap().term() > old_term);
+
+        sim.partition(&[&[leader]]);
+        sim.request(NodeId::from("CCCC"), leader, "g1", MsgType::Get("k".to_owned()));
+        sim.run_for(5);
+        // Acks from late in its old term, when its rounds had run further
+        // than they have since
+        for &peer in &peers {
+            let ack = MsgType::AEResp { term: old_term, success: true, match_index: 0, commit_idx: 0,
+                                        conflict_term: 0, conflict_index: 0 };
+            deliver_late(&mut sim, Msg::new(BaseMsg::new(peer, leader, leader, "hb1000".to_owned()), ack));
+        }
+        sim.run_for(20);
+        assert!(sim.take_replies().is_empty(), "seed {}: read confirmed by an old term's acks", seed);
+    }
+}
+
+#[test]
+fn test_replies_to_an_old_terms_appends_leave_replication_alone() {
+    for seed in 0..3 {
+        let mut sim = Simulation::new("stale-append", seed, 3, Faults::reliable());
+        sim.run_for(1000);
+        put(&mut sim, "m0", "k", "v0");
+        let leader = sim.leader().expect("no leader");
+        let old_term = sim.node(leader).unwrap().term();
+        let peers: Vec<NodeId> = sim.replicas.keys().cloned().filter(|id| *id != leader).collect();
+        transfer(&mut sim, leader, peers[0]);
+        transfer(&mut sim, peers[0], leader);
+
+        // Only the leader has this write, so nothing may commit it
+        sim.partition(&[&[leader]]);
+        sim.request(NodeId::from("CCCC"), leader, "m1", MsgType::Put("k".to_owned(), "v1".to_owned()));
+        sim.run_for(5);
+        let (last_index, commit_idx) = {
+            let node = sim.node(leader).unwrap();
+            (node.status().last_index, node.commit_idx())
+        };
+        let progress = sim.node(leader).unwrap().status().followers;
+
+        // Replies to appends from its old leadership, reordered behind
+        // everything since: one claiming the whole log, one refusing it all
+        for &(success, conflict_index) in &[(true, 0), (false, 1)] {
+            let reply = MsgType::AEResp { term: old_term, success: success, match_index: last_index, commit_idx: 0,
+                                          conflict_term: 0, conflict_index: conflict_index };
+            deliver_late(&mut sim, Msg::new(BaseMsg::new(peers[0], leader, leader, "ae".to_owned()), reply));
+            assert_eq!(sim.node(leader).unwrap().status().followers, progress, "seed {}", seed);
+        }
+        sim.run_for(20);
+        assert!(sim.take_replies().is_empty(), "seed {}: a write only the leader holds was acknowledged", seed);
+        assert_eq!(sim.node(leader).unwrap().commit_idx(), commit_idx, "seed {}", seed);
+    }
+}
+
+#[test]
+fn test_split_off_group_elects_a_leader_and_serves_the_upper_keys() {
+    for seed in 0..3 {
+        let mut sim = split_hosts("host-split", seed);
+        sim.run_for(1000);
+        assert!(sim.leader("0.0").is_some(), "seed {}: the new group never elected a leader", seed);
+
+        let answer = ask_hosts(&mut sim, "g0", MsgType::Get("m".to_owned()));
+        assert_eq!((answer.base.group, answer.msg), ("0.0".to_owned(), MsgType::OK("2".to_owned())), "seed {}", seed);
+        let answer = ask_hosts(&mut sim, "m2", MsgType::Put("z".to_owned(), "3".to_owned()));
+        assert_eq!((answer.base.group, answer.msg), ("0.0".to_owned(), MsgType::OK("3".to_owned())), "seed {}", seed);
+        let answer = ask_hosts(&mut sim, "g1", MsgType::Get("a".to_owned()));
+        assert_eq!((answer.base.group, answer.msg), ("0".to_owned(), MsgType::OK("1".to_owned())), "seed {}", seed);
+    }
+}
+
+#[test]
+fn test_merge_hands_the_retired_range_to_the_left_group() {
+    for seed in 0..3 {
+        let mut sim = split_hosts("host-merge", seed);
+        sim.run_for(1000);
+        let answer = ask_hosts(&mut sim, "r0", MsgType::Merge("m".to_owned()));
+        assert_eq!(answer.msg, MsgType::OK(String::new()), "seed {}", seed);
+
+        // The first group's leader notices the retired group on its next look
+        sim.run_for(2000);
+        let answer = ask_hosts(&mut sim, "g0", MsgType::Get("m".to_owned()));
+        assert_eq!((answer.base.group, answer.msg), ("0".to_owned(), MsgType::OK("2".to_owned())), "seed {}", seed);
+        sim.run_for(1000);
+        for id in sim.hosts.keys() {
+            let groups: Vec<&String> = sim.host(*id).unwrap().groups().keys().collect();
+            assert_eq!(groups, vec!["0"], "seed {}: {} still has the absorbed group", seed, id);
+        }
+    }
+}
+
+#[test]
+fn test_restarted_host_replays_its_split() {
+    use super::shard::KeyRange;
+    use super::state_machine::StateMachine;
+
+    for seed in 0..3 {
+        let mut sim = split_hosts("host-restart", seed);
+        sim.run_for(1000);
+        let answer = ask_hosts(&mut sim, "m2", MsgType::Put("z".to_owned(), "3".to_owned()));
+        assert_eq!(answer.msg, MsgType::OK("3".to_owned()), "seed {}", seed);
+        let leader = sim.leader("0").expect("no leader");
+        let id = sim.hosts.keys().cloned().find(|id| *id != leader).unwrap();
+        sim.run_for(200);
+
+        sim.crash(id);
+        sim.restart(id);
+        sim.run_for(1000);
+        let host = sim.host(id).unwrap();
+        let ranges: Vec<(&str, Option<&KeyRange>)> = host.groups().iter()
+            .map(|(name, group)| (&name[..], group.state_machine().range()))
+            .collect();
+        assert_eq!(ranges, vec![("0", Some(&KeyRange::new("", Some("g")))), ("0.0", Some(&KeyRange::new("g", None)))],
+                   "seed {}", seed);
+        let child = host.groups()["0.0"].state_machine();
+        assert_eq!((child.query("m"), child.query("z")), (MsgType::OK("2".to_owned()), MsgType::OK("3".to_owned())),
+                   "seed {}", seed);
+        assert_eq!(host.groups()["0"].state_machine().query("m"), MsgType::Redirect, "seed {}", seed);
+    }
+}
+
+#[test]
+fn test_pre_votes_are_refused_while_a_leader_is_heard_and_never_change_terms() {
+    use super::msg::InternalMsg;
+
+    for seed in 0..3 {
+        let mut sim = Simulation::new("pre-vote", seed, 3, Faults::reliable());
+        sim.run_for(1000);
+        put(&mut sim, "m0", "k", "v0");
+        let leader = sim.leader().expect("no leader");
+        let peers: Vec<NodeId> = sim.replicas.keys().cloned().filter(|id| *id != leader).collect();
+        let follower = peers[0];
+        let (term, voted_for, last_index) = {
+            let status = sim.node(follower).unwrap().status();
+            (status.term, status.voted_for, status.last_index)
+        };
+        let pre_vote = MsgType::PreVote { details: InternalMsg::new(term + 1, last_index, term),
+                                          candidate_id: NodeId::from("X000") };
+
+        sim.request(NodeId::from("X000"), follower, "pv0", pre_vote.clone());
+        sim.run_for(25);
+        let answers: Vec<MsgType> = sim.take_replies().into_iter().map(|reply| reply.msg).collect();
+        assert_eq!(answers, vec![MsgType::PVResp(term, false)], "seed {}", seed);
+
+        // Alone, it stops hearing from any leader and would now agree
+        sim.crash(leader);
+        sim.crash(peers[1]);
+        sim.run_for(1000);
+        sim.request(NodeId::from("X000"), follower, "pv1", pre_vote);
+        sim.run_for(25);
+        let answers: Vec<MsgType> = sim.take_replies().into_iter().map(|reply| reply.msg).collect();
+        assert_eq!(answers, vec![MsgType::PVResp(term, true)], "seed {}", seed);
+        let status = sim.node(follower).unwrap().status();
+        assert_eq!((status.term, status.voted_for), (term, voted_for), "seed {}", seed);
+    }
+}
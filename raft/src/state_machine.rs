@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+
+use rustc_serialize::json::{Json, ToJson};
+
+use super::msg::{Command, DecodeError, DecodeResult, MsgType};
+
+/// The replicated service that committed log entries are applied to. The
+/// consensus core handles configuration and no-op entries itself, so `apply`
+/// only ever sees client commands.
+pub trait StateMachine {
+    /// Applies a committed command and returns the reply owed to the client
+    fn apply(&mut self, command: &Command) -> MsgType;
+
+    /// Answers a read of `key` against the current state
+    fn query(&self, key: &str) -> MsgType;
+
+    fn snapshot(&self) -> Json;
+
+    /// Replaces the current state with a `snapshot` taken by any replica.
+    /// A snapshot that doesn't parse leaves the state as it was.
+    fn restore(&mut self, snapshot: &Json) -> DecodeResult<()>;
+
+    /// Removes every key from `at` on and returns them as a snapshot, for
+    /// another group to restore
+    fn split_off(&mut self, at: &str) -> Json;
+
+    /// Adds the keys in a snapshot taken by another group, which holds none
+    /// of ours
+    fn absorb(&mut self, snapshot: &Json) -> DecodeResult<()>;
+}
+
+/// The default string to string store
+pub struct KvStore {
+    map: HashMap<String, String>,
+}
+
+impl KvStore {
+    pub fn new() -> KvStore {
+        KvStore {
+            map: HashMap::new(),
+        }
+    }
+}
+
+impl StateMachine for KvStore {
+    fn apply(&mut self, command: &Command) -> MsgType {
+        match *command {
+            Command::Put(ref key, ref value) => {
+                self.map.insert(key.clone(), value.clone());
+                MsgType::OK(value.clone())
+            },
+            Command::Delete(ref key) => match self.map.remove(key) {
+                Some(old) => MsgType::OK(old),
+                None      => MsgType::NotFound,
+            },
+            Command::Cas {ref key, ref expected, ref new} => {
+                let current = self.map.get(key).cloned();
+                if current == *expected {
+                    self.map.insert(key.clone(), new.clone());
+                    MsgType::OK(new.clone())
+                } else {
+                    MsgType::CasFailed(current)
+                }
+            },
+            Command::Append(ref key, ref value) => {
+                let current = self.map.entry(key.clone()).or_insert(String::new());
+                current.push_str(value);
+                MsgType::OK(current.clone())
+            },
+            Command::Batch(ref puts) => {
+                for &(ref key, ref value) in puts {
+                    self.map.insert(key.clone(), value.clone());
+                }
+                MsgType::OK(puts.len().to_string())
+            },
+            _ => MsgType::Fail,
+        }
+    }
+
+    fn query(&self, key: &str) -> MsgType {
+        match self.map.get(key) {
+            Some(value) => MsgType::OK(value.clone()),
+            None        => MsgType::Fail,
+        }
+    }
+
+    fn snapshot(&self) -> Json {
+        self.map.to_json()
+    }
+
+    fn restore(&mut self, snapshot: &Json) -> DecodeResult<()> {
+        self.map = try!(try!(snapshot.as_object().ok_or(DecodeError::Malformed("snapshot is not an object".to_owned())))
+            .iter()
+            .map(|(key, value)| value.as_string()
+                 .map(|value| (key.clone(), value.to_owned()))
+                 .ok_or(DecodeError::WrongType("snapshot value")))
+            .collect());
+        Ok(())
+    }
+
+    fn split_off(&mut self, at: &str) -> Json {
+        let moved: Vec<String> = self.map.keys().filter(|key| &key[..] >= at).cloned().collect();
+        let mut split = HashMap::new();
+        for key in moved {
+            let value = self.map.remove(&key).unwrap();
+            split.insert(key, value);
+        }
+        split.to_json()
+    }
+
+    fn absorb(&mut self, snapshot: &Json) -> DecodeResult<()> {
+        let mut other = KvStore::new();
+        try!(other.restore(snapshot));
+        self.map.extend(other.map);
+        Ok(())
+    }
+}
+
+/// Named integer counters: a put adds its value to the counter at its key
+pub struct Counter {
+    counts: HashMap<String, i64>,
+}
+
+impl Counter {
+    pub fn new() -> Counter {
+        Counter {
+            counts: HashMap::new(),
+        }
+    }
+}
+
+impl StateMachine for Counter {
+    fn apply(&mut self, command: &Command) -> MsgType {
+        match *command {
+            Command::Put(ref key, ref delta) => match delta.parse::<i64>() {
+                Ok(delta) => {
+                    let count = self.counts.entry(key.clone()).or_insert(0);
+                    *count += delta;
+                    MsgType::OK(count.to_string())
+                },
+                Err(_) => MsgType::Fail,
+            },
+            _ => MsgType::Fail,
+        }
+    }
+
+    fn query(&self, key: &str) -> MsgType {
+        MsgType::OK(self.counts.get(key).cloned().unwrap_or(0).to_string())
+    }
+
+    fn snapshot(&self) -> Json {
+        self.counts.to_json()
+    }
+
+    fn restore(&mut self, snapshot: &Json) -> DecodeResult<()> {
+        self.counts = try!(try!(snapshot.as_object().ok_or(DecodeError::Malformed("snapshot is not an object".to_owned())))
+            .iter()
+            .map(|(key, count)| count.as_i64().map(|count| (key.clone(), count)).ok_or(DecodeError::WrongType("count")))
+            .collect());
+        Ok(())
+    }
+
+    fn split_off(&mut self, at: &str) -> Json {
+        let moved: Vec<String> = self.counts.keys().filter(|key| &key[..] >= at).cloned().collect();
+        let mut split = HashMap::new();
+        for key in moved {
+            let count = self.counts.remove(&key).unwrap();
+            split.insert(key, count);
+        }
+        split.to_json()
+    }
+
+    fn absorb(&mut self, snapshot: &Json) -> DecodeResult<()> {
+        let mut other = Counter::new();
+        try!(other.restore(snapshot));
+        self.counts.extend(other.counts);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_kv_store_write_ops() {
+    let s = |x: &str| x.to_owned();
+    let mut kv = KvStore::new();
+    assert_eq!(kv.apply(&Command::Cas { key: s("x"), expected: None, new: s("a") }), MsgType::OK(s("a")));
+    assert_eq!(kv.apply(&Command::Cas { key: s("x"), expected: None, new: s("b") }),
+               MsgType::CasFailed(Some(s("a"))));
+    assert_eq!(kv.apply(&Command::Append(s("x"), s("bc"))), MsgType::OK(s("abc")));
+    assert_eq!(kv.apply(&Command::Delete(s("x"))), MsgType::OK(s("abc")));
+    assert_eq!(kv.apply(&Command::Delete(s("x"))), MsgType::NotFound);
+    assert_eq!(kv.apply(&Command::Batch(vec![(s("a"), s("1")), (s("b"), s("2"))])), MsgType::OK(s("2")));
+    assert_eq!(kv.query("b"), MsgType::OK(s("2")));
+}
+
+#[test]
+fn test_state_machines_restore_from_snapshots() {
+    let mut kv = KvStore::new();
+    assert_eq!(kv.apply(&Command::Put("x".to_owned(), "1".to_owned())), MsgType::OK("1".to_owned()));
+    let mut copy = KvStore::new();
+    copy.restore(&kv.snapshot()).unwrap();
+    assert_eq!(copy.query("x"), MsgType::OK("1".to_owned()));
+    assert_eq!(copy.query("y"), MsgType::Fail);
+    assert!(copy.restore(&Json::from_str("{\"x\":2}").unwrap()).is_err());
+    assert!(copy.restore(&Json::from_str("[]").unwrap()).is_err());
+    assert_eq!(copy.query("x"), MsgType::OK("1".to_owned()), "a bad snapshot changes nothing");
+
+    let mut counter = Counter::new();
+    counter.apply(&Command::Put("hits".to_owned(), "5".to_owned()));
+    assert_eq!(counter.apply(&Command::Put("hits".to_owned(), "-2".to_owned())), MsgType::OK("3".to_owned()));
+    assert_eq!(counter.apply(&Command::Put("hits".to_owned(), "two".to_owned())), MsgType::Fail);
+    let mut copy = Counter::new();
+    copy.restore(&counter.snapshot()).unwrap();
+    assert_eq!(copy.query("hits"), MsgType::OK("3".to_owned()));
+    assert!(copy.restore(&Json::from_str("{\"hits\":\"3\"}").unwrap()).is_err());
+    assert_eq!(copy.query("hits"), MsgType::OK("3".to_owned()));
+
+    let moved = kv.split_off("x");
+    assert_eq!(kv.query("x"), MsgType::Fail);
+    let mut right = KvStore::new();
+    right.restore(&moved).unwrap();
+    assert_eq!(right.query("x"), MsgType::OK("1".to_owned()));
+    kv.absorb(&right.snapshot()).unwrap();
+    assert_eq!(kv.query("x"), MsgType::OK("1".to_owned()));
+}
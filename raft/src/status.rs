@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use rustc_serialize::json::{Json, ToJson};
+
+use super::msg::{AddJson, DecodeError, DecodeResult};
+use super::node::NodeId;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Role {
+    Follower,
+    Learner,
+    PreCandidate,
+    Candidate,
+    Leader,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Role::Follower     => "follower",
+            Role::Learner      => "learner",
+            Role::PreCandidate => "pre_candidate",
+            Role::Candidate    => "candidate",
+            Role::Leader       => "leader",
+        }
+    }
+
+    pub fn from_str(s: &str) -> Option<Role> {
+        match s {
+            "follower"      => Some(Role::Follower),
+            "learner"       => Some(Role::Learner),
+            "pre_candidate" => Some(Role::PreCandidate),
+            "candidate"     => Some(Role::Candidate),
+            "leader"        => Some(Role::Leader),
+            _               => None,
+        }
+    }
+}
+
+/// How far a leader has replicated its log to one follower
+#[derive(Clone, PartialEq, Debug)]
+pub struct Progress {
+    pub next_index: u64,
+    pub match_index: u64,
+}
+
+/// What a replica believes about itself and the cluster, as reported by a
+/// `status` request. `followers` is only filled in on leaders.
+#[derive(Clone, PartialEq, Debug)]
+pub struct NodeStatus {
+    pub id: NodeId,
+    pub role: Role,
+    pub term: u64,
+    pub voted_for: Option<NodeId>,
+    pub leader: Option<NodeId>,
+    pub commit_idx: u64,
+    pub last_applied: u64,
+    pub last_index: u64,
+    pub log_len: u64,
+    pub followers: BTreeMap<NodeId, Progress>,
+}
+
+impl ToJson for NodeStatus {
+    fn to_json(&self) -> Json {
+        let mut d = BTreeMap::new();
+        d.add_json("id", self.id);
+        d.add_json("role", self.role.as_str().to_owned());
+        d.add_json("term", self.term);
+        d.add_json("voted_for", self.voted_for);
+        d.add_json("leader", self.leader);
+        d.add_json("commit_idx", self.commit_idx);
+        d.add_json("last_applied", self.last_applied);
+        d.add_json("last_index", self.last_index);
+        d.add_json("log_len", self.log_len);
+        let mut followers = BTreeMap::new();
+        for (id, progress) in &self.followers {
+            let mut p = BTreeMap::new();
+            p.add_json("next_index", progress.next_index);
+            p.add_json("match_index", progress.match_index);
+            followers.insert(id.as_str().to_owned(), Json::Object(p));
+        }
+        d.add_json("followers", Json::Object(followers));
+        Json::Object(d)
+    }
+}
+
+impl NodeStatus {
+    pub fn from_json(json: &Json) -> DecodeResult<NodeStatus> {
+        let role = try!(string(json, "role"));
+        let mut followers = BTreeMap::new();
+        if let Some(progress) = json.find("followers") {
+            for (id, p) in try!(progress.as_object().ok_or(DecodeError::WrongType("followers"))) {
+                let id = try!(NodeId::from_str(id).ok_or(DecodeError::BadNodeId(id.clone())));
+                followers.insert(id, Progress {
+                    next_index: try!(number(p, "next_index")),
+                    match_index: try!(number(p, "match_index")),
+                });
+            }
+        }
+        Ok(NodeStatus {
+            id: try!(try!(node_id(json, "id")).ok_or(DecodeError::MissingField("id"))),
+            role: try!(Role::from_str(role).ok_or(DecodeError::WrongType("role"))),
+            term: try!(number(json, "term")),
+            voted_for: try!(node_id(json, "voted_for")),
+            leader: try!(node_id(json, "leader")),
+            commit_idx: try!(number(json, "commit_idx")),
+            last_applied: try!(number(json, "last_applied")),
+            last_index: try!(number(json, "last_index")),
+            log_len: try!(number(json, "log_len")),
+            followers: followers,
+        })
+    }
+}
+
+/// One row of the cluster table; leaders add a line per follower
+impl fmt::Display for NodeStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let id = |id: Option<NodeId>| id.map_or("-".to_owned(), |id| id.as_str().to_owned());
+        try!(write!(f, "{:<6} {:<13} {:>6} {:>6} {:>6} {:>8} {:>8} {:>8} {:>8}",
+                    self.id.as_str(), self.role.as_str(), self.term, id(self.voted_for), id(self.leader),
+                    self.commit_idx, self.last_applied, self.last_index, self.log_len));
+        for (follower, progress) in &self.followers {
+            try!(write!(f, "\n  -> {:<6} next {:>8} match {:>8}",
+                        follower.as_str(), progress.next_index, progress.match_index));
+        }
+        Ok(())
+    }
+}
+
+/// Column headings lined up with `NodeStatus`'s `Display`
+pub fn table_header() -> String {
+    format!("{:<6} {:<13} {:>6} {:>6} {:>6} {:>8} {:>8} {:>8} {:>8}",
+            "id", "role", "term", "voted", "leader", "commit", "applied", "last", "log")
+}
+
+fn number(json: &Json, key: &'static str) -> DecodeResult<u64> {
+    json.find(key)
+        .ok_or(DecodeError::MissingField(key))
+        .and_then(|n| n.as_u64().ok_or(DecodeError::WrongType(key)))
+}
+
+fn string<'a>(json: &'a Json, key: &'static str) -> DecodeResult<&'a str> {
+    json.find(key)
+        .ok_or(DecodeError::MissingField(key))
+        .and_then(|s| s.as_string().ok_or(DecodeError::WrongType(key)))
+}
+
+fn node_id(json: &Json, key: &'static str) -> DecodeResult<Option<NodeId>> {
+    match json.find(key) {
+        Some(&Json::Null) | None => Ok(None),
+        Some(id) => NodeId::as_node_id(id).map(Some).ok_or(DecodeError::BadNodeId(id.to_string())),
+    }
+}
+
+#[test]
+fn test_status_roundtrip() {
+    let mut followers = BTreeMap::new();
+    followers.insert(NodeId::from("0002"), Progress { next_index: 12, match_index: 11 });
+    let status = NodeStatus {
+        id: NodeId::from("0001"),
+        role: Role::Leader,
+        term: 3,
+        voted_for: Some(NodeId::from("0001")),
+        leader: Some(NodeId::from("0001")),
+        commit_idx: 11,
+        last_applied: 11,
+        last_index: 12,
+        log_len: 12,
+        followers: followers,
+    };
+    assert_eq!(NodeStatus::from_json(&status.to_json()).unwrap(), status);
+    assert_eq!(format!("{}", status).lines().count(), 2);
+}
@@ -0,0 +1,329 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use rustc_serialize::json::{Json, ToJson};
+
+use super::msg::{AddJson, Entry, Snapshot};
+use super::node::NodeId;
+
+const LOG_FILE: &'static str = "log";
+const LOG_TMP_FILE: &'static str = "log.tmp";
+const META_FILE: &'static str = "meta";
+const META_TMP_FILE: &'static str = "meta.tmp";
+const SNAPSHOT_FILE: &'static str = "snapshot";
+const SNAPSHOT_TMP_FILE: &'static str = "snapshot.tmp";
+
+/// The parts of a replica's state that must survive a restart, other than the log
+#[derive(Clone, PartialEq, Debug)]
+pub struct Metadata {
+    pub current_term: u64,
+    pub voted_for: Option<NodeId>,
+    pub commit_idx: u64,
+}
+
+impl Metadata {
+    fn from_json(json: &Json) -> Option<Metadata> {
+        let obj = match json.as_object() {
+            Some(obj) => obj,
+            None      => return None,
+        };
+        let voted_for = match obj.get("voted_for") {
+            Some(&Json::Null) | None => None,
+            Some(id)                 => Some(match NodeId::as_node_id(id) {
+                Some(id) => id,
+                None     => return None,
+            }),
+        };
+        Some(Metadata {
+            current_term: match obj.get("term").and_then(Json::as_u64) {
+                Some(term) => term,
+                None       => return None,
+            },
+            voted_for: voted_for,
+            commit_idx: obj.get("commit_idx").and_then(Json::as_u64).unwrap_or(0),
+        })
+    }
+}
+
+impl Default for Metadata {
+    fn default() -> Metadata {
+        Metadata {
+            current_term: 0,
+            voted_for: None,
+            commit_idx: 0,
+        }
+    }
+}
+
+impl ToJson for Metadata {
+    fn to_json(&self) -> Json {
+        let mut d = BTreeMap::new();
+        d.add_json("term", self.current_term);
+        d.add_json("voted_for", self.voted_for);
+        d.add_json("commit_idx", self.commit_idx);
+        Json::Object(d)
+    }
+}
+
+/// Durable state for one replica: an append-only log of JSON lines, fsynced on
+/// every write, and small metadata and snapshot files that are replaced
+/// atomically. After a compaction the log file opens with a `{"start": N}`
+/// header line, meaning its first entry is at index N + 1.
+pub struct Storage {
+    dir: PathBuf,
+    log: File,
+    start: u64,
+    offsets: Vec<u64>,
+    end: u64,
+}
+
+impl Storage {
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Storage> {
+        let dir = dir.as_ref().to_path_buf();
+        try!(fs::create_dir_all(&dir));
+        let log = try!(OpenOptions::new()
+                       .read(true)
+                       .append(true)
+                       .create(true)
+                       .open(dir.join(LOG_FILE)));
+        Ok(Storage {
+            dir: dir,
+            log: log,
+            start: 0,
+            offsets: vec![],
+            end: 0,
+        })
+    }
+
+    /// Index of the entry just before the first one in the log file
+    pub fn log_start(&self) -> u64 {
+        self.start
+    }
+
+    /// Reads back every intact entry in the log. A partially written trailing
+    /// entry, left behind by a crash mid-append, is cut off the file. Any
+    /// other line that won't parse is an error: the entries after it were
+    /// synced, and may be committed.
+    pub fn read_log(&mut self) -> io::Result<Vec<Entry>> {
+        let mut entries = vec![];
+        self.start = 0;
+        self.offsets.clear();
+        self.end = 0;
+
+        let mut reader = BufReader::new(try!(File::open(self.dir.join(LOG_FILE))));
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = try!(reader.read_line(&mut line));
+            if read == 0 || !line.ends_with('\n') {
+                break;
+            }
+            let json = try!(Json::from_str(line.trim_right()).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("corrupt raft log at byte {}: {}", self.end, e))
+            }));
+            match json.find("start").and_then(Json::as_u64) {
+                Some(start) if self.end == 0 => self.start = start,
+                _ => {
+                    entries.push(try!(Entry::from_json(&json).map_err(|e| {
+                        io::Error::new(io::ErrorKind::InvalidData, format!("corrupt raft log entry: {}", e))
+                    })));
+                    self.offsets.push(self.end);
+                },
+            }
+            self.end += read as u64;
+        }
+
+        try!(self.log.set_len(self.end));
+        try!(self.log.sync_all());
+        Ok(entries)
+    }
+
+    pub fn read_meta(&self) -> io::Result<Metadata> {
+        let mut contents = String::new();
+        match File::open(self.dir.join(META_FILE)) {
+            Ok(mut file) => try!(file.read_to_string(&mut contents)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Metadata::default()),
+            Err(e) => return Err(e),
+        };
+        Json::from_str(&contents)
+            .ok()
+            .and_then(|json| Metadata::from_json(&json))
+            .ok_or(io::Error::new(io::ErrorKind::InvalidData, "corrupt raft metadata file"))
+    }
+
+    pub fn save_meta(&self, meta: &Metadata) -> io::Result<()> {
+        self.replace(META_FILE, META_TMP_FILE, meta.to_json().to_string().as_bytes())
+    }
+
+    pub fn read_snapshot(&self) -> io::Result<Snapshot> {
+        let mut contents = String::new();
+        match File::open(self.dir.join(SNAPSHOT_FILE)) {
+            Ok(mut file) => try!(file.read_to_string(&mut contents)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Snapshot::default()),
+            Err(e) => return Err(e),
+        };
+        Json::from_str(&contents)
+            .ok()
+            .and_then(|json| Snapshot::from_json(&json).ok())
+            .ok_or(io::Error::new(io::ErrorKind::InvalidData, "corrupt raft snapshot file"))
+    }
+
+    pub fn save_snapshot(&self, snapshot: &Snapshot) -> io::Result<()> {
+        self.replace(SNAPSHOT_FILE, SNAPSHOT_TMP_FILE, snapshot.to_json().to_string().as_bytes())
+    }
+
+    /// Rewrites the log file so that it holds only `entries`, the first of
+    /// which is at index `start + 1`
+    pub fn compact(&mut self, start: u64, entries: &[Entry]) -> io::Result<()> {
+        let mut buf = String::new();
+        let mut offsets = vec![];
+        buf.push_str(&format!("{{\"start\":{}}}\n", start));
+        for entry in entries {
+            offsets.push(buf.len() as u64);
+            buf.push_str(&entry.to_json().to_string());
+            buf.push('\n');
+        }
+        try!(self.replace(LOG_FILE, LOG_TMP_FILE, buf.as_bytes()));
+
+        self.log = try!(OpenOptions::new()
+                        .read(true)
+                        .append(true)
+                        .open(self.dir.join(LOG_FILE)));
+        self.start = start;
+        self.offsets = offsets;
+        self.end = buf.len() as u64;
+        Ok(())
+    }
+
+    /// Atomically swaps the contents of `name` for `contents`
+    fn replace(&self, name: &str, tmp_name: &str, contents: &[u8]) -> io::Result<()> {
+        let tmp = self.dir.join(tmp_name);
+        {
+            let mut file = try!(File::create(&tmp));
+            try!(file.write_all(contents));
+            try!(file.sync_all());
+        }
+        try!(fs::rename(&tmp, self.dir.join(name)));
+        File::open(&self.dir).and_then(|dir| dir.sync_all())
+    }
+
+    pub fn append(&mut self, entries: &[Entry]) -> io::Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let mut buf = String::new();
+        for entry in entries {
+            self.offsets.push(self.end + buf.len() as u64);
+            buf.push_str(&entry.to_json().to_string());
+            buf.push('\n');
+        }
+        try!(self.log.write_all(buf.as_bytes()));
+        try!(self.log.sync_data());
+        self.end += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Drops every entry after index `last_idx`
+    pub fn truncate(&mut self, last_idx: u64) -> io::Result<()> {
+        let len = last_idx.saturating_sub(self.start) as usize;
+        if len >= self.offsets.len() {
+            return Ok(());
+        }
+        self.end = self.offsets[len];
+        self.offsets.truncate(len);
+        try!(self.log.set_len(self.end));
+        self.log.sync_data()
+    }
+}
+
+#[allow(dead_code)]
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = ::std::env::temp_dir().join(format!("raft-storage-{}", name));
+    drop(fs::remove_dir_all(&dir));
+    dir
+}
+
+#[test]
+fn test_log_survives_reopen() {
+    let dir = scratch_dir("reopen");
+    {
+        let mut storage = Storage::open(&dir).unwrap();
+        assert_eq!(storage.read_log().unwrap(), vec![]);
+        storage.append(&[Entry::new("x", "1", 1), Entry::new("y", "2", 1)]).unwrap();
+        storage.append(&[Entry::new("z", "3", 2)]).unwrap();
+        storage.truncate(2).unwrap();
+        storage.append(&[Entry::new("w", "4", 3)]).unwrap();
+        storage.save_meta(&Metadata {
+            current_term: 3,
+            voted_for: Some(NodeId::from("0001")),
+            commit_idx: 2,
+        }).unwrap();
+    }
+
+    let mut storage = Storage::open(&dir).unwrap();
+    assert_eq!(storage.read_log().unwrap(),
+               vec![Entry::new("x", "1", 1), Entry::new("y", "2", 1), Entry::new("w", "4", 3)]);
+    assert_eq!(storage.read_meta().unwrap(), Metadata {
+        current_term: 3,
+        voted_for: Some(NodeId::from("0001")),
+        commit_idx: 2,
+    });
+    drop(fs::remove_dir_all(&dir));
+}
+
+#[test]
+fn test_compacted_log_keeps_its_start() {
+    let dir = scratch_dir("compact");
+    {
+        let mut storage = Storage::open(&dir).unwrap();
+        storage.read_log().unwrap();
+        storage.append(&[Entry::new("x", "1", 1), Entry::new("y", "2", 1), Entry::new("z", "3", 2)]).unwrap();
+        storage.save_snapshot(&Snapshot::new(2, 1, Json::from_str("{\"x\":\"1\",\"y\":\"2\"}").unwrap())).unwrap();
+        storage.compact(2, &[Entry::new("z", "3", 2)]).unwrap();
+        storage.append(&[Entry::new("w", "4", 2)]).unwrap();
+        storage.truncate(3).unwrap();
+    }
+
+    let mut storage = Storage::open(&dir).unwrap();
+    assert_eq!(storage.read_log().unwrap(), vec![Entry::new("z", "3", 2)]);
+    assert_eq!(storage.log_start(), 2);
+    assert_eq!(storage.read_snapshot().unwrap().last_index, 2);
+    drop(fs::remove_dir_all(&dir));
+}
+
+#[test]
+fn test_torn_append_is_discarded() {
+    let dir = scratch_dir("torn");
+    {
+        let mut storage = Storage::open(&dir).unwrap();
+        storage.append(&[Entry::new("x", "1", 1)]).unwrap();
+        storage.log.write_all(b"{\"key\":\"y\",\"te").unwrap();
+    }
+
+    let mut storage = Storage::open(&dir).unwrap();
+    assert_eq!(storage.read_log().unwrap(), vec![Entry::new("x", "1", 1)]);
+    assert_eq!(storage.read_meta().unwrap(), Metadata::default());
+    storage.append(&[Entry::new("y", "2", 1)]).unwrap();
+    assert_eq!(Storage::open(&dir).unwrap().read_log().unwrap(),
+               vec![Entry::new("x", "1", 1), Entry::new("y", "2", 1)]);
+    drop(fs::remove_dir_all(&dir));
+}
+
+#[test]
+fn test_corrupt_entry_before_the_tail_is_an_error() {
+    let dir = scratch_dir("corrupt");
+    {
+        let mut storage = Storage::open(&dir).unwrap();
+        storage.append(&[Entry::new("x", "1", 1)]).unwrap();
+        storage.log.write_all(b"{\"key\":\"y\",\"te\n").unwrap();
+        storage.append(&[Entry::new("z", "3", 1)]).unwrap();
+    }
+
+    let len = fs::metadata(dir.join(LOG_FILE)).unwrap().len();
+    let mut storage = Storage::open(&dir).unwrap();
+    assert_eq!(storage.read_log().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    assert_eq!(fs::metadata(dir.join(LOG_FILE)).unwrap().len(), len, "nothing after the bad line was cut off");
+    drop(fs::remove_dir_all(&dir));
+}
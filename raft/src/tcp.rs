@@ -0,0 +1,316 @@
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use super::clock::{Clock, SystemClock, WallClock};
+use super::codec::Links;
+use super::msg::Msg;
+use super::node::NodeId;
+use super::port::Port;
+use super::settings::Settings;
+use super::trace::Tracer;
+use super::transport::Transport;
+
+/// Frames waiting for a peer's connection before more are dropped
+const PEER_QUEUE: usize = 1024;
+
+/// Replies waiting for a client's connection before more are dropped
+const CLIENT_QUEUE: usize = 1024;
+
+/// Replicas talking directly over TCP, in the same frames as the harness's
+/// socket. Each listens on its own address from `Settings::addresses` and
+/// dials its peers' when it has something for them. Messages for anyone
+/// else, like a client, go back down the connection that id first wrote
+/// from, for as long as that connection stays open.
+///
+/// Nothing is retried or waited for: a frame for a peer that can't be
+/// reached, or for a client that isn't reading, is dropped, and raft or the
+/// client sends whatever matters again.
+pub struct TcpTransport {
+    links: Arc<Links>,
+    peers: HashMap<NodeId, mpsc::SyncSender<Vec<u8>>>,
+    clients: Arc<Mutex<HashMap<NodeId, Client>>>,
+}
+
+/// Where a client's replies go: the queue of the connection that claimed
+/// its id, numbered so that no other connection can take it over
+struct Client {
+    conn: u64,
+    queue: mpsc::SyncSender<Vec<u8>>,
+}
+
+impl TcpTransport {
+    /// Listens on our address and hands everything that arrives to `sender`
+    pub fn start(settings: &Settings, links: Arc<Links>, sender: mpsc::Sender<Msg>) -> io::Result<TcpTransport> {
+        let addr = try!(settings.addresses.get(&settings.id)
+                        .ok_or(io::Error::new(io::ErrorKind::InvalidInput, "no address to listen on")));
+        let listener = try!(TcpListener::bind(&addr[..]));
+
+        let mut peers = HashMap::new();
+        for (&id, addr) in &settings.addresses {
+            if id == settings.id {
+                continue;
+            }
+            let (queue, frames) = mpsc::sync_channel(PEER_QUEUE);
+            let dialer = Dialer {
+                addr: addr.clone(),
+                backoff_min: settings.reconnect_min,
+                backoff_max: settings.reconnect_max,
+            };
+            thread::spawn(move || dialer.run(frames));
+            peers.insert(id, queue);
+        }
+
+        let clients = Arc::new(Mutex::new(HashMap::new()));
+        let acceptor = Acceptor {
+            peers: peers.keys().cloned().collect(),
+            clients: clients.clone(),
+            links: links.clone(),
+            sender: sender,
+            settings: settings.clone(),
+        };
+        thread::spawn(move || acceptor.run(listener));
+
+        Ok(TcpTransport {
+            links: links,
+            peers: peers,
+            clients: clients,
+        })
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send(&self, msg: &Msg) {
+        let frame = self.links.encode(msg);
+        if let Some(queue) = self.peers.get(&msg.base.dst) {
+            drop(queue.try_send(frame));
+            return;
+        }
+        let mut clients = self.clients.lock().unwrap();
+        let closed = match clients.get(&msg.base.dst).map(|client| client.queue.try_send(frame)) {
+            Some(Err(mpsc::TrySendError::Disconnected(_))) => true,
+            _                                              => false,
+        };
+        if closed {
+            clients.remove(&msg.base.dst);
+        }
+    }
+}
+
+/// Writes one peer's frames to it, connecting when there is something to
+/// send and the last failure is far enough behind
+struct Dialer {
+    addr: String,
+    backoff_min: u64,
+    backoff_max: u64,
+}
+
+impl Dialer {
+    fn run(self, frames: mpsc::Receiver<Vec<u8>>) {
+        let clock = SystemClock;
+        let mut stream: Option<TcpStream> = None;
+        let mut backoff = self.backoff_min;
+        let mut retry_at = 0;
+        for frame in frames.iter() {
+            if stream.is_none() && clock.now_ms() >= retry_at {
+                match TcpStream::connect(&self.addr[..]) {
+                    Ok(connected) => {
+                        drop(connected.set_nodelay(true));
+                        stream = Some(connected);
+                        backoff = self.backoff_min;
+                    },
+                    Err(_) => {
+                        retry_at = clock.now_ms() + backoff;
+                        backoff = cmp::min(backoff * 2, self.backoff_max);
+                    },
+                }
+            }
+            let failed = match stream {
+                Some(ref mut stream) => stream.write_all(&frame).is_err(),
+                None                 => false,
+            };
+            if failed {
+                // The peer probably restarted; dial it again with the next frame
+                stream = None;
+            }
+        }
+    }
+}
+
+/// Writes replies down a client's connection until it fails
+fn answer(mut stream: TcpStream, frames: mpsc::Receiver<Vec<u8>>) {
+    for frame in frames.iter() {
+        if stream.write_all(&frame).is_err() {
+            return;
+        }
+    }
+}
+
+/// Takes connections from peers and clients alike, relaying what they send
+/// to the replica and remembering clients' connections to answer them on
+struct Acceptor {
+    peers: HashSet<NodeId>,
+    clients: Arc<Mutex<HashMap<NodeId, Client>>>,
+    links: Arc<Links>,
+    sender: mpsc::Sender<Msg>,
+    /// For each connection's tracer
+    settings: Settings,
+}
+
+impl Acceptor {
+    fn run(self, listener: TcpListener) {
+        for (conn, stream) in listener.incoming().filter_map(Result::ok).enumerate() {
+            let conn = conn as u64;
+            drop(stream.set_nodelay(true));
+            let reply = match stream.try_clone() {
+                Ok(reply) => reply,
+                Err(_)    => continue,
+            };
+            // Replies get a thread of their own, so a client that stops
+            // reading holds up nobody but itself
+            let (queue, frames) = mpsc::sync_channel(CLIENT_QUEUE);
+            thread::spawn(move || answer(reply, frames));
+            let peers = self.peers.clone();
+            let clients = self.clients.clone();
+            let mut claimed = HashSet::new();
+            let port = Port::new(stream, self.sender.clone(), self.links.clone()).on_receive(move |msg: &Msg| {
+                let src = msg.base.src;
+                if peers.contains(&src) || claimed.contains(&src) {
+                    return;
+                }
+                // An id another open connection already claimed keeps its
+                // replies there
+                let mut clients = clients.lock().unwrap();
+                if clients.entry(src).or_insert(Client { conn: conn, queue: queue.clone() }).conn == conn {
+                    claimed.insert(src);
+                }
+            });
+            let settings = self.settings.clone();
+            let clients = self.clients.clone();
+            thread::spawn(move || {
+                // A connection is no reason to give up on its messages
+                let tracer = Tracer::from_settings(&settings, Box::new(WallClock))
+                    .unwrap_or_else(|_| Tracer::new(settings.id, settings.trace_level, Box::new(WallClock)));
+                port.relay(&tracer);
+
+                // Its ids are free for the next connection to claim
+                let mut clients = clients.lock().unwrap();
+                let ids: Vec<NodeId> = clients.iter()
+                    .filter(|&(_, client)| client.conn == conn)
+                    .map(|(&id, _)| id)
+                    .collect();
+                for id in ids {
+                    clients.remove(&id);
+                }
+            });
+        }
+    }
+}
+
+#[test]
+fn test_peers_and_clients_reach_each_other_over_tcp() {
+    use std::io::{BufRead, BufReader};
+    use std::time::Duration;
+    use super::msg::{BaseMsg, MsgType};
+
+    let free = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let (a, b) = (NodeId::from("0000"), NodeId::from("0001"));
+    let mut settings = Settings::new(a, vec![b]);
+    // Nothing dials `a` here, so it can listen anywhere
+    settings.addresses.insert(a, "127.0.0.1:0".to_owned());
+    settings.addresses.insert(b, free.to_string());
+    let (to_a, _) = mpsc::channel();
+    let from_a = TcpTransport::start(&settings, Arc::new(Links::new(false)), to_a).unwrap();
+    settings.id = b;
+    settings.peers = vec![a];
+    let (to_b, at_b) = mpsc::channel();
+    let from_b = TcpTransport::start(&settings, Arc::new(Links::new(false)), to_b).unwrap();
+
+    let msg = Msg::new(BaseMsg::new(a, b, a, "1".to_owned()), MsgType::Get("x".to_owned()));
+    from_a.send(&msg);
+    assert_eq!(at_b.recv_timeout(Duration::from_secs(5)).unwrap(), msg);
+
+    let client = NodeId::from("C001");
+    let mut conn = TcpStream::connect(free).unwrap();
+    let request = Msg::new(BaseMsg::new(client, b, NodeId::broadcast(), "2".to_owned()), MsgType::Get("x".to_owned()));
+    conn.write_all(&super::codec::encode_json(&request, false)).unwrap();
+    assert_eq!(at_b.recv_timeout(Duration::from_secs(5)).unwrap(), request);
+    from_b.send(&Msg::new(BaseMsg::new(b, client, b, "2".to_owned()), MsgType::Fail));
+    let mut line = String::new();
+    BufReader::new(conn).read_line(&mut line).unwrap();
+    assert!(line.contains("\"C001\""), "the reply went back down the client's connection: {}", line);
+}
+
+#[test]
+fn test_a_client_that_stops_reading_does_not_block_sends() {
+    use std::time::Duration;
+    use super::msg::{BaseMsg, MsgType};
+
+    let free = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let b = NodeId::from("0001");
+    let mut settings = Settings::new(b, vec![]);
+    settings.addresses.insert(b, free.to_string());
+    let (to_b, at_b) = mpsc::channel();
+    let from_b = TcpTransport::start(&settings, Arc::new(Links::new(false)), to_b).unwrap();
+
+    let client = NodeId::from("C001");
+    let mut conn = TcpStream::connect(free).unwrap();
+    let request = Msg::new(BaseMsg::new(client, b, NodeId::broadcast(), "1".to_owned()), MsgType::Get("x".to_owned()));
+    conn.write_all(&super::codec::encode_json(&request, false)).unwrap();
+    at_b.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    // Far more than the socket's buffers hold, and `conn` never reads any of it
+    let reply = Msg::new(BaseMsg::new(b, client, b, "1".to_owned()), MsgType::OK(vec!['v'; 64 * 1024].into_iter().collect()));
+    let (done, finished) = mpsc::channel();
+    thread::spawn(move || {
+        for _ in 0..4 * CLIENT_QUEUE {
+            from_b.send(&reply);
+        }
+        done.send(()).unwrap();
+    });
+    assert!(finished.recv_timeout(Duration::from_secs(30)).is_ok(), "sending to a stalled client blocked");
+    drop(conn);
+}
+
+#[test]
+fn test_a_client_id_is_answered_on_the_connection_that_claimed_it() {
+    use std::io::{BufRead, BufReader};
+    use std::time::Duration;
+    use super::msg::{BaseMsg, MsgType};
+
+    let free = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let b = NodeId::from("0001");
+    let mut settings = Settings::new(b, vec![]);
+    settings.addresses.insert(b, free.to_string());
+    let (to_b, at_b) = mpsc::channel();
+    let from_b = TcpTransport::start(&settings, Arc::new(Links::new(false)), to_b).unwrap();
+
+    let client = NodeId::from("C001");
+    let request = |mid: &str| Msg::new(BaseMsg::new(client, b, NodeId::broadcast(), mid.to_owned()), MsgType::Get("x".to_owned()));
+    let reply = |mid: &str| Msg::new(BaseMsg::new(b, client, b, mid.to_owned()), MsgType::OK(mid.to_owned()));
+    let mut owner = TcpStream::connect(free).unwrap();
+    owner.write_all(&super::codec::encode_json(&request("1"), false)).unwrap();
+    at_b.recv_timeout(Duration::from_secs(5)).unwrap();
+    owner.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut thief = TcpStream::connect(free).unwrap();
+    thief.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    thief.write_all(&super::codec::encode_json(&request("2"), false)).unwrap();
+    at_b.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    from_b.send(&reply("2"));
+    let mut line = String::new();
+    BufReader::new(owner).read_line(&mut line).unwrap();
+    assert!(line.contains("\"2\""), "the reply went to the connection that claimed the id first: {}", line);
+
+    // Once the owner hangs up, the id is free again
+    thread::sleep(Duration::from_millis(200));
+    thief.write_all(&super::codec::encode_json(&request("3"), false)).unwrap();
+    at_b.recv_timeout(Duration::from_secs(5)).unwrap();
+    from_b.send(&reply("3"));
+    let mut line = String::new();
+    BufReader::new(thief).read_line(&mut line).unwrap();
+    assert!(line.contains("\"3\""), "the reply went to the id's new connection: {}", line);
+}
@@ -0,0 +1,268 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use rustc_serialize::json::{Json, ToJson};
+
+use super::clock::Clock;
+use super::node::NodeId;
+use super::settings::Settings;
+
+/// How much a replica says about itself; a tracer at one level also records
+/// everything above it
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Level {
+    /// Every append, acknowledgement, commit and client reply
+    Debug,
+    /// Elections, votes, term and configuration changes
+    Info,
+    /// Things that should not happen often, like losing a quorum
+    Warn,
+}
+
+impl Level {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Level::Debug => "debug",
+            Level::Info  => "info",
+            Level::Warn  => "warn",
+        }
+    }
+
+    pub fn from_str(name: &str) -> Option<Level> {
+        match name {
+            "debug" => Some(Level::Debug),
+            "info"  => Some(Level::Info),
+            "warn"  => Some(Level::Warn),
+            _       => None,
+        }
+    }
+}
+
+/// Somewhere trace lines go. A line is one JSON object without its newline.
+pub trait Sink {
+    fn write(&self, line: &str);
+}
+
+pub struct StdoutSink;
+
+impl Sink for StdoutSink {
+    fn write(&self, line: &str) {
+        println!("{}", line);
+    }
+}
+
+pub struct StderrSink;
+
+impl Sink for StderrSink {
+    fn write(&self, line: &str) {
+        drop(writeln!(io::stderr(), "{}", line));
+    }
+}
+
+/// Appends to a file, so a restarted replica keeps its earlier trace
+pub struct FileSink {
+    file: RefCell<File>,
+}
+
+impl FileSink {
+    pub fn open(path: &Path) -> io::Result<FileSink> {
+        let file = try!(OpenOptions::new().append(true).create(true).open(path));
+        Ok(FileSink { file: RefCell::new(file) })
+    }
+}
+
+impl Sink for FileSink {
+    fn write(&self, line: &str) {
+        drop(writeln!(self.file.borrow_mut(), "{}", line));
+    }
+}
+
+/// Writes one replica's events as JSON lines stamped with its id, term and
+/// the time, e.g.
+///
+/// `{"ts":1457300000123,"node":"0001","term":3,"level":"info","event":"vote","candidate":"0002","granted":true}`
+pub struct Tracer {
+    node: NodeId,
+    group: Option<String>,
+    level: Level,
+    clock: Box<Clock>,
+    sinks: Vec<Box<Sink>>,
+}
+
+impl Tracer {
+    /// A tracer that records nothing until it is given a sink
+    pub fn new(node: NodeId, level: Level, clock: Box<Clock>) -> Tracer {
+        Tracer {
+            node: node,
+            group: None,
+            level: level,
+            clock: clock,
+            sinks: vec![],
+        }
+    }
+
+    pub fn with_sink(mut self, sink: Box<Sink>) -> Tracer {
+        self.sinks.push(sink);
+        self
+    }
+
+    /// Labels every event with the consensus group it happened in, for
+    /// processes that host more than one
+    pub fn in_group(mut self, group: &str) -> Tracer {
+        self.group = Some(group.to_owned());
+        self
+    }
+
+    /// The tracer `settings` ask for. Sinks are `stdout`, `stderr` or a path
+    /// to append to.
+    pub fn from_settings(settings: &Settings, clock: Box<Clock>) -> io::Result<Tracer> {
+        let mut tracer = Tracer::new(settings.id, settings.trace_level, clock);
+        for sink in &settings.trace_sinks {
+            tracer = match &sink[..] {
+                "stdout" => tracer.with_sink(Box::new(StdoutSink)),
+                "stderr" => tracer.with_sink(Box::new(StderrSink)),
+                path     => tracer.with_sink(Box::new(try!(FileSink::open(Path::new(path))))),
+            };
+        }
+        Ok(tracer)
+    }
+
+    /// Whether an event at `level` would be written anywhere, so callers can
+    /// skip building its fields
+    pub fn enabled(&self, level: Level) -> bool {
+        level >= self.level && !self.sinks.is_empty()
+    }
+
+    pub fn event(&self, level: Level, term: u64, event: &str, fields: &[(&str, Json)]) {
+        if !self.enabled(level) {
+            return;
+        }
+        let mut line = format!("{{\"ts\":{},\"node\":{},\"term\":{},\"level\":\"{}\",\"event\":{}",
+                               self.clock.now_ms(), self.node.to_json(), term, level.name(), event.to_json());
+        if let Some(ref group) = self.group {
+            line.push_str(&format!(",\"group\":{}", group.to_json()));
+        }
+        for &(key, ref value) in fields {
+            line.push_str(&format!(",{}:{}", key.to_json(), value));
+        }
+        line.push('}');
+        for sink in &self.sinks {
+            sink.write(&line);
+        }
+    }
+}
+
+/// A trace line read back, for putting timelines together
+#[derive(Clone, PartialEq, Debug)]
+pub struct Record {
+    pub ts: u64,
+    pub node: String,
+    pub term: u64,
+    pub level: Level,
+    pub event: String,
+    /// Everything else the event carried
+    pub fields: BTreeMap<String, Json>,
+}
+
+impl Record {
+    /// `None` for anything that is not a trace line
+    pub fn parse(line: &str) -> Option<Record> {
+        let mut obj = match Json::from_str(line) {
+            Ok(Json::Object(obj)) => obj,
+            _                     => return None,
+        };
+        let ts = obj.remove("ts").and_then(|ts| ts.as_u64());
+        let node = obj.remove("node").and_then(|node| node.as_string().map(str::to_owned));
+        let term = obj.remove("term").and_then(|term| term.as_u64());
+        let level = obj.remove("level").and_then(|level| level.as_string().and_then(Level::from_str));
+        let event = obj.remove("event").and_then(|event| event.as_string().map(str::to_owned));
+        match (ts, node, term, level, event) {
+            (Some(ts), Some(node), Some(term), Some(level), Some(event)) => Some(Record {
+                ts: ts,
+                node: node,
+                term: term,
+                level: level,
+                event: event,
+                fields: obj,
+            }),
+            _ => None,
+        }
+    }
+
+    /// One line for a person: time, node, term, level, event, then fields
+    pub fn describe(&self) -> String {
+        let mut line = format!("{:>13} {:<8} t{:<4} {:<5} {}",
+                               self.ts, self.node, self.term, self.level.name(), self.event);
+        for (key, value) in &self.fields {
+            line.push_str(&format!(" {}={}", key, value));
+        }
+        line
+    }
+}
+
+impl ToJson for Record {
+    fn to_json(&self) -> Json {
+        let mut obj = self.fields.clone();
+        obj.insert("ts".to_owned(), self.ts.to_json());
+        obj.insert("node".to_owned(), self.node.to_json());
+        obj.insert("term".to_owned(), self.term.to_json());
+        obj.insert("level".to_owned(), self.level.name().to_json());
+        obj.insert("event".to_owned(), self.event.to_json());
+        Json::Object(obj)
+    }
+}
+
+/// Interleaves several replicas' traces into one timeline. Events with the
+/// same timestamp keep the order they had in `traces`.
+pub fn merge(traces: Vec<Vec<Record>>) -> Vec<Record> {
+    let mut timeline: Vec<Record> = traces.into_iter().flat_map(|trace| trace.into_iter()).collect();
+    timeline.sort_by(|a, b| a.ts.cmp(&b.ts));
+    timeline
+}
+
+#[test]
+fn test_traces_round_trip_and_merge_by_time() {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    struct Lines(Rc<RefCell<Vec<String>>>);
+    impl Sink for Lines {
+        fn write(&self, line: &str) {
+            self.0.borrow_mut().push(line.to_owned());
+        }
+    }
+    struct Fixed(Rc<Cell<u64>>);
+    impl Clock for Fixed {
+        fn now_ms(&self) -> u64 {
+            self.0.get()
+        }
+    }
+
+    let now = Rc::new(Cell::new(10));
+    let traces: Vec<Rc<RefCell<Vec<String>>>> = vec![Rc::new(RefCell::new(vec![])), Rc::new(RefCell::new(vec![]))];
+    let tracer = |id: &str, trace: &Rc<RefCell<Vec<String>>>| {
+        Tracer::new(NodeId::from(id), Level::Info, Box::new(Fixed(now.clone())))
+            .with_sink(Box::new(Lines(trace.clone())))
+    };
+    let (a, b) = (tracer("0000", &traces[0]), tracer("0001", &traces[1]));
+
+    a.event(Level::Info, 1, "election_started", &[]);
+    a.event(Level::Debug, 1, "append_sent", &[("to", NodeId::from("0001").to_json())]);
+    now.set(12);
+    a.event(Level::Info, 1, "became_leader", &[]);
+    now.set(11);
+    b.event(Level::Info, 1, "vote", &[("candidate", NodeId::from("0000").to_json()), ("granted", true.to_json())]);
+    assert_eq!(traces[0].borrow().len(), 2, "debug events are filtered out at info");
+    assert_eq!(traces[1].borrow()[0],
+               "{\"ts\":11,\"node\":\"0001\",\"term\":1,\"level\":\"info\",\"event\":\"vote\",\"candidate\":\"0000\",\"granted\":true}");
+
+    let records = traces.iter()
+        .map(|trace| trace.borrow().iter().map(|line| Record::parse(line).unwrap()).collect())
+        .collect();
+    let timeline: Vec<String> = merge(records).into_iter().map(|record| record.event).collect();
+    assert_eq!(timeline, vec!["election_started", "vote", "became_leader"]);
+    assert!(Record::parse("{\"ts\": 1}").is_none());
+}
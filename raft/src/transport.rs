@@ -0,0 +1,35 @@
+use std::cell::RefCell;
+use std::io::Write;
+use std::sync::Arc;
+
+use unix_socket::UnixStream;
+
+use super::codec::Links;
+use super::msg::Msg;
+
+/// Carries a replica's outgoing messages to whoever routes them by `dst`
+pub trait Transport {
+    fn send(&self, msg: &Msg);
+}
+
+/// Frames written to the socket the test harness owns: JSON lines, or binary
+/// frames on links where both ends have agreed to the binary codec
+pub struct SocketTransport {
+    socket: RefCell<UnixStream>,
+    links: Arc<Links>,
+}
+
+impl SocketTransport {
+    pub fn new(socket: UnixStream, links: Arc<Links>) -> SocketTransport {
+        SocketTransport {
+            socket: RefCell::new(socket),
+            links: links,
+        }
+    }
+}
+
+impl Transport for SocketTransport {
+    fn send(&self, msg: &Msg) {
+        drop((*self.socket.borrow_mut()).write_all(&self.links.encode(msg)))
+    }
+}
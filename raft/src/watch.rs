@@ -0,0 +1,100 @@
+use std::collections::VecDeque;
+
+use super::node::NodeId;
+
+/// One key changed by an applied entry
+#[derive(Clone, PartialEq, Debug)]
+pub struct Change {
+    pub index: u64,
+    pub key: String,
+    /// `None` once the key is deleted
+    pub value: Option<String>,
+}
+
+/// A client's subscription, held by the leader. Events go back to `client`
+/// under the MID it watched with.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Watch {
+    pub client: NodeId,
+    pub mid: String,
+    pub key: String,
+    pub prefix: bool,
+    /// The first index the client still wants to hear about
+    pub from_index: u64,
+}
+
+impl Watch {
+    pub fn matches(&self, change: &Change) -> bool {
+        change.index >= self.from_index && if self.prefix {
+            change.key.starts_with(&self.key)
+        } else {
+            change.key == self.key
+        }
+    }
+}
+
+/// The most recent changes applied to the state machine, so a watch can
+/// resume where it left off on a new leader. Older changes are forgotten
+/// once there are more than `capacity`.
+pub struct ChangeLog {
+    changes: VecDeque<Change>,
+    capacity: usize,
+    /// Every change at this index or later is still here
+    complete_from: u64,
+}
+
+impl ChangeLog {
+    pub fn new(capacity: usize) -> ChangeLog {
+        ChangeLog {
+            changes: VecDeque::new(),
+            capacity: capacity,
+            complete_from: 1,
+        }
+    }
+
+    pub fn record(&mut self, change: Change) {
+        self.changes.push_back(change);
+        while self.changes.len() > self.capacity {
+            let dropped = self.changes.pop_front().unwrap();
+            self.complete_from = dropped.index + 1;
+        }
+    }
+
+    /// Forgets everything; only changes after `last_index` will be known.
+    /// Used when the state machine is replaced by a snapshot.
+    pub fn reset(&mut self, last_index: u64) {
+        self.changes.clear();
+        self.complete_from = last_index + 1;
+    }
+
+    pub fn complete_from(&self) -> u64 {
+        self.complete_from
+    }
+
+    /// Every change at `from_index` or later, or `None` if some of them have
+    /// been forgotten
+    pub fn since(&self, from_index: u64) -> Option<Vec<&Change>> {
+        if from_index < self.complete_from {
+            return None;
+        }
+        Some(self.changes.iter().filter(|change| change.index >= from_index).collect())
+    }
+}
+
+#[test]
+fn test_change_log_forgets_the_oldest_changes() {
+    let change = |index, key: &str| Change { index: index, key: key.to_owned(), value: None };
+    let mut log = ChangeLog::new(3);
+    log.record(change(2, "a"));
+    log.record(change(2, "b"));
+    log.record(change(5, "svc/a"));
+    assert_eq!(log.since(1).unwrap().len(), 3);
+
+    log.record(change(6, "svc/b"));
+    assert_eq!(log.complete_from(), 3);
+    assert!(log.since(2).is_none());
+    assert_eq!(log.since(3).unwrap(), vec![&change(5, "svc/a"), &change(6, "svc/b")]);
+
+    let watch = Watch { client: NodeId::from("W000"), mid: "w".to_owned(), key: "svc/".to_owned(), prefix: true, from_index: 6 };
+    assert_eq!(log.since(3).unwrap().into_iter().filter(|change| watch.matches(change)).count(), 1);
+}
@@ -0,0 +1,23 @@
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Where cargo put the binaries: tests run from `target/debug/deps`
+fn bin(name: &str) -> PathBuf {
+    let exe = env::current_exe().unwrap();
+    exe.parent().and_then(|deps| deps.parent()).unwrap().join(name)
+}
+
+#[test]
+fn test_short_harness_run_with_kills_is_linearizable() {
+    let output = Command::new(bin("harness"))
+        .args(&["--replicas", "3", "--clients", "2", "--duration", "3000",
+                "--kill-every", "1000", "--down-for", "300", "--seed", "9001"])
+        .output()
+        .unwrap();
+    let report = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", report);
+    assert!(report.contains("is linearizable"), "{}", report);
+    assert!(!report.contains(" 0 kills"), "{}", report);
+    assert!(!report.contains("operations: 0 gets, 0 puts"), "{}", report);
+}